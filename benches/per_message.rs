@@ -0,0 +1,70 @@
+//! Storage overhead of handling an ordinary message in a whitelisted group,
+//! with and without the in-process cache in front of SQLite.
+
+use std::sync::Arc;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use serde_json::json;
+use teloxide::{prelude::*, types::Message};
+
+use telos::{
+    admin::AdminHandler,
+    db::DB,
+    handler::Handler,
+    miguel::MiguelHandler,
+    module::Registry,
+    storage::{SharedStorage, WhitelistStore, cache::CachedStorage},
+};
+
+const GROUP: i64 = -100;
+const SUPERADMIN: i64 = 640129894;
+
+fn message() -> Message {
+    serde_json::from_value(json!({
+        "message_id": 1,
+        "date": 0,
+        "chat": {"id": GROUP, "type": "supergroup", "title": "group"},
+        "from": {"id": 2, "is_bot": false, "first_name": "user"},
+        "text": "just an ordinary message",
+    }))
+    .unwrap()
+}
+
+fn handler(storage: SharedStorage) -> Handler {
+    let registry = Registry::new(storage.clone())
+        .register(AdminHandler::new(storage, Arc::default()).unwrap())
+        .register(MiguelHandler::new(Arc::default()).unwrap());
+    Handler::new(registry)
+}
+
+fn per_message(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("telos-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let db = runtime.block_on(async {
+        let db = DB::new(&format!("sqlite://{}?mode=rwc", dir.join("db.sqlite").display())).await.unwrap();
+        db.migrate().await.unwrap();
+        db.add_whitelisted_group(GROUP, SUPERADMIN, Some("group")).await.unwrap();
+        db
+    });
+
+    // Never called, no message in the benchmark triggers a reply
+    let bot = Bot::new("TOKEN");
+    let msg = message();
+
+    let uncached = handler(Arc::new(db.clone()));
+    c.bench_function("handle message, sqlite", |b| {
+        b.to_async(&runtime).iter(|| uncached.handle(&bot, &msg))
+    });
+
+    let cached = handler(Arc::new(CachedStorage::new(Arc::new(db))));
+    c.bench_function("handle message, cached", |b| {
+        b.to_async(&runtime).iter(|| cached.handle(&bot, &msg))
+    });
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, per_message);
+criterion_main!(benches);
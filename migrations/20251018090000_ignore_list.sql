@@ -0,0 +1,23 @@
+
+CREATE TABLE IF NOT EXISTS ignored_users (
+    user_id INTEGER NOT NULL,
+    -- NULL means the user is ignored in every chat
+    chat_id INTEGER,
+
+    added_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME,
+    FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS ignored_users_scope
+ON ignored_users (user_id, COALESCE(chat_id, 0));
+
+CREATE TABLE IF NOT EXISTS exempted_bots (
+    user_id INTEGER PRIMARY KEY,
+    name TEXT,
+
+    added_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL
+);
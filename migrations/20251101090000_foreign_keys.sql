@@ -0,0 +1,76 @@
+-- Foreign keys were declared but not enforced on every connection, so clean
+-- up anything left dangling before they are.
+--
+-- Removing an admin removes the admins they added, but no longer the groups
+-- and threads they whitelisted: those stay whitelisted with added_by = NULL.
+-- Removing a group still removes its threads.
+--
+-- This runs with foreign keys enforced, so rows are copied with their
+-- dangling references already cleared, and admins are only deleted once
+-- nothing cascades from them anymore.
+
+CREATE TABLE whitelisted_groups_new (
+    group_id INTEGER PRIMARY KEY,
+    group_name TEXT,
+
+    added_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL
+);
+
+-- Admins that can't be traced back to a superadmin have lost their adder
+CREATE TEMPORARY TABLE reachable_admins AS
+WITH RECURSIVE reachable(user_id) AS (
+    SELECT user_id FROM admins WHERE added_by IS NULL
+    UNION
+    SELECT admins.user_id FROM admins JOIN reachable ON admins.added_by = reachable.user_id
+)
+SELECT user_id FROM reachable;
+
+INSERT INTO whitelisted_groups_new (group_id, group_name, added_by, created_at)
+SELECT group_id, group_name,
+    CASE WHEN added_by IN (SELECT user_id FROM reachable_admins) THEN added_by END,
+    created_at
+FROM whitelisted_groups;
+
+CREATE TABLE whitelisted_threads_new (
+    thread_id INTEGER,
+    group_id INTEGER,
+
+    group_name TEXT,
+    thread_name TEXT,
+
+    added_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY(thread_id, group_id),
+    FOREIGN KEY(group_id) REFERENCES whitelisted_groups_new(group_id) ON DELETE CASCADE,
+    FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL
+);
+
+-- Threads of groups that are no longer whitelisted are dropped
+INSERT INTO whitelisted_threads_new (thread_id, group_id, group_name, thread_name, added_by, created_at)
+SELECT thread_id, group_id, group_name, thread_name,
+    CASE WHEN added_by IN (SELECT user_id FROM reachable_admins) THEN added_by END,
+    created_at
+FROM whitelisted_threads
+WHERE group_id IN (SELECT group_id FROM whitelisted_groups_new);
+
+-- Drop the children first, so dropping the groups cascades nowhere
+DROP TABLE whitelisted_threads;
+DROP TABLE whitelisted_groups;
+
+-- Renaming also updates the reference in whitelisted_threads_new
+ALTER TABLE whitelisted_groups_new RENAME TO whitelisted_groups;
+ALTER TABLE whitelisted_threads_new RENAME TO whitelisted_threads;
+
+-- Deleting an admin only clears references to them, not to ids that were
+-- never admins
+UPDATE ignored_users SET added_by = NULL
+WHERE added_by IS NOT NULL AND added_by NOT IN (SELECT user_id FROM reachable_admins);
+
+UPDATE exempted_bots SET added_by = NULL
+WHERE added_by IS NOT NULL AND added_by NOT IN (SELECT user_id FROM reachable_admins);
+
+DELETE FROM admins WHERE user_id NOT IN (SELECT user_id FROM reachable_admins);
+
+DROP TABLE reachable_admins;
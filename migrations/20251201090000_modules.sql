@@ -0,0 +1,11 @@
+
+-- Modules are enabled everywhere unless disabled in a chat
+CREATE TABLE IF NOT EXISTS disabled_modules (
+    chat_id INTEGER NOT NULL,
+    module TEXT NOT NULL,
+
+    added_by INTEGER,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY(chat_id, module),
+    FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL
+);
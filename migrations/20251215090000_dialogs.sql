@@ -0,0 +1,11 @@
+-- Multi-step commands waiting for the next answer of a user in a chat
+CREATE TABLE IF NOT EXISTS dialogs (
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    dialog TEXT NOT NULL,
+    -- JSON array of the answers given so far
+    answers TEXT NOT NULL,
+
+    expires_at DATETIME NOT NULL,
+    PRIMARY KEY(chat_id, user_id)
+);
@@ -0,0 +1,16 @@
+-- Removing an admin removes the admins they added, but no longer the groups
+-- and threads they whitelisted: those stay whitelisted with added_by = NULL.
+-- Removing a group still removes its threads.
+--
+-- PostgreSQL always enforced the foreign keys, so there are no orphans to
+-- clean up here.
+
+ALTER TABLE whitelisted_groups
+    DROP CONSTRAINT whitelisted_groups_added_by_fkey,
+    ADD CONSTRAINT whitelisted_groups_added_by_fkey
+        FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL;
+
+ALTER TABLE whitelisted_threads
+    DROP CONSTRAINT whitelisted_threads_added_by_fkey,
+    ADD CONSTRAINT whitelisted_threads_added_by_fkey
+        FOREIGN KEY(added_by) REFERENCES admins(user_id) ON DELETE SET NULL;
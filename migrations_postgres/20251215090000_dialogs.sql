@@ -0,0 +1,11 @@
+-- Multi-step commands waiting for the next answer of a user in a chat
+CREATE TABLE IF NOT EXISTS dialogs (
+    chat_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    dialog TEXT NOT NULL,
+    -- JSON array of the answers given so far
+    answers TEXT NOT NULL,
+
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY(chat_id, user_id)
+);
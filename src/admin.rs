@@ -1,63 +1,138 @@
-use teloxide::{prelude::*};
-
-use crate::db;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use teloxide::{net::Download, prelude::*, types::InputFile};
+use uuid::Uuid;
+
+use crate::{backup, callback::{Answer, Button, Callbacks}, config::Config, db::{self, DbError}, dialog::{self, Dialog, Step}, export::ConfigDocument, identity::{self, Identity}, metrics::Metrics, module::{Command, Module, Permission}, queue::Queue, storage::{SharedStorage, cache::CacheStats}};
+
+const CONFIRM: &str = "confirm";
+const CANCEL: &str = "cancel";
+const MAX_CONFIG_SIZE: u32 = 1024 * 1024;
+const MAX_MESSAGE_LEN: usize = 3500;
+
+const ADMINS: &str = "🛠️ Admin Commands";
+const WHITELIST: &str = "✅ Whitelist Management";
+const IGNORE: &str = "🔇 Ignore List";
+const CONFIGURATION: &str = "📦 Configuration";
+const GENERAL: &str = "ℹ️ General";
+
+const COMMANDS: &[Command] = &[
+    Command { group: ADMINS, usage: "/list_admins", description: "List all registered admins.", permission: Permission::Admin },
+    Command { group: ADMINS, usage: "/remove_admin <user_id>", description: "Remove an admin.", permission: Permission::Admin },
+    Command { group: ADMINS, usage: "/make_superadmin <user_id>", description: "Promote an admin to superadmin.", permission: Permission::Superadmin },
+    Command { group: ADMINS, usage: "/become_admin", description: "Request admin access.", permission: Permission::Anyone },
+    Command { group: ADMINS, usage: "/approve_become_admin <request_id>", description: "Approve a request to become admin.", permission: Permission::Admin },
+    Command { group: ADMINS, usage: "/reject_become_admin <request_id>", description: "Reject a request.", permission: Permission::Admin },
+    Command { group: ADMINS, usage: "/list_become_admin_requests", description: "List all pending admin requests.", permission: Permission::Admin },
+    Command { group: WHITELIST, usage: "/whitelist_group", description: "Whitelist the current group.", permission: Permission::Admin },
+    Command { group: WHITELIST, usage: "/whitelist_thread", description: "Whitelist the current thread.", permission: Permission::Admin },
+    Command { group: WHITELIST, usage: "/unwhitelist_group [group_id]", description: "Remove a group from whitelist.", permission: Permission::Admin },
+    Command { group: WHITELIST, usage: "/unwhitelist_thread [group_id] [thread_id]", description: "Remove a thread from whitelist, asking for the ids in private.", permission: Permission::Admin },
+    Command { group: WHITELIST, usage: "/list_whitelisted_groups", description: "Show all whitelisted groups.", permission: Permission::Admin },
+    Command { group: WHITELIST, usage: "/list_whitelisted_threads", description: "Show whitelisted threads in this group.", permission: Permission::Admin },
+    Command { group: IGNORE, usage: "/ignore [user_id] [here] [duration]", description: "Ignore a user (reply or id), optionally only in this chat or for a while (30m, 12h, 7d).", permission: Permission::Admin },
+    Command { group: IGNORE, usage: "/unignore [user_id] [here]", description: "Stop ignoring a user.", permission: Permission::Admin },
+    Command { group: IGNORE, usage: "/list_ignored", description: "Show all ignored users.", permission: Permission::Admin },
+    Command { group: IGNORE, usage: "/exempt_bot [bot_id]", description: "Let a bot's messages through.", permission: Permission::Admin },
+    Command { group: IGNORE, usage: "/unexempt_bot [bot_id]", description: "Ignore a bot's messages again.", permission: Permission::Admin },
+    Command { group: IGNORE, usage: "/list_exempted_bots", description: "Show all exempted bots.", permission: Permission::Admin },
+    Command { group: CONFIGURATION, usage: "/export_config", description: "Send the admins, whitelists, ignore list and disabled modules as a file.", permission: Permission::Superadmin },
+    Command { group: CONFIGURATION, usage: "/import_config", description: "Reply to an exported file to replace the current config.", permission: Permission::Superadmin },
+    Command { group: CONFIGURATION, usage: "/backup", description: "Receive a snapshot of the database in private.", permission: Permission::Superadmin },
+    Command { group: CONFIGURATION, usage: "/cache_stats", description: "Show how often lookups were answered from the cache.", permission: Permission::Admin },
+    Command { group: GENERAL, usage: "/forget_me", description: "Delete what the bot stores about you.", permission: Permission::Anyone },
+];
+
+/// `/unwhitelist_thread` sent outside the group, with no ids
+const UNWHITELIST_THREAD: Dialog = Dialog {
+    name: "unwhitelist_thread",
+    steps: &[
+        Step { prompt: "Which group is the thread in? Send the group id, like -1001234567890.", check: dialog::number::<i64> },
+        Step { prompt: "Which thread? Send the thread id.", check: dialog::number::<i32> },
+    ],
+    timeout: std::time::Duration::from_secs(5 * 60),
+};
+
+const DIALOGS: &[Dialog] = &[UNWHITELIST_THREAD];
+
+/// A destructive command waiting for its issuer to press Confirm
+#[derive(Serialize, Deserialize)]
+enum PendingAction {
+    RemoveAdmin { user_id: i64 },
+    UnwhitelistGroup { group_id: i64 },
+    MakeSuperadmin { user_id: i64 },
+    ImportConfig { doc: Box<ConfigDocument> },
+}
 
+/// Payload of the Confirm button, kept with its keyboard: the admin who
+/// issued the command and the command
+#[derive(Serialize, Deserialize)]
+struct Confirmation(i64, PendingAction);
 
 pub struct AdminHandler {
-    db: db::DB,
+    db: SharedStorage,
+    config: Arc<Config>,
+    callbacks: Arc<Callbacks>,
+    cache_stats: Option<Arc<CacheStats>>,
+    metrics: Arc<Metrics>,
+    queue: Arc<Queue>,
 }
 
 impl AdminHandler {
-    pub fn new(db: db::DB) -> Option<Self> {
-        Some(Self { db })
+    pub fn new(db: SharedStorage, config: Arc<Config>) -> Option<Self> {
+        Some(Self {
+            db,
+            config,
+            callbacks: Arc::default(),
+            cache_stats: None,
+            metrics: Arc::default(),
+            queue: Arc::default(),
+        })
     }
 
-    pub async fn check_whitelist(&self, msg: &Message) -> Result<bool, Box<dyn std::error::Error>> {
-        if msg.chat.is_group() || msg.chat.is_supergroup() {
-            match self.is_group_allowed(msg.chat.id.0).await {
-                Ok(true) => {}
-                Ok(false) => {
-                    log::trace!("Group is not whitelisted: {:?}", msg);
-                    return Ok(false);
-                }
-                Err(e) => {
-                    log::error!("Error checking if group is whitelisted: {:?}", e);
-                    return Err(e.into());
-                }
-            }
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 
-            if let Some(thread_id) = msg.thread_id {
-                match self.is_thread_allowed(thread_id.0.0, msg.chat.id.0).await {
-                    Ok(true) => {}
-                    Ok(false) => {
-                        log::trace!("Thread is not whitelisted: {:?}", msg);
-                        return Ok(false);
-                    }
-                    Err(e) => {
-                        log::error!("Error checking if thread is whitelisted: {:?}", e);
-                        return Err(e.into());
-                    }
-                }
-            }
-        }
+    pub fn with_queue(mut self, queue: Arc<Queue>) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    pub fn with_cache_stats(mut self, cache_stats: Arc<CacheStats>) -> Self {
+        self.cache_stats = Some(cache_stats);
+        self
+    }
 
-        Ok(true)
+    /// Counts a command and names this handler in the update's span
+    fn command(&self, cmd: &str) {
+        self.metrics.command(cmd);
+        tracing::Span::current().record("handler", "admin");
+        tracing::debug!(command = cmd, "Admin command");
     }
 
-    pub async fn is_group_allowed(&self, group_id: i64) -> Result<bool, sqlx::Error> {
-        self.db.is_group_whitelisted(group_id).await
+    async fn reply(&self, bot: &Bot, msg: &Message, text: impl Into<String>) -> ResponseResult<()> {
+        let mut reply = bot.send_message(msg.chat.id, text);
+        if let Some(thread_id) = msg.thread_id {
+            reply = reply.message_thread_id(thread_id);
+        }
+        self.queue.send(reply).await?;
+        Ok(())
     }
 
-    pub async fn is_thread_allowed(&self, thread_id: i32, group_id: i64) -> Result<bool, sqlx::Error> {
-        self.db.is_thread_whitelisted(thread_id, group_id).await
+    fn confirmation_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.admin.confirmation_ttl_secs)
     }
 
     pub async fn handle(&self, bot: &Bot, msg: &Message) -> Result<(), teloxide::RequestError> {
-        if msg.from.is_none() {
+        let Some(actor) = Identity::of(msg) else {
             return Ok(());
-        }
-
-        let from = msg.from.as_ref().unwrap().clone();
+        };
+        let actor_id = actor.id();
 
         let text = msg.text().unwrap_or_default().to_lowercase();
 
@@ -66,51 +141,84 @@ impl AdminHandler {
             Some((cmd, _)) => cmd,
         };
 
-        let admin = match self.db.get_admin(from.id.0 as i64).await {
-            Ok(Some(admin)) => admin,
-            Ok(None) => {
-                if cmd == "/become_admin" {
-                    return self.become_admin(bot, msg).await;
-                }
+        // Other messages cost neither a lookup nor a call to Telegram
+        if !COMMANDS.iter().any(|command| command.usage.split(' ').next() == Some(cmd)) {
+            return Ok(());
+        }
+
+        // Anyone can ask to be forgotten, admin or not
+        if cmd == "/forget_me" {
+            self.command(cmd);
+            return self.forget_me(bot, msg, actor_id).await;
+        }
 
-                log::info!("User is not admin: {:?}", msg);
+        let admin = match self.db.get_admin(actor_id).await {
+            Ok(Some(admin)) => Some(admin),
+            Ok(None) if cmd == "/become_admin" => None,
+            Ok(None) => {
+                tracing::trace!("User is not admin");
                 return Ok(());
             }
             Err(e) => {
-                log::error!("Error checking if user is admin: {:?}", e);
+                tracing::error!("Error checking if user is admin: {:?}", e);
                 return Ok(());
             }
         };
 
+        // Anonymous admins are checked with Telegram before the group acts
+        match actor.verify(bot).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::trace!("Chat {} has no anonymous admins", actor_id);
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("Error verifying anonymous admin: {:?}", e);
+                return Ok(());
+            }
+        }
+
+        let Some(admin) = admin else {
+            self.command(cmd);
+            return self.become_admin(bot, msg, actor_id).await;
+        };
 
-        log::trace!("Command: {cmd:?}");
-        log::trace!("Text: {text:?}");
 
         match cmd {
-            "/whitelist_group" => self.whitelist_group(bot, msg, from.id.0).await?,
-            "/whitelist_thread" => self.whitelist_thread(bot, msg, from.id.0).await?,
-            "/unwhitelist_group" => self.unwhitelist_group(bot, msg, from.id.0).await?,
-            "/unwhitelist_thread" => self.unwhitelist_thread(bot, msg, from.id.0).await?,
+            "/whitelist_group" => self.whitelist_group(bot, msg, actor_id).await?,
+            "/whitelist_thread" => self.whitelist_thread(bot, msg, actor_id).await?,
+            "/unwhitelist_group" => self.unwhitelist_group(bot, msg, actor_id).await?,
+            "/unwhitelist_thread" => self.unwhitelist_thread(bot, msg, actor_id).await?,
             "/remove_admin" => self.remove_admin(bot, msg, admin).await?,
             "/make_superadmin" => self.make_superadmin(bot, msg, admin).await?,
             "/list_admins" => self.list_admins(bot, msg).await?,
             "/list_whitelisted_groups" => self.list_whitelisted_groups(bot, msg).await?,
             "/list_whitelisted_threads" => self.list_whitelisted_threads(bot, msg).await?,
-            "/approve_become_admin" => self.approve_become_admin(bot, msg, from.id.0).await?,
-            "/reject_become_admin" => self.reject_become_admin(bot, msg, from.id.0).await?,
+            "/approve_become_admin" => self.approve_become_admin(bot, msg, actor_id).await?,
+            "/reject_become_admin" => self.reject_become_admin(bot, msg, actor_id).await?,
             "/list_become_admin_requests" => self.list_become_admin_requests(bot, msg).await?,
-            "/help" => self.help(bot, msg).await?,
+            "/ignore" => self.ignore(bot, msg, actor_id).await?,
+            "/unignore" => self.unignore(bot, msg).await?,
+            "/list_ignored" => self.list_ignored(bot, msg).await?,
+            "/exempt_bot" => self.exempt_bot(bot, msg, actor_id).await?,
+            "/unexempt_bot" => self.unexempt_bot(bot, msg).await?,
+            "/list_exempted_bots" => self.list_exempted_bots(bot, msg).await?,
+            "/export_config" => self.export_config(bot, msg, admin).await?,
+            "/import_config" => self.import_config(bot, msg, admin).await?,
+            "/backup" => self.backup(bot, msg, admin).await?,
+            "/cache_stats" => self.cache_stats(bot, msg).await?,
             &_ => {
                 return Ok(());
             }
         }
 
+        self.command(cmd);
 
         Ok(())
     }
 
-    async fn whitelist_group(&self, bot: &Bot, msg: &Message, admin_id: u64) -> Result<(), teloxide::RequestError> {
-        log::trace!("Whtelisting group: {:?}", msg);
+    async fn whitelist_group(&self, bot: &Bot, msg: &Message, admin_id: i64) -> Result<(), teloxide::RequestError> {
+        tracing::trace!("Whitelisting group");
         // This command is only valid in groups
         if !msg.chat.is_group() && !msg.chat.is_supergroup() {
             return Ok(());
@@ -121,7 +229,7 @@ impl AdminHandler {
 
         match self.db.add_whitelisted_group(
             group_id, 
-            admin_id as i64,
+            admin_id,
             group_name,
         ).await {
             Ok(_) => {
@@ -129,23 +237,29 @@ impl AdminHandler {
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
+            }
+            Err(DbError::AlreadyExists) => {
+                self.reply(bot, msg, "This group is already whitelisted").await?;
+            }
+            Err(DbError::NotFound) => {
+                self.reply(bot, msg, "You are no longer an admin").await?;
             }
             Err(e) => {
-                log::error!("Error whitelisting group: {:?}", e);
+                tracing::error!("Error whitelisting group: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error whitelisting group!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn whitelist_thread(&self, bot: &Bot, msg: &Message, admin_id: u64) -> Result<(), teloxide::RequestError> {
-        log::trace!("Whtelisting thread: {:?}", msg);
+    async fn whitelist_thread(&self, bot: &Bot, msg: &Message, admin_id: i64) -> Result<(), teloxide::RequestError> {
+        tracing::trace!("Whitelisting thread");
         if !msg.chat.is_supergroup() {
             return Ok(());
         }
@@ -154,7 +268,7 @@ impl AdminHandler {
         let thread_id = match msg.thread_id {
             Some(thread_id) => thread_id,
             None => {
-                bot.send_message(msg.chat.id, "Can only be used in threads!").await?;
+                self.queue.send(bot.send_message(msg.chat.id, "Can only be used in threads!")).await?;
                 return Ok(());
             }
         };
@@ -165,28 +279,32 @@ impl AdminHandler {
         match self.db.add_whitelisted_thread(
             thread_id.0.0, 
             group_id, 
-            admin_id as i64,
+            admin_id,
             group_name,
             thread_name,
         ).await {
             Ok(_) => {
-                bot.send_message(msg.chat.id, "Thread whitelisted!")
-                    .message_thread_id(thread_id)
-                    .await?;
+                let reply = bot.send_message(msg.chat.id, "Thread whitelisted!").message_thread_id(thread_id);
+                self.queue.send(reply).await?;
+            }
+            Err(DbError::AlreadyExists) => {
+                self.reply(bot, msg, "This thread is already whitelisted").await?;
+            }
+            Err(DbError::NotFound) => {
+                self.reply(bot, msg, "Whitelist the group with /whitelist_group first").await?;
             }
             Err(e) => {
-                log::error!("Error whitelisting thread: {:?}", e);
-                bot.send_message(msg.chat.id, "Error whitelisting thread!")
-                    .message_thread_id(thread_id)
-                    .await?;
+                tracing::error!("Error whitelisting thread: {:?}", e);
+                let reply = bot.send_message(msg.chat.id, "Error whitelisting thread!").message_thread_id(thread_id);
+                self.queue.send(reply).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn unwhitelist_group(&self, bot: &Bot, msg: &Message, _admin_id: u64) -> ResponseResult<()> {
-        log::trace!("Unwhitelisting group: {:?}", msg);
+    async fn unwhitelist_group(&self, bot: &Bot, msg: &Message, admin_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Unwhitelisting group");
         let text = msg.text().unwrap_or_default().to_lowercase();
 
         let group_id = if msg.chat.is_group() || msg.chat.is_supergroup() {
@@ -202,7 +320,7 @@ impl AdminHandler {
                             if let Some(thread_id) = msg.thread_id {
                                 reply = reply.message_thread_id(thread_id);
                             }
-                            reply.await?;
+                            self.queue.send(reply).await?;
                             return Ok(());
                         }
                     }
@@ -212,63 +330,69 @@ impl AdminHandler {
                     if let Some(thread_id) = msg.thread_id {
                         reply = reply.message_thread_id(thread_id);
                     }
-                    reply.await?;
+                    self.queue.send(reply).await?;
                     return Ok(());
                 }
             }
         };
 
-        match self.db.remove_whitelisted_group(group_id).await {
-            Ok(_) => {
-                let mut reply = bot.send_message(msg.chat.id, "Group unwhitelisted!");
-                if let Some(thread_id) = msg.thread_id {
-                    reply = reply.message_thread_id(thread_id);
-                }
-                reply.await?;
+        let group = match self.db.get_whitelisted_group(group_id).await {
+            Ok(Some(group)) => group,
+            Ok(None) => {
+                self.reply(bot, msg, "Group is not whitelisted").await?;
+                return Ok(());
             }
             Err(e) => {
-                log::error!("Error unwhitelisting group: {:?}", e);
-                let mut reply = bot.send_message(msg.chat.id, "Error unwhitelisting group!");
-                if let Some(thread_id) = msg.thread_id {
-                    reply = reply.message_thread_id(thread_id);
-                }
-                reply.await?;
+                tracing::error!("Error getting whitelisted group: {:?}", e);
+                self.reply(bot, msg, "Error unwhitelisting group!").await?;
+                return Ok(());
             }
-        }
+        };
 
-        Ok(())
+        let threads = match self.db.get_whitelisted_threads(group_id).await {
+            Ok(threads) => threads.len(),
+            Err(e) => {
+                tracing::error!("Error listing whitelisted threads: {:?}", e);
+                self.reply(bot, msg, "Error unwhitelisting group!").await?;
+                return Ok(());
+            }
+        };
+
+        let summary = format!(
+            "Unwhitelist group {} ({})? This also removes {} whitelisted thread(s).",
+            group.group_name.as_deref().unwrap_or("<unnamed>"),
+            group_id,
+            threads,
+        );
+
+        self.request_confirmation(bot, msg, admin_id, PendingAction::UnwhitelistGroup { group_id }, summary).await
     }
 
-    async fn unwhitelist_thread(&self, bot: &Bot, msg: &Message, _admin_id: u64) -> ResponseResult<()> {
-        log::trace!("Unwhitelisting thread: {:?}", msg);
+    async fn unwhitelist_thread(&self, bot: &Bot, msg: &Message, _admin_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Unwhitelisting thread");
         let text = msg.text().unwrap_or_default().to_lowercase();
         let args =  text.split_whitespace().collect::<Vec<&str>>();
 
         let (group_id, thread_id) = match args.len() {
             1 => {
                 if !msg.chat.is_group() && !msg.chat.is_supergroup() {
-                    let mut reply = bot.send_message(msg.chat.id, "Invalid command: Use /unwhitelist_thread <@group_id> <@thread_id>");
-                    if let Some(thread_id) = msg.thread_id {
-                        reply = reply.message_thread_id(thread_id);
-                    }
-                    reply.await?;
-                    return Ok(());
+                    return dialog::start(&self.db, &self.queue, bot, msg, &UNWHITELIST_THREAD).await;
                 }
 
                 match msg.thread_id {
                     Some(thread_id_val) => (msg.chat.id.0, thread_id_val.0.0),
                     None => {
-                        bot.send_message(
+                        self.queue.send(bot.send_message(
                             msg.chat.id,
                             "Invalid command: Use /unwhitelist_thread <@thread_id> in threads or provide group ID"
-                        ).await?;
+                        )).await?;
                         return Ok(());
                     }
                 }
             },
             2 => {
                 if !msg.chat.is_group() && !msg.chat.is_supergroup() {
-                    bot.send_message(msg.chat.id, "Invalid command: Use /unwhitelist_thread <@group_id> <@thread_id>").await?;
+                    self.queue.send(bot.send_message(msg.chat.id, "Invalid command: Use /unwhitelist_thread <@group_id> <@thread_id>")).await?;
                     return Ok(());
                 }
 
@@ -279,7 +403,7 @@ impl AdminHandler {
                         if let Some(thread_id) = msg.thread_id {
                             reply = reply.message_thread_id(thread_id);
                         }
-                        reply.await?;
+                        self.queue.send(reply).await?;
                         return Ok(());
                     }
                 };
@@ -294,7 +418,7 @@ impl AdminHandler {
                         if let Some(thread_id) = msg.thread_id {
                             reply = reply.message_thread_id(thread_id);
                         }
-                        reply.await?;
+                        self.queue.send(reply).await?;
                         return Ok(());
                     }
                 };
@@ -305,7 +429,7 @@ impl AdminHandler {
                         if let Some(thread_id) = msg.thread_id {
                             reply = reply.message_thread_id(thread_id);
                         }
-                        reply.await?;
+                        self.queue.send(reply).await?;
                         return Ok(());
                     }
                 };
@@ -319,46 +443,84 @@ impl AdminHandler {
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
 
+        self.remove_thread(bot, msg, group_id, thread_id).await
+    }
+
+    async fn remove_thread(&self, bot: &Bot, msg: &Message, group_id: i64, thread_id: i32) -> ResponseResult<()> {
         match self.db.remove_whitelisted_thread(thread_id, group_id).await {
             Ok(_) => {
                 let mut reply = bot.send_message(msg.chat.id, "Thread unwhitelisted!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
+            }
+            Err(DbError::NotFound) => {
+                self.reply(bot, msg, "This thread is not whitelisted").await?;
             }
             Err(e) => {
-                log::error!("Error unwhitelisting thread: {:?}", e);
+                tracing::error!("Error unwhitelisting thread: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error unwhitelisting thread!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
             }
         }
 
         Ok(())
     }
 
+    /// Runs the command a dialog collected the arguments of, if its admin
+    /// still is one
+    pub async fn finish(&self, bot: &Bot, msg: &Message, dialog: &str, answers: &[String]) -> ResponseResult<()> {
+        let Some(actor) = Identity::of(msg) else {
+            return Ok(());
+        };
+
+        match self.db.get_admin(actor.id()).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                tracing::error!("Error checking if user is admin: {:?}", e);
+                return Ok(());
+            }
+        }
+
+        match answers {
+            [group_id, thread_id] if dialog == UNWHITELIST_THREAD.name => {
+                // Both were checked when answered
+                let (Ok(group_id), Ok(thread_id)) = (group_id.parse(), thread_id.parse()) else {
+                    return Ok(());
+                };
+                self.remove_thread(bot, msg, group_id, thread_id).await
+            }
+            _ => {
+                tracing::warn!("Unknown dialog {} with {} answer(s)", dialog, answers.len());
+                Ok(())
+            }
+        }
+    }
+
     async fn remove_admin(&self, bot: &Bot, msg: &Message, admin: db::Admin) -> ResponseResult<()> {
-        log::trace!("Removing admin: {:?}", msg);
+        tracing::trace!("Removing admin");
         let text = msg.text().unwrap_or_default().to_lowercase();
 
         let user_id = match text.split_whitespace().nth(1) {
             Some(user_id) => {
-                match user_id.parse::<u64>() {
+                match user_id.parse::<i64>() {
                     Ok(user_id) => user_id,
                     Err(_) => {
                         let mut reply = bot.send_message(msg.chat.id, "Invalid user id");
                         if let Some(thread_id) = msg.thread_id {
                             reply = reply.message_thread_id(thread_id);
                         }
-                        reply.await?;
+                        self.queue.send(reply).await?;
                         return Ok(());
                     }
                 }
@@ -368,135 +530,284 @@ impl AdminHandler {
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
 
-        
-        if admin.is_superadmin() {
-            match self.db.remove_admin(user_id as i64).await {
-                Ok(_) => {
-                    let mut reply = bot.send_message(msg.chat.id, "Admin removed!");
-                    if let Some(thread_id) = msg.thread_id {
-                        reply = reply.message_thread_id(thread_id);
-                    }
-                    reply.await?;
-                }
-                Err(e) => {
-                    log::error!("Error removing admin: {:?}", e);
-                    let mut reply = bot.send_message(msg.chat.id, "Error removing admin!");
-                    if let Some(thread_id) = msg.thread_id {
-                        reply = reply.message_thread_id(thread_id);
-                    }
-                    reply.await?;
-                }
-            }
-        } else {
-            match self.db.remove_admin_with_traversal(user_id as i64, admin.user_id).await {
-                Ok(true) => {
-                    let mut reply = bot.send_message(msg.chat.id, "Admin removed!");
-                    if let Some(thread_id) = msg.thread_id {
-                        reply = reply.message_thread_id(thread_id);
-                    }
-                    reply.await?;
-                }
+        // Checked before anything about the target is shown
+        if !admin.is_superadmin() {
+            match self.db.is_ancestor(user_id, admin.user_id).await {
+                Ok(true) => {}
                 Ok(false) => {
-                    let mut reply = bot.send_message(msg.chat.id, "You are not an admin of this user");
-                    if let Some(thread_id) = msg.thread_id {
-                        reply = reply.message_thread_id(thread_id);
-                    }
-                    reply.await?;
+                    self.reply(bot, msg, "You can only remove admins you added, directly or through others").await?;
+                    return Ok(());
                 }
                 Err(e) => {
-                    log::error!("Error removing admin: {:?}", e);
-                    let mut reply = bot.send_message(msg.chat.id, "Error removing admin!");
-                    if let Some(thread_id) = msg.thread_id {
-                        reply = reply.message_thread_id(thread_id);
-                    }
-                    reply.await?;
+                    tracing::error!("Error checking who added the admin: {:?}", e);
+                    self.reply(bot, msg, "Error removing admin!").await?;
+                    return Ok(());
                 }
             }
         }
 
-        Ok(())
+        let target = match self.db.get_admin(user_id).await {
+            Ok(Some(target)) => target,
+            Ok(None) => {
+                self.reply(bot, msg, "User is not an admin").await?;
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("Error getting admin: {:?}", e);
+                self.reply(bot, msg, "Error removing admin!").await?;
+                return Ok(());
+            }
+        };
+
+        let subtree = match self.db.count_admin_subtree(user_id).await {
+            Ok(subtree) => subtree,
+            Err(e) => {
+                tracing::error!("Error counting admin subtree: {:?}", e);
+                self.reply(bot, msg, "Error removing admin!").await?;
+                return Ok(());
+            }
+        };
+
+        let summary = format!(
+            "Remove admin {} ({})? This also removes {} admin(s) they added. Groups they whitelisted stay whitelisted.",
+            target.name.as_deref().unwrap_or("<unnamed>"),
+            user_id,
+            subtree,
+        );
+
+        self.request_confirmation(bot, msg, admin.user_id, PendingAction::RemoveAdmin { user_id }, summary).await
     }
 
 
     async fn make_superadmin(&self, bot: &Bot, msg: &Message, admin: db::Admin) -> ResponseResult<()> {
-        log::trace!("Making superadmin: {:?}", msg);
+        tracing::trace!("Making superadmin");
         // This command is only valid in private chats
         if !admin.is_superadmin() {
             let mut reply = bot.send_message(msg.chat.id, "You are not a superadmin");
             if let Some(thread_id) = msg.thread_id {
                 reply = reply.message_thread_id(thread_id);
             }
-            reply.await?;
+            self.queue.send(reply).await?;
             return Ok(());
         }
 
 
         let text = msg.text().unwrap_or_default().to_lowercase();
 
-        let target_id = text.split_whitespace().nth(1);
-
-        match target_id {
+        let target_id = match text.split_whitespace().nth(1) {
             Some(target_id) => {
-                match target_id.parse::<u64>() {
-                    Ok(target_id) => {
-                        match self.db.make_superadmin(target_id as i64).await {
-                            Ok(_) => {
-                                let mut reply = bot.send_message(msg.chat.id, "Superadmin made!");
-                                if let Some(thread_id) = msg.thread_id {
-                                    reply = reply.message_thread_id(thread_id);
-                                }
-                                reply.await?;
-                            }
-                            Err(e) => {
-                                log::error!("Error making superadmin: {:?}", e);
-                                let mut reply = bot.send_message(msg.chat.id, "Error making superadmin!");
-                                if let Some(thread_id) = msg.thread_id {
-                                    reply = reply.message_thread_id(thread_id);
-                                }
-                                reply.await?;
-                            }
-                        }
-                    },
+                match target_id.parse::<i64>() {
+                    Ok(target_id) => target_id,
                     Err(_) => {
-                        let mut reply = bot.send_message(msg.chat.id, "Invalid user id");
-                        if let Some(thread_id) = msg.thread_id {
-                            reply = reply.message_thread_id(thread_id);
-                        }
-                        reply.await?;
+                        self.reply(bot, msg, "Invalid user id").await?;
                         return Ok(());
                     }
                 }
             },
             None => {
-                let mut reply = bot.send_message(msg.chat.id, "Invalid command, use /make_superadmin <@user_id>");
-                if let Some(thread_id) = msg.thread_id {
-                    reply = reply.message_thread_id(thread_id);
-                }
-                reply.await?;
+                self.reply(bot, msg, "Invalid command, use /make_superadmin <@user_id>").await?;
+                return Ok(());
+            }
+        };
+
+        let target = match self.db.get_admin(target_id).await {
+            Ok(Some(target)) => target,
+            Ok(None) => {
+                self.reply(bot, msg, "User is not an admin").await?;
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("Error getting admin: {:?}", e);
+                self.reply(bot, msg, "Error making superadmin!").await?;
                 return Ok(());
             }
+        };
+
+        if target.is_superadmin() {
+            self.reply(bot, msg, "User is already a superadmin").await?;
+            return Ok(());
+        }
+
+        let summary = format!(
+            "Promote {} ({}) to superadmin? They will be able to remove any admin, including you.",
+            target.name.as_deref().unwrap_or("<unnamed>"),
+            target_id,
+        );
+
+        self.request_confirmation(bot, msg, admin.user_id, PendingAction::MakeSuperadmin { user_id: target_id }, summary).await
+    }
+
+    async fn request_confirmation(
+        &self,
+        bot: &Bot,
+        msg: &Message,
+        admin_id: i64,
+        action: PendingAction,
+        summary: String,
+    ) -> ResponseResult<()> {
+        let buttons = vec![vec![
+            Button::new("Confirm", CONFIRM, &Confirmation(admin_id, action)),
+            Button::new("Cancel", CANCEL, &admin_id),
+        ]];
+
+        let mut request = bot.send_message(msg.chat.id, summary);
+        if let Some(thread_id) = msg.thread_id {
+            request = request.message_thread_id(thread_id);
         }
+        let ttl = self.confirmation_ttl();
+        self.callbacks.send(&self.queue, bot, request, buttons, ttl, "Expired, nothing was changed.").await?;
 
         Ok(())
     }
 
+    /// Runs or cancels the command of a confirmation, if the admin who
+    /// issued it pressed the button
+    pub async fn press(&self, bot: &Bot, q: &CallbackQuery) -> ResponseResult<Answer> {
+        let Some(press) = self.callbacks.press(q) else {
+            return Ok(Answer::text("This confirmation has expired"));
+        };
+
+        tracing::trace!("Confirmation callback");
+        self.command(press.prefix());
+
+        let (admin_id, action) = match press.prefix() {
+            CONFIRM => match press.data::<Confirmation>() {
+                Some(Confirmation(admin_id, action)) => (admin_id, Some(action)),
+                None => return Ok(Answer::expired()),
+            },
+            _ => match press.data::<i64>() {
+                Some(admin_id) => (admin_id, None),
+                None => return Ok(Answer::expired()),
+            },
+        };
+
+        if !self.may_answer(bot, admin_id, q.from.id).await {
+            return Ok(Answer::alert("Only the admin who issued this command can answer it"));
+        }
+        self.callbacks.finish(&press);
+
+        let outcome = match action {
+            Some(action) => self.execute_action(&action, admin_id).await,
+            None => "Cancelled, nothing was changed.".to_string(),
+        };
+
+        if let Some(message) = &q.message {
+            let summary = message.regular_message().and_then(|m| m.text()).unwrap_or_default();
+            let edit = bot.edit_message_text(message.chat().id, message.id(), format!("{summary}\n\n{outcome}"));
+            self.queue.send(edit).await?;
+        }
+
+        Ok(Answer::default())
+    }
+
+    /// Whether the user who pressed a button may answer for `admin_id`:
+    /// themselves, or any admin of a chat that acts as an admin
+    async fn may_answer(&self, bot: &Bot, admin_id: i64, user_id: UserId) -> bool {
+        if admin_id == user_id.0 as i64 {
+            return true;
+        }
+        // User ids are positive, only chats are answered for by their admins
+        if admin_id >= 0 {
+            return false;
+        }
+
+        match identity::is_chat_admin(bot, ChatId(admin_id), user_id).await {
+            Ok(is_admin) => is_admin,
+            Err(e) => {
+                tracing::error!("Error checking the admins of chat {}: {:?}", admin_id, e);
+                false
+            }
+        }
+    }
+
+    async fn execute_action(&self, action: &PendingAction, admin_id: i64) -> String {
+        // The admin may have lost their rights while the confirmation was pending
+        let admin = match self.db.get_admin(admin_id).await {
+            Ok(Some(admin)) => admin,
+            Ok(None) => return "You are no longer an admin".to_string(),
+            Err(e) => {
+                tracing::error!("Error checking if user is admin: {:?}", e);
+                return "Error checking your admin rights!".to_string();
+            }
+        };
+
+        match action {
+            PendingAction::RemoveAdmin { user_id } => {
+                let result = if admin.is_superadmin() {
+                    self.db.remove_admin(*user_id).await
+                } else {
+                    self.db.remove_admin_with_traversal(*user_id, admin.user_id).await
+                };
+
+                match result {
+                    Ok(_) => "Admin removed!".to_string(),
+                    Err(DbError::NotFound) => "This admin no longer exists".to_string(),
+                    Err(DbError::PermissionDenied) => {
+                        "You can only remove admins you added, directly or through others".to_string()
+                    }
+                    Err(e) => {
+                        tracing::error!("Error removing admin: {:?}", e);
+                        "Error removing admin!".to_string()
+                    }
+                }
+            }
+            PendingAction::UnwhitelistGroup { group_id } => {
+                match self.db.remove_whitelisted_group(*group_id).await {
+                    Ok(_) => "Group unwhitelisted!".to_string(),
+                    Err(DbError::NotFound) => "This group is no longer whitelisted".to_string(),
+                    Err(e) => {
+                        tracing::error!("Error unwhitelisting group: {:?}", e);
+                        "Error unwhitelisting group!".to_string()
+                    }
+                }
+            }
+            PendingAction::MakeSuperadmin { user_id } => {
+                if !admin.is_superadmin() {
+                    return "You are not a superadmin".to_string();
+                }
+
+                match self.db.make_superadmin(*user_id).await {
+                    Ok(_) => "Superadmin made!".to_string(),
+                    Err(DbError::NotFound) => "This admin no longer exists".to_string(),
+                    Err(e) => {
+                        tracing::error!("Error making superadmin: {:?}", e);
+                        "Error making superadmin!".to_string()
+                    }
+                }
+            }
+            PendingAction::ImportConfig { doc } => {
+                if !admin.is_superadmin() {
+                    return "You are not a superadmin".to_string();
+                }
+
+                match self.db.replace_config(doc).await {
+                    Ok(_) => "Config imported!".to_string(),
+                    Err(e) => {
+                        tracing::error!("Error importing config: {:?}", e);
+                        "Error importing config, nothing was changed!".to_string()
+                    }
+                }
+            }
+        }
+    }
+
     async fn list_admins(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-        log::trace!("Listing admins: {:?}", msg);
+        tracing::trace!("Listing admins");
 
         let admins = match self.db.get_admins().await {
             Ok(admins) => admins,
             Err(e) => {
-                log::error!("Error listing admins: {:?}", e);
+                tracing::error!("Error listing admins: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error listing admins!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
@@ -510,22 +821,22 @@ impl AdminHandler {
         if let Some(thread_id) = msg.thread_id {
             reply = reply.message_thread_id(thread_id);
         }
-        reply.await?;
+        self.queue.send(reply).await?;
 
         Ok(())
     }
 
     async fn list_whitelisted_groups(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-        log::trace!("Listing whitelisted groups: {:?}", msg);
+        tracing::trace!("Listing whitelisted groups");
         let groups = match self.db.get_whitelisted_groups().await {
             Ok(groups) => groups,
             Err(e) => {
-                log::error!("Error listing whitelisted groups: {:?}", e);
+                tracing::error!("Error listing whitelisted groups: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error listing whitelisted groups!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
@@ -539,22 +850,22 @@ impl AdminHandler {
         if let Some(thread_id) = msg.thread_id {
             reply = reply.message_thread_id(thread_id);
         }
-        reply.await?;
+        self.queue.send(reply).await?;
 
         Ok(())
     }
 
     async fn list_whitelisted_threads(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-        log::trace!("Listing whitelisted threads: {:?}", msg);
+        tracing::trace!("Listing whitelisted threads");
         let threads = match self.db.get_whitelisted_threads(msg.chat.id.0).await {
             Ok(threads) => threads,
             Err(e) => {
-                log::error!("Error listing whitelisted threads: {:?}", e);
+                tracing::error!("Error listing whitelisted threads: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error listing whitelisted threads!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
@@ -568,52 +879,79 @@ impl AdminHandler {
         if let Some(thread_id) = msg.thread_id {
             reply = reply.message_thread_id(thread_id);
         }
-        reply.await?;
+        self.queue.send(reply).await?;
 
         Ok(())
     }
 
-    async fn become_admin(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-        log::trace!("Becoming admin: {:?}", msg);
+    async fn forget_me(&self, bot: &Bot, msg: &Message, user_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Forgetting user");
 
-        if msg.from.is_none() {
-            return Ok(());
+        let report = match self.db.forget_user(user_id).await {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::error!("Error forgetting user: {:?}", e);
+                self.reply(bot, msg, "Error forgetting you, nothing was removed!").await?;
+                return Ok(());
+            }
+        };
+
+        let is_admin = matches!(self.db.get_admin(user_id).await, Ok(Some(_)));
+
+        let mut message_lines = vec![];
+        if report.requests_deleted > 0 {
+            message_lines.push(format!("Deleted {} admin request(s) with your id and username.", report.requests_deleted));
+        }
+        if report.admin_name_removed {
+            message_lines.push("Removed your username from the admin list.".to_string());
+        }
+        if report.dialogs_deleted > 0 {
+            message_lines.push(format!("Ended {} open question(s) and deleted your answers.", report.dialogs_deleted));
+        }
+        if is_admin {
+            message_lines.push("You are still an admin, so your id is kept. Ask an admin to /remove_admin you to remove it.".to_string());
+        }
+        if report.ignore_entries_kept > 0 {
+            message_lines.push(format!("Kept {} ignore list entr(ies) about you, an admin has to /unignore you.", report.ignore_entries_kept));
+        }
+        if message_lines.is_empty() {
+            message_lines.push("Nothing about you was stored.".to_string());
         }
 
-        let user_id = msg.from.as_ref().unwrap().id.0;
-        let user_name= msg.from.as_ref().unwrap().username.as_deref();
+        self.reply(bot, msg, message_lines.join("\n")).await
+    }
 
+    /// Asks for admin rights for `user_id`, which is the chat's id when an
+    /// anonymous admin or a channel asks
+    async fn become_admin(&self, bot: &Bot, msg: &Message, user_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Becoming admin");
 
-        match self.db.create_become_admin_request(user_id as i64, user_name).await {
-            Ok(Some(request_id)) => {
+        match self.db.create_become_admin_request(user_id, identity::name(msg)).await {
+            Ok(request_id) => {
                 let mut reply = bot.send_message(msg.chat.id, format!("Request created, use /approve_become_admin <{}> to approve", request_id));
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
             }
-            Ok(None) => {
-                let mut reply = bot.send_message(msg.chat.id, "Error creating request");
-                if let Some(thread_id) = msg.thread_id {
-                    reply = reply.message_thread_id(thread_id);
-                }
-                reply.await?;
+            Err(DbError::AlreadyExists) => {
+                self.reply(bot, msg, "You already have a pending request, wait for an admin to answer it").await?;
             }
             Err(e) => {
-                log::error!("Error creating become admin request: {:?}", e);
+                tracing::error!("Error creating become admin request: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error creating become admin request!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn approve_become_admin(&self, bot: &Bot, msg: &Message, admin_id: u64) -> ResponseResult<()> {
-        log::trace!("Approving become admin: {:?}", msg);
+    async fn approve_become_admin(&self, bot: &Bot, msg: &Message, admin_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Approving become admin");
         let text = msg.text().unwrap_or_default().to_lowercase();
 
         let request_id = match text.split_whitespace().nth(1) {
@@ -625,7 +963,7 @@ impl AdminHandler {
                         if let Some(thread_id) = msg.thread_id {
                             reply = reply.message_thread_id(thread_id);
                         }
-                        reply.await?;
+                        self.queue.send(reply).await?;
                         return Ok(());
                     }
                 }
@@ -635,34 +973,43 @@ impl AdminHandler {
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
 
-        match self.db.approve_become_admin_request(&request_id, admin_id as i64).await {
+        match self.db.approve_become_admin_request(&request_id, admin_id).await {
             Ok(_) => {
                 let mut reply = bot.send_message(msg.chat.id, "Admin approved!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
+            }
+            Err(DbError::NotFound) => {
+                self.reply(bot, msg, format!("No request with id {}", request_id)).await?;
+            }
+            Err(DbError::NotPending) => {
+                self.reply(bot, msg, "This request was already answered").await?;
+            }
+            Err(DbError::AlreadyExists) => {
+                self.reply(bot, msg, "This user is already an admin").await?;
             }
             Err(e) => {
-                log::error!("Error approving become admin request: {:?}", e);
+                tracing::error!("Error approving become admin request: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error approving become admin request!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn reject_become_admin(&self, bot: &Bot, msg: &Message, _admin_id: u64) -> ResponseResult<()> {
-        log::trace!("Rejecting become admin: {:?}", msg);
+    async fn reject_become_admin(&self, bot: &Bot, msg: &Message, _admin_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Rejecting become admin");
         let text = msg.text().unwrap_or_default().to_lowercase();
 
         let request_id = match text.split_whitespace().nth(1) {
@@ -674,7 +1021,7 @@ impl AdminHandler {
                         if let Some(thread_id) = msg.thread_id {
                             reply = reply.message_thread_id(thread_id);
                         }
-                        reply.await?;
+                        self.queue.send(reply).await?;
                         return Ok(());
                     }
                 }
@@ -684,7 +1031,7 @@ impl AdminHandler {
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
@@ -695,15 +1042,21 @@ impl AdminHandler {
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
+            }
+            Err(DbError::NotFound) => {
+                self.reply(bot, msg, format!("No request with id {}", request_id)).await?;
+            }
+            Err(DbError::NotPending) => {
+                self.reply(bot, msg, "This request was already answered").await?;
             }
             Err(e) => {
-                log::error!("Error rejecting become admin request: {:?}", e);
+                tracing::error!("Error rejecting become admin request: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error rejecting become admin request!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
             }
         }
 
@@ -711,17 +1064,17 @@ impl AdminHandler {
     }
 
     async fn list_become_admin_requests(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-        log::trace!("Listing become admin requests: {:?}", msg);
+        tracing::trace!("Listing become admin requests");
 
         let requests = match self.db.get_become_admin_requests().await {
             Ok(requests) => requests,
             Err(e) => {
-                log::error!("Error listing become admin requests: {:?}", e);
+                tracing::error!("Error listing become admin requests: {:?}", e);
                 let mut reply = bot.send_message(msg.chat.id, "Error listing become admin requests!");
                 if let Some(thread_id) = msg.thread_id {
                     reply = reply.message_thread_id(thread_id);
                 }
-                reply.await?;
+                self.queue.send(reply).await?;
                 return Ok(());
             }
         };
@@ -735,45 +1088,750 @@ impl AdminHandler {
         if let Some(thread_id) = msg.thread_id {
             reply = reply.message_thread_id(thread_id);
         }
-        reply.await?;
+        self.queue.send(reply).await?;
 
         Ok(())
     }
 
-async fn help(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-    log::trace!("Help: {:?}", msg);
-
-    let help = r#"
-<b>🛠️ Admin Commands</b>
-/list_admins — List all registered admins.
-/remove_admin &lt;user_id&gt; — Remove an admin.
-/make_superadmin &lt;user_id&gt; — Promote an admin to superadmin.
-/become_admin — Request admin access.
-/approve_become_admin &lt;request_id&gt; — Approve a request to become admin.
-/reject_become_admin &lt;request_id&gt; — Reject a request.
-/list_become_admin_requests — List all pending admin requests.
-
-<b>✅ Whitelist Management</b>
-/whitelist_group — Whitelist the current group.
-/whitelist_thread — Whitelist the current thread.
-/unwhitelist_group [group_id] — Remove a group from whitelist.
-/unwhitelist_thread [group_id] [thread_id] — Remove a thread from whitelist.
-/list_whitelisted_groups — Show all whitelisted groups.
-/list_whitelisted_threads — Show whitelisted threads in this group.
+    async fn ignore(&self, bot: &Bot, msg: &Message, admin_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Ignoring user");
+        let text = msg.text().unwrap_or_default().to_lowercase();
+        let args = text.split_whitespace().skip(1).collect::<Vec<&str>>();
 
-<b>ℹ️ General</b>
-/help — Show this help message.
-"#;
+        let (user_id, args) = match command_target(msg, &args) {
+            Some(target) => target,
+            None => {
+                self.reply(bot, msg, "Invalid command, reply to a message or use /ignore <user_id> [here] [duration]").await?;
+                return Ok(());
+            }
+        };
 
-    let mut reply = bot.send_message(msg.chat.id, help)
-        .parse_mode(teloxide::types::ParseMode::Html);
+        let mut chat_id = None;
+        let mut expires_at = None;
+        for arg in args {
+            if *arg == "here" {
+                chat_id = Some(msg.chat.id.0);
+                continue;
+            }
 
-    if let Some(thread_id) = msg.thread_id {
-        reply = reply.message_thread_id(thread_id);
-    }
+            match parse_duration(arg) {
+                Some(duration) => expires_at = Some(Utc::now() + duration),
+                None => {
+                    self.reply(bot, msg, format!("Invalid argument: {arg}, expected \"here\" or a duration like 30m, 12h or 7d")).await?;
+                    return Ok(());
+                }
+            }
+        }
 
-    reply.await?;
+        // Ignoring an admin would lock them out of every command
+        match self.db.get_admin(user_id).await {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                self.reply(bot, msg, "Admins can't be ignored").await?;
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("Error checking if user is admin: {:?}", e);
+                self.reply(bot, msg, "Error ignoring user!").await?;
+                return Ok(());
+            }
+        }
 
-    Ok(())
-}
+        match self.db.ignore_user(user_id, chat_id, admin_id, expires_at).await {
+            Ok(_) => {
+                let scope = if chat_id.is_some() { "in this chat" } else { "everywhere" };
+                let until = match expires_at {
+                    Some(expires_at) => format!(" until {}", expires_at.format("%Y-%m-%d %H:%M UTC")),
+                    None => String::new(),
+                };
+                self.reply(bot, msg, format!("User {user_id} ignored {scope}{until}!")).await?;
+            }
+            Err(e) => {
+                tracing::error!("Error ignoring user: {:?}", e);
+                self.reply(bot, msg, "Error ignoring user!").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn unignore(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        tracing::trace!("Unignoring user");
+        let text = msg.text().unwrap_or_default().to_lowercase();
+        let args = text.split_whitespace().skip(1).collect::<Vec<&str>>();
+
+        let (user_id, args) = match command_target(msg, &args) {
+            Some(target) => target,
+            None => {
+                self.reply(bot, msg, "Invalid command, reply to a message or use /unignore <user_id> [here]").await?;
+                return Ok(());
+            }
+        };
+
+        let chat_id = match args {
+            [] => None,
+            ["here"] => Some(msg.chat.id.0),
+            _ => {
+                self.reply(bot, msg, "Invalid command, use /unignore <user_id> [here]").await?;
+                return Ok(());
+            }
+        };
+
+        match self.db.unignore_user(user_id, chat_id).await {
+            Ok(_) => self.reply(bot, msg, "User unignored!").await?,
+            Err(DbError::NotFound) => self.reply(bot, msg, "User was not ignored with that scope").await?,
+            Err(e) => {
+                tracing::error!("Error unignoring user: {:?}", e);
+                self.reply(bot, msg, "Error unignoring user!").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list_ignored(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        tracing::trace!("Listing ignored users");
+
+        let users = match self.db.get_ignored_users().await {
+            Ok(users) => users,
+            Err(e) => {
+                tracing::error!("Error listing ignored users: {:?}", e);
+                self.reply(bot, msg, "Error listing ignored users!").await?;
+                return Ok(());
+            }
+        };
+
+        let mut message_lines = vec!["Ignored users:".to_string()];
+        for user in users {
+            message_lines.push(format!("{:?}", user));
+        }
+
+        self.reply(bot, msg, message_lines.join("\n")).await
+    }
+
+    async fn exempt_bot(&self, bot: &Bot, msg: &Message, admin_id: i64) -> ResponseResult<()> {
+        tracing::trace!("Exempting bot");
+        let text = msg.text().unwrap_or_default().to_lowercase();
+        let args = text.split_whitespace().skip(1).collect::<Vec<&str>>();
+
+        let user_id = match command_target(msg, &args) {
+            Some((user_id, [])) => user_id,
+            _ => {
+                self.reply(bot, msg, "Invalid command, reply to a message or use /exempt_bot <bot_id>").await?;
+                return Ok(());
+            }
+        };
+
+        // Ids can't be checked, but a replied message tells who sent it
+        let target = replied(msg).filter(|reply| Identity::of(reply).is_some_and(|sender| sender.id() == user_id));
+        if let Some(reply) = target
+            && (reply.sender_chat.is_some() || !reply.from.as_ref().is_some_and(|from| from.is_bot))
+        {
+            self.reply(bot, msg, "Only bots can be exempted").await?;
+            return Ok(());
+        }
+
+        let name = target.and_then(identity::username);
+
+        match self.db.add_exempted_bot(user_id, admin_id, name).await {
+            Ok(_) => self.reply(bot, msg, "Bot exempted!").await?,
+            Err(DbError::AlreadyExists) => self.reply(bot, msg, format!("Bot {user_id} is already exempted")).await?,
+            Err(e) => {
+                tracing::error!("Error exempting bot: {:?}", e);
+                self.reply(bot, msg, "Error exempting bot!").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn unexempt_bot(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        tracing::trace!("Unexempting bot");
+        let text = msg.text().unwrap_or_default().to_lowercase();
+        let args = text.split_whitespace().skip(1).collect::<Vec<&str>>();
+
+        let user_id = match command_target(msg, &args) {
+            Some((user_id, [])) => user_id,
+            _ => {
+                self.reply(bot, msg, "Invalid command, reply to a message or use /unexempt_bot <bot_id>").await?;
+                return Ok(());
+            }
+        };
+
+        match self.db.remove_exempted_bot(user_id).await {
+            Ok(_) => self.reply(bot, msg, "Bot unexempted!").await?,
+            Err(DbError::NotFound) => self.reply(bot, msg, "Bot was not exempted").await?,
+            Err(e) => {
+                tracing::error!("Error unexempting bot: {:?}", e);
+                self.reply(bot, msg, "Error unexempting bot!").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list_exempted_bots(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        tracing::trace!("Listing exempted bots");
+
+        let bots = match self.db.get_exempted_bots().await {
+            Ok(bots) => bots,
+            Err(e) => {
+                tracing::error!("Error listing exempted bots: {:?}", e);
+                self.reply(bot, msg, "Error listing exempted bots!").await?;
+                return Ok(());
+            }
+        };
+
+        let mut message_lines = vec!["Exempted bots:".to_string()];
+        for exempted in bots {
+            message_lines.push(format!("{:?}", exempted));
+        }
+
+        self.reply(bot, msg, message_lines.join("\n")).await
+    }
+
+    async fn export_config(&self, bot: &Bot, msg: &Message, admin: db::Admin) -> ResponseResult<()> {
+        tracing::trace!("Exporting config");
+        if !admin.is_superadmin() {
+            self.reply(bot, msg, "You are not a superadmin").await?;
+            return Ok(());
+        }
+
+        let doc = match ConfigDocument::from_db(&*self.db).await {
+            Ok(doc) => doc,
+            Err(e) => {
+                tracing::error!("Error exporting config: {:?}", e);
+                self.reply(bot, msg, "Error exporting config!").await?;
+                return Ok(());
+            }
+        };
+
+        let file = InputFile::memory(doc.to_json()).file_name("telos-config.json");
+        let mut request = bot.send_document(msg.chat.id, file);
+        if let Some(thread_id) = msg.thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        self.queue.send(request).await?;
+
+        Ok(())
+    }
+
+    async fn import_config(&self, bot: &Bot, msg: &Message, admin: db::Admin) -> ResponseResult<()> {
+        tracing::trace!("Importing config");
+        if !admin.is_superadmin() {
+            self.reply(bot, msg, "You are not a superadmin").await?;
+            return Ok(());
+        }
+
+        let document = match msg.reply_to_message().and_then(|m| m.document()) {
+            Some(document) => document,
+            None => {
+                self.reply(bot, msg, "Invalid command, reply to an exported config file with /import_config").await?;
+                return Ok(());
+            }
+        };
+
+        if document.file.size > MAX_CONFIG_SIZE {
+            self.reply(bot, msg, "Config file is too large").await?;
+            return Ok(());
+        }
+
+        let file = bot.get_file(document.file.id.clone()).await?;
+        let mut data = Vec::new();
+        if let Err(e) = bot.download_file(&file.path, &mut data).await {
+            tracing::error!("Error downloading config file: {:?}", e);
+            self.reply(bot, msg, "Error downloading config file!").await?;
+            return Ok(());
+        }
+
+        let doc = match ConfigDocument::from_json(&data) {
+            Ok(doc) => doc,
+            Err(e) => {
+                self.reply(bot, msg, e).await?;
+                return Ok(());
+            }
+        };
+
+        if !doc.admins.iter().any(|a| a.user_id == admin.user_id && a.is_superadmin()) {
+            self.reply(bot, msg, "Refusing to import a config in which you are not a superadmin").await?;
+            return Ok(());
+        }
+
+        let current = match ConfigDocument::from_db(&*self.db).await {
+            Ok(current) => current,
+            Err(e) => {
+                tracing::error!("Error exporting config: {:?}", e);
+                self.reply(bot, msg, "Error reading current config!").await?;
+                return Ok(());
+            }
+        };
+
+        let diff = current.diff(&doc);
+        if diff.is_empty() {
+            self.reply(bot, msg, "Config is already up to date, nothing to import").await?;
+            return Ok(());
+        }
+
+        let mut summary = format!("Import config with {} change(s)?\n{}", diff.len(), diff.join("\n"));
+        if summary.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !summary.is_char_boundary(end) {
+                end -= 1;
+            }
+            summary.truncate(end);
+            summary.push_str("\n…");
+        }
+
+        self.request_confirmation(bot, msg, admin.user_id, PendingAction::ImportConfig { doc: Box::new(doc) }, summary).await
+    }
+
+    async fn backup(&self, bot: &Bot, msg: &Message, admin: db::Admin) -> ResponseResult<()> {
+        tracing::trace!("Backing up database");
+        if !admin.is_superadmin() {
+            self.reply(bot, msg, "You are not a superadmin").await?;
+            return Ok(());
+        }
+        if admin.is_chat() {
+            self.reply(bot, msg, "Backups are only sent in private, ask without posting as the chat").await?;
+            return Ok(());
+        }
+
+        let path = match backup::snapshot(&*self.db, &std::env::temp_dir().join(Uuid::now_v7().to_string())).await {
+            Ok(path) => path,
+            Err(e) => {
+                tracing::error!("Error backing up database: {:?}", e);
+                self.reply(bot, msg, "Error backing up database!").await?;
+                return Ok(());
+            }
+        };
+
+        // The snapshot holds every user id we know, so it only goes to the admin
+        let sent = self.queue
+            .send(bot.send_document(UserId(admin.user_id as u64), InputFile::file(&path)))
+            .await;
+
+        if let Some(dir) = path.parent()
+            && let Err(e) = std::fs::remove_dir_all(dir)
+        {
+            tracing::error!("Error removing temporary backup: {:?}", e);
+        }
+
+        match sent {
+            Ok(_) => {
+                if !msg.chat.is_private() {
+                    self.reply(bot, msg, "Backup sent in private!").await?;
+                }
+            }
+            Err(e) => {
+                tracing::error!("Error sending backup: {:?}", e);
+                self.reply(bot, msg, "Error sending backup, start a private chat with me first!").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn cache_stats(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        tracing::trace!("Cache stats");
+
+        let stats = match &self.cache_stats {
+            Some(stats) => stats,
+            None => {
+                self.reply(bot, msg, "Caching is disabled").await?;
+                return Ok(());
+            }
+        };
+
+        let lookups = stats.hits() + stats.misses();
+        let ratio = if lookups == 0 { 0.0 } else { stats.hits() as f64 * 100.0 / lookups as f64 };
+
+        self.reply(bot, msg, format!("Cache hits: {}\nCache misses: {}\nHit ratio: {:.1}%", stats.hits(), stats.misses(), ratio)).await
+    }
+}
+
+#[async_trait]
+impl Module for AdminHandler {
+    fn name(&self) -> &'static str {
+        "admin"
+    }
+
+    /// Admins must be able to whitelist a chat before anything else runs in it
+    fn required(&self) -> bool {
+        true
+    }
+
+    fn filter(&self, msg: &Message) -> bool {
+        Identity::of(msg).is_some()
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        COMMANDS
+    }
+
+    async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        AdminHandler::handle(self, bot, msg).await
+    }
+
+    fn buttons(&self) -> &'static [&'static str] {
+        &[CONFIRM, CANCEL]
+    }
+
+    async fn press(&self, bot: &Bot, q: &CallbackQuery) -> ResponseResult<Answer> {
+        AdminHandler::press(self, bot, q).await
+    }
+
+    fn dialogs(&self) -> &'static [Dialog] {
+        DIALOGS
+    }
+
+    async fn finish(&self, bot: &Bot, msg: &Message, dialog: &str, answers: &[String]) -> ResponseResult<()> {
+        AdminHandler::finish(self, bot, msg, dialog, answers).await
+    }
+}
+
+
+/// Resolves the user or chat a command acts on, either the first argument
+/// when it is an id or the sender of the replied message, returning the
+/// remaining arguments
+fn command_target<'a, 'b>(msg: &Message, args: &'a [&'b str]) -> Option<(i64, &'a [&'b str])> {
+    if let Some((first, rest)) = args.split_first()
+        && let Ok(user_id) = first.parse::<i64>()
+    {
+        return Some((user_id, rest));
+    }
+
+    let sender = replied(msg).and_then(Identity::of)?;
+    Some((sender.id(), args))
+}
+
+/// The message `msg` replies to, leaving out the start of the forum topic
+/// it was sent in, which every message of a topic replies to
+fn replied(msg: &Message) -> Option<&Message> {
+    let reply = msg.reply_to_message()?;
+    let topic_start = msg.is_topic_message && msg.thread_id.is_some_and(|thread_id| thread_id.0 == reply.id);
+    (!topic_start).then_some(reply)
+}
+
+/// Parses durations like "30m", "12h", "7d" or "2w"
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = text.chars().last()?;
+    let amount = text[..text.len() - unit.len_utf8()].parse::<i64>().ok()?;
+    if amount <= 0 {
+        return None;
+    }
+
+    match unit {
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{Value, json};
+    use teloxide::prelude::*;
+
+    use super::{AdminHandler, command_target};
+    use crate::module::Registry;
+    use crate::storage::{AdminStore, IgnoreStore, RequestStore, WhitelistStore, memory::MemoryStorage};
+    use crate::testing::{Calls, fake_api};
+
+    const SUPERADMIN: u64 = 1;
+    const GROUP: i64 = -100;
+
+    fn user(id: u64, is_bot: bool) -> Value {
+        json!({"id": id, "is_bot": is_bot, "first_name": format!("user{id}")})
+    }
+
+    fn message(chat: Value, from: Value, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 10,
+            "date": 0,
+            "chat": chat,
+            "from": from,
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    fn private(from: u64, text: &str) -> Message {
+        message(json!({"id": from, "type": "private", "first_name": "user"}), user(from, false), text)
+    }
+
+    fn group(from: u64, text: &str) -> Message {
+        message(json!({"id": GROUP, "type": "supergroup", "title": "group"}), user(from, false), text)
+    }
+
+    fn callback(from: u64, data: &str) -> CallbackQuery {
+        callback_in(from as i64, from, data)
+    }
+
+    fn callback_in(chat: i64, from: u64, data: &str) -> CallbackQuery {
+        serde_json::from_value(json!({
+            "id": "query",
+            "from": user(from, false),
+            "chat_instance": "instance",
+            "data": data,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": {"id": chat, "type": "private", "first_name": "user"},
+                "text": "summary",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn setup() -> (Arc<MemoryStorage>, AdminHandler) {
+        let storage = Arc::new(MemoryStorage::with_superadmin(SUPERADMIN as i64, Some("root")));
+        let handler = AdminHandler::new(storage.clone(), Arc::default()).unwrap();
+        (storage, handler)
+    }
+
+    /// Callback data of the Confirm (0) or Cancel (1) button last sent
+    fn button_data(calls: &Calls, button: usize) -> String {
+        let calls = calls.lock().unwrap();
+        let (_, body) = calls.iter().rev().find(|(method, _)| method == "SendMessage").unwrap();
+        body["reply_markup"]["inline_keyboard"][0][button]["callback_data"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn whitelisting_a_group_allows_its_messages() {
+        let (bot, _) = fake_api().await;
+        let (storage, handler) = setup();
+        let registry = Registry::new(storage.clone());
+
+        assert!(!registry.check_whitelist(&group(2, "hello")).await.unwrap());
+
+        handler.handle(&bot, &group(SUPERADMIN, "/whitelist_group")).await.unwrap();
+
+        assert!(storage.is_group_whitelisted(GROUP).await.unwrap());
+        assert!(registry.check_whitelist(&group(2, "hello")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn commands_from_non_admins_are_ignored() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+
+        handler.handle(&bot, &group(2, "/whitelist_group")).await.unwrap();
+
+        assert!(!storage.is_group_whitelisted(GROUP).await.unwrap());
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn approved_requests_create_admins() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+
+        handler.handle(&bot, &private(2, "/become_admin")).await.unwrap();
+        let requests = storage.get_become_admin_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let approve = format!("/approve_become_admin {}", requests[0].request_id);
+        handler.handle(&bot, &private(SUPERADMIN, &approve)).await.unwrap();
+
+        let admin = storage.get_admin(2).await.unwrap().unwrap();
+        assert_eq!(admin.added_by, Some(SUPERADMIN as i64));
+
+        handler.handle(&bot, &private(SUPERADMIN, &approve)).await.unwrap();
+        let calls = calls.lock().unwrap();
+        let (_, body) = calls.iter().rev().find(|(method, _)| method == "SendMessage").unwrap();
+        assert_eq!(body["text"], "This request was already answered");
+    }
+
+    #[tokio::test]
+    async fn removing_an_admin_waits_for_the_issuer_to_confirm() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+        storage.add_admin(2, Some(SUPERADMIN as i64), None).await.unwrap();
+        storage.add_admin(3, Some(2), None).await.unwrap();
+
+        handler.handle(&bot, &private(SUPERADMIN, "/remove_admin 2")).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_some());
+
+        let data = button_data(&calls, 0);
+
+        handler.press(&bot, &callback(3, &data)).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_some());
+
+        handler.press(&bot, &callback(SUPERADMIN, &data)).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_none());
+        assert!(storage.get_admin(3).await.unwrap().is_none());
+
+        // A confirmation can only be used once
+        storage.add_admin(2, Some(SUPERADMIN as i64), None).await.unwrap();
+        handler.press(&bot, &callback(SUPERADMIN, &data)).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn admins_only_see_confirmations_for_admins_they_added() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+        storage.add_admin(2, Some(SUPERADMIN as i64), Some("two")).await.unwrap();
+        storage.add_admin(3, Some(SUPERADMIN as i64), Some("three")).await.unwrap();
+        storage.add_admin(4, Some(2), Some("four")).await.unwrap();
+        storage.add_admin(5, Some(4), Some("five")).await.unwrap();
+
+        handler.handle(&bot, &private(2, "/remove_admin 3")).await.unwrap();
+        handler.handle(&bot, &private(2, "/remove_admin 9")).await.unwrap();
+        handler.handle(&bot, &private(2, "/remove_admin 5")).await.unwrap();
+
+        let calls = calls.lock().unwrap();
+        let sent: Vec<&Value> = calls.iter().filter(|(method, _)| method == "SendMessage").map(|(_, body)| body).collect();
+        assert_eq!(sent[0]["text"], "You can only remove admins you added, directly or through others");
+        assert!(sent[0]["reply_markup"].is_null());
+        assert_eq!(sent[1]["text"], sent[0]["text"]);
+        assert!(sent[2]["text"].as_str().unwrap().starts_with("Remove admin five (5)?"));
+    }
+
+    #[tokio::test]
+    async fn confirmations_only_act_on_what_was_stored() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+        storage.add_admin(2, Some(SUPERADMIN as i64), None).await.unwrap();
+        storage.add_admin(3, Some(SUPERADMIN as i64), None).await.unwrap();
+
+        handler.handle(&bot, &group(SUPERADMIN, "/remove_admin 2")).await.unwrap();
+        let data = button_data(&calls, 0);
+        assert!(!data.contains("RemoveAdmin"));
+
+        // Another admin claiming the confirmation as theirs
+        let forged = r#"confirm:[3,{"RemoveAdmin":{"user_id":2}}]"#;
+        handler.press(&bot, &callback_in(GROUP, 3, forged)).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_some());
+
+        handler.press(&bot, &callback_in(GROUP, 3, &data)).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_some());
+
+        handler.press(&bot, &callback_in(GROUP, SUPERADMIN, &data)).await.unwrap();
+        assert!(storage.get_admin(2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn cancelled_confirmations_change_nothing() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+        storage.add_whitelisted_group(GROUP, SUPERADMIN as i64, Some("group")).await.unwrap();
+
+        handler.handle(&bot, &private(SUPERADMIN, &format!("/unwhitelist_group {GROUP}"))).await.unwrap();
+
+        let data = button_data(&calls, 1);
+        handler.press(&bot, &callback(SUPERADMIN, &data)).await.unwrap();
+
+        assert!(storage.is_group_whitelisted(GROUP).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn forget_me_removes_requests_and_names() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+
+        handler.handle(&bot, &private(2, "/become_admin")).await.unwrap();
+        handler.handle(&bot, &private(2, "/forget_me")).await.unwrap();
+        assert!(storage.get_become_admin_requests().await.unwrap().is_empty());
+
+        handler.handle(&bot, &private(SUPERADMIN, "/forget_me")).await.unwrap();
+        let admin = storage.get_admin(SUPERADMIN as i64).await.unwrap().unwrap();
+        assert_eq!(admin.name, None);
+
+        let calls = calls.lock().unwrap();
+        let replies: Vec<&str> = calls
+            .iter()
+            .filter(|(method, _)| method == "SendMessage")
+            .map(|(_, body)| body["text"].as_str().unwrap())
+            .collect();
+        assert!(replies[1].starts_with("Deleted 1 admin request(s)"));
+        assert!(replies[2].starts_with("Removed your username from the admin list."));
+    }
+
+    /// A message in forum topic 5 of the group replying to `reply`
+    fn in_topic(from: u64, text: &str, reply: Value) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 10,
+            "message_thread_id": 5,
+            "is_topic_message": true,
+            "date": 0,
+            "chat": {"id": GROUP, "type": "supergroup", "title": "group", "is_forum": true},
+            "from": user(from, false),
+            "text": text,
+            "reply_to_message": reply,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn command_targets_are_ids_or_replied_senders() {
+        let chat = json!({"id": GROUP, "type": "supergroup", "title": "group", "is_forum": true});
+        let topic_start = json!({
+            "message_id": 5,
+            "message_thread_id": 5,
+            "date": 0,
+            "chat": chat,
+            "from": user(9, false),
+            "forum_topic_created": {"name": "topic", "icon_color": 7322096},
+        });
+        let answered = json!({"message_id": 8, "message_thread_id": 5, "date": 0, "chat": chat, "from": user(3, false), "text": "hi"});
+
+        assert_eq!(command_target(&group(SUPERADMIN, "/ignore 2 here"), &["2", "here"]), Some((2, &["here"][..])));
+
+        // Every message of a topic replies to its start, which names no one
+        assert_eq!(command_target(&in_topic(SUPERADMIN, "/ignore 2", topic_start.clone()), &["2"]), Some((2, &[][..])));
+        assert_eq!(command_target(&in_topic(SUPERADMIN, "/ignore", topic_start), &[]), None);
+
+        let reply = in_topic(SUPERADMIN, "/ignore here", answered);
+        assert_eq!(command_target(&reply, &["here"]), Some((3, &["here"][..])));
+        assert_eq!(command_target(&reply, &["4"]), Some((4, &[][..])));
+    }
+
+    #[tokio::test]
+    async fn only_bots_are_exempted() {
+        let (bot, calls) = fake_api().await;
+        let (storage, handler) = setup();
+        let chat = json!({"id": GROUP, "type": "supergroup", "title": "group"});
+        let reply_to = |from: Value| {
+            let mut msg = serde_json::to_value(group(SUPERADMIN, "/exempt_bot")).unwrap();
+            msg["reply_to_message"] = json!({"message_id": 3, "date": 0, "chat": chat, "from": from, "text": "hi"});
+            serde_json::from_value::<Message>(msg).unwrap()
+        };
+
+        handler.handle(&bot, &reply_to(user(2, false))).await.unwrap();
+        assert!(!storage.is_bot_exempted(2).await.unwrap());
+
+        handler.handle(&bot, &reply_to(user(50, true))).await.unwrap();
+        handler.handle(&bot, &group(SUPERADMIN, "/exempt_bot 50")).await.unwrap();
+        assert!(storage.is_bot_exempted(50).await.unwrap());
+
+        let calls = calls.lock().unwrap();
+        let replies: Vec<&str> = calls.iter().map(|(_, body)| body["text"].as_str().unwrap()).collect();
+        assert_eq!(replies, ["Only bots can be exempted", "Bot exempted!", "Bot 50 is already exempted"]);
+    }
+
+    #[tokio::test]
+    async fn bots_and_ignored_users_are_dropped() {
+        let (bot, _) = fake_api().await;
+        let (storage, handler) = setup();
+        let registry = Registry::new(storage.clone());
+
+        let from_bot = message(json!({"id": GROUP, "type": "supergroup", "title": "group"}), user(50, true), "hi");
+        assert!(registry.should_ignore(&from_bot).await);
+
+        storage.add_exempted_bot(50, SUPERADMIN as i64, None).await.unwrap();
+        assert!(!registry.should_ignore(&from_bot).await);
+
+        handler.handle(&bot, &group(SUPERADMIN, "/ignore 2 here")).await.unwrap();
+        assert!(registry.should_ignore(&group(2, "hi")).await);
+        assert!(!registry.should_ignore(&private(2, "hi")).await);
+
+        // Admins can't be ignored, or they would lose access to every command
+        handler.handle(&bot, &group(SUPERADMIN, "/ignore 1")).await.unwrap();
+        assert!(!storage.is_user_ignored(SUPERADMIN as i64, GROUP).await.unwrap());
+    }
 }
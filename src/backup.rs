@@ -0,0 +1,302 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc};
+
+use crate::{config::BackupSettings, db, shutdown::Shutdown, storage::Storage};
+
+const BACKUP_PREFIX: &str = "telos-";
+const BACKUP_SUFFIX: &str = ".sqlite";
+/// Down to the microsecond, so backups taken in the same second don't collide
+const BACKUP_TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.6f";
+/// Reads names with or without the fraction, the latter from older versions
+const BACKUP_TIME_PARSE_FORMAT: &str = "%Y%m%d-%H%M%S%.f";
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+#[derive(Clone, Debug)]
+pub struct BackupSchedule {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+impl BackupSchedule {
+    /// Builds the schedule from the `[backup]` settings. Scheduled backups
+    /// are disabled unless `backup.dir` is set.
+    pub fn from_config(settings: &BackupSettings) -> Result<Option<Self>, String> {
+        let dir = match &settings.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(None),
+        };
+
+        if settings.interval_hours == 0 {
+            return Err("backup.interval_hours must be at least 1".to_string());
+        }
+
+        Ok(Some(Self {
+            dir,
+            interval: Duration::from_secs(settings.interval_hours.saturating_mul(60 * 60)),
+            keep_daily: settings.keep_daily,
+            keep_weekly: settings.keep_weekly,
+        }))
+    }
+}
+
+/// Snapshots the database into a new file inside `dir`, returning its path
+pub async fn snapshot(db: &dyn Storage, dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    std::fs::create_dir_all(dir)?;
+
+    let name = format!("{BACKUP_PREFIX}{}{BACKUP_SUFFIX}", Utc::now().format(BACKUP_TIME_FORMAT));
+    let path = dir.join(name);
+    let path_str = path.to_str().ok_or("Backup path is not valid UTF-8")?;
+
+    db.vacuum_into(path_str).await?;
+
+    Ok(path)
+}
+
+/// Takes a backup every `schedule.interval`, pruning old ones after each run
+pub async fn run_schedule(db: db::DB, schedule: BackupSchedule, shutdown: Shutdown) {
+    tracing::info!("Scheduled backups enabled in {:?}", schedule.dir);
+
+    let mut interval = tokio::time::interval(schedule.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown.triggered() => return,
+        }
+
+        match snapshot(&db, &schedule.dir).await {
+            Ok(path) => tracing::info!("Backup written to {:?}", path),
+            Err(e) => {
+                tracing::error!("Error writing scheduled backup: {:?}", e);
+                continue;
+            }
+        }
+
+        match prune(&schedule.dir, schedule.keep_daily, schedule.keep_weekly) {
+            Ok(removed) => {
+                for path in removed {
+                    tracing::info!("Removed old backup {:?}", path);
+                }
+            }
+            Err(e) => tracing::error!("Error pruning backups: {:?}", e),
+        }
+    }
+}
+
+/// Removes backups that are neither the latest of one of the last
+/// `keep_daily` days nor the latest of one of the last `keep_weekly` weeks
+pub fn prune(dir: &Path, keep_daily: usize, keep_weekly: usize) -> std::io::Result<Vec<PathBuf>> {
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(taken_at) = backup_time(&path) {
+            backups.push((taken_at, path));
+        }
+    }
+
+    // Newest first, so the first backup seen for a day or week is the one kept
+    backups.sort_by_key(|b| std::cmp::Reverse(b.0));
+
+    let mut days: Vec<NaiveDate> = vec![];
+    let mut weeks: Vec<(i32, u32)> = vec![];
+    let mut keep = HashSet::new();
+    for (taken_at, path) in &backups {
+        let day = taken_at.date();
+        if !days.contains(&day) && days.len() < keep_daily {
+            days.push(day);
+            keep.insert(path.clone());
+        }
+
+        let week = (day.iso_week().year(), day.iso_week().week());
+        if !weeks.contains(&week) && weeks.len() < keep_weekly {
+            weeks.push(week);
+            keep.insert(path.clone());
+        }
+    }
+
+    let mut removed = vec![];
+    for (_, path) in backups {
+        if !keep.contains(&path) {
+            std::fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+
+    Ok(removed)
+}
+
+fn backup_time(path: &Path) -> Option<NaiveDateTime> {
+    let name = path.file_name()?.to_str()?;
+    let time = name.strip_prefix(BACKUP_PREFIX)?.strip_suffix(BACKUP_SUFFIX)?;
+    NaiveDateTime::parse_from_str(time, BACKUP_TIME_PARSE_FORMAT).ok()
+}
+
+/// Extracts the file behind a SQLite connection string such as
+/// `sqlite://db.sqlite?mode=rwc` or `/data/db.sqlite`
+pub fn database_file(url: &str) -> Option<PathBuf> {
+    if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+        return None;
+    }
+
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .unwrap_or(url);
+    let path = path.split('?').next().unwrap_or_default();
+
+    if path.is_empty() || path == ":memory:" {
+        return None;
+    }
+
+    Some(PathBuf::from(path))
+}
+
+/// Replaces the database file with a snapshot before the pool is opened. The
+/// current file is kept next to it with a `.before-restore-<time>` suffix.
+pub fn restore(snapshot: &Path, database: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut header = [0u8; 16];
+    let mut file = std::fs::File::open(snapshot)?;
+    std::io::Read::read_exact(&mut file, &mut header)
+        .map_err(|_| format!("{snapshot:?} is not a SQLite database"))?;
+    if header != SQLITE_HEADER {
+        return Err(format!("{snapshot:?} is not a SQLite database").into());
+    }
+
+    if database.exists() {
+        let mut kept = database.as_os_str().to_owned();
+        kept.push(format!(".before-restore-{}", Utc::now().format(BACKUP_TIME_FORMAT)));
+        std::fs::rename(database, &kept)?;
+        tracing::info!("Previous database moved to {:?}", kept);
+    }
+
+    // Leftover journals would be replayed on top of the restored file
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let mut journal = database.as_os_str().to_owned();
+        journal.push(suffix);
+        if Path::new(&journal).exists() {
+            std::fs::remove_file(&journal)?;
+        }
+    }
+
+    std::fs::copy(snapshot, database)?;
+    tracing::info!("Database restored from {:?}", snapshot);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::prune;
+    #[cfg(not(feature = "postgres"))]
+    use super::{backup_time, snapshot};
+    #[cfg(not(feature = "postgres"))]
+    use crate::db::DB;
+
+    /// A new directory with an empty file for each name
+    fn backups(names: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("telos-backups-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir(&dir).unwrap();
+        for name in names {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        dir
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> =
+            std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn pruning_keeps_the_latest_backup_of_each_day_and_week() {
+        let dir = backups(&[
+            // Week 42 to 44 of 2025
+            "telos-20251013-120000.sqlite",
+            "telos-20251020-120000.sqlite",
+            "telos-20251027-120000.sqlite",
+            "telos-20251101-120000.sqlite",
+            // Week 45, with two on the Wednesday
+            "telos-20251103-120000.sqlite",
+            "telos-20251105-090000.sqlite",
+            "telos-20251105-180000.250000.sqlite",
+            "telos-20251107-120000.sqlite",
+            "notes.txt",
+        ]);
+
+        let mut removed: Vec<String> = prune(&dir, 3, 3)
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        removed.sort();
+
+        assert_eq!(removed, [
+            "telos-20251013-120000.sqlite",
+            "telos-20251027-120000.sqlite",
+            "telos-20251105-090000.sqlite",
+        ]);
+        assert_eq!(names(&dir), [
+            "notes.txt",
+            "telos-20251020-120000.sqlite",
+            "telos-20251101-120000.sqlite",
+            "telos-20251103-120000.sqlite",
+            "telos-20251105-180000.250000.sqlite",
+            "telos-20251107-120000.sqlite",
+        ]);
+
+        // Pruning again has nothing left to remove
+        assert!(prune(&dir, 3, 3).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pruning_with_nothing_to_keep_removes_every_backup() {
+        let dir = backups(&["telos-20251105-090000.sqlite", "telos-20251105-180000.sqlite", "db.sqlite"]);
+
+        assert_eq!(prune(&dir, 0, 0).unwrap().len(), 2);
+        assert_eq!(names(&dir), ["db.sqlite"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn daily_backups_also_count_as_the_weekly_one() {
+        // Sunday and Monday fall in different weeks
+        let dir = backups(&["telos-20251101-120000.sqlite", "telos-20251102-120000.sqlite", "telos-20251103-120000.sqlite"]);
+
+        let removed = prune(&dir, 1, 2).unwrap();
+        assert_eq!(removed, [dir.join("telos-20251101-120000.sqlite")]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    #[tokio::test]
+    async fn backups_taken_in_a_row_get_their_own_files() {        // A file, as snapshots of an in-memory database stay in memory
+        let dir = std::env::temp_dir().join(format!("telos-backups-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir(&dir).unwrap();
+        let db = DB::new(&format!("sqlite://{}?mode=rwc", dir.join("db.sqlite").display())).await.unwrap();
+        db.migrate().await.unwrap();
+
+        let backups = dir.join("backups");
+        let first = snapshot(&db, &backups).await.unwrap();
+        let second = snapshot(&db, &backups).await.unwrap();
+        assert_ne!(first, second);
+        assert!(backup_time(&first).unwrap() < backup_time(&second).unwrap());
+        assert!(first.exists() && second.exists());
+
+        // Names from before the fraction was added are still pruned
+        assert!(backup_time(Path::new("telos-20251101-120000.sqlite")).is_some());
+
+        db.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
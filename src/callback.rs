@@ -0,0 +1,267 @@
+//! Inline keyboards and the presses of their buttons. A button's callback
+//! data is the prefix its presses are routed by and a key, like
+//! `cancel#0199f4…`. Its typed payload stays here with the keyboard, since
+//! clients can send any callback data they like: a press only counts when
+//! its key belongs to the keyboard of the message it was pressed on.
+//! Keyboards expire: once their time is up the buttons are taken off the
+//! message and presses are refused, which also covers keyboards sent before
+//! a restart.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+use teloxide::{
+    payloads::SendMessage,
+    prelude::*,
+    requests::JsonRequest,
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, MessageId},
+};
+use uuid::Uuid;
+
+use crate::queue::Queue;
+
+/// Longest callback data Telegram accepts, in bytes
+pub const MAX_DATA_LEN: usize = 64;
+
+/// The prefix of callback data, which tells who handles the press
+pub fn prefix(data: &str) -> &str {
+    data.split('#').next().unwrap_or_default()
+}
+
+pub struct Button {
+    label: String,
+    prefix: &'static str,
+    payload: String,
+}
+
+impl Button {
+    pub fn new(label: impl Into<String>, prefix: &'static str, payload: &impl Serialize) -> Self {
+        let payload = serde_json::to_string(payload).expect("callback payloads serialize to JSON");
+        Self { label: label.into(), prefix, payload }
+    }
+}
+
+/// How the query of a press is answered
+#[derive(Default)]
+pub struct Answer {
+    pub text: Option<String>,
+    /// Shows the text in a dialog instead of a notification
+    pub alert: bool,
+}
+
+impl Answer {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self { text: Some(text.into()), alert: false }
+    }
+
+    pub fn alert(text: impl Into<String>) -> Self {
+        Self { text: Some(text.into()), alert: true }
+    }
+
+    pub fn expired() -> Self {
+        Self::text("This button has expired")
+    }
+}
+
+/// A press of a button on a keyboard that is still live
+pub struct Press<'a> {
+    pub query: &'a CallbackQuery,
+    prefix: &'a str,
+    payload: String,
+}
+
+impl Press<'_> {
+    pub fn prefix(&self) -> &str {
+        self.prefix
+    }
+
+    /// The payload of the button, None if it isn't a `T`
+    pub fn data<T: DeserializeOwned>(&self) -> Option<T> {
+        serde_json::from_str(&self.payload).ok()
+    }
+}
+
+struct Live {
+    expires_at: Instant,
+    /// Keys of its buttons' payloads
+    keys: Vec<String>,
+}
+
+/// Keyboards a handler sent that still work
+#[derive(Default)]
+pub struct Callbacks {
+    stored: Mutex<HashMap<String, String>>,
+    live: Mutex<HashMap<(ChatId, MessageId), Live>>,
+}
+
+impl Callbacks {
+    /// Sends `request` with `rows` of buttons that work for `ttl`. When it
+    /// passes, `expired` is added under the text and the buttons go away.
+    pub async fn send(
+        self: &Arc<Self>,
+        queue: &Arc<Queue>,
+        bot: &Bot,
+        request: JsonRequest<SendMessage>,
+        rows: Vec<Vec<Button>>,
+        ttl: Duration,
+        expired: &str,
+    ) -> ResponseResult<Message> {
+        let mut keys = Vec::new();
+        let keyboard: Vec<Vec<InlineKeyboardButton>> = rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|button| self.encode(button, &mut keys)).collect())
+            .collect();
+
+        let sent = match queue.send(request.reply_markup(InlineKeyboardMarkup::new(keyboard))).await {
+            Ok(sent) => sent,
+            Err(e) => {
+                self.forget(keys);
+                return Err(e);
+            }
+        };
+
+        let key = (sent.chat.id, sent.id);
+        self.live.lock().unwrap().insert(key, Live { expires_at: Instant::now() + ttl, keys });
+
+        let callbacks = Arc::clone(self);
+        let queue = Arc::clone(queue);
+        let bot = bot.clone();
+        let text = format!("{}\n\n{expired}", sent.text().unwrap_or_default());
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            if !callbacks.retire(key) {
+                return;
+            }
+
+            if let Err(e) = queue.send(bot.edit_message_text(key.0, key.1, text)).await {
+                tracing::error!("Error expiring keyboard: {:?}", e);
+            }
+        });
+
+        Ok(sent)
+    }
+
+    fn encode(&self, button: Button, keys: &mut Vec<String>) -> InlineKeyboardButton {
+        let key = Uuid::now_v7().simple().to_string();
+        let data = format!("{}#{key}", button.prefix);
+        assert!(data.len() <= MAX_DATA_LEN, "button prefix {} is too long", button.prefix);
+        self.stored.lock().unwrap().insert(key.clone(), button.payload);
+        keys.push(key);
+        InlineKeyboardButton::callback(button.label, data)
+    }
+
+    /// The button pressed in `query`, None when its keyboard is no longer live
+    pub fn press<'a>(&self, query: &'a CallbackQuery) -> Option<Press<'a>> {
+        let message = query.message.as_ref()?;
+        let message_key = (message.chat().id, message.id());
+        let expires_at = self.live.lock().unwrap().get(&message_key)?.expires_at;
+        if expires_at <= Instant::now() {
+            self.retire(message_key);
+            return None;
+        }
+
+        let data = query.data.as_deref()?;
+        let (prefix, key) = data.split_once('#')?;
+        if !self.live.lock().unwrap().get(&message_key)?.keys.iter().any(|live| live == key) {
+            return None;
+        }
+        let payload = self.stored.lock().unwrap().get(key)?.clone();
+
+        Some(Press { query, prefix, payload })
+    }
+
+    /// Stops the buttons of the pressed keyboard from working, for keyboards
+    /// that are answered once
+    pub fn finish(&self, press: &Press<'_>) {
+        if let Some(message) = &press.query.message {
+            self.retire((message.chat().id, message.id()));
+        }
+    }
+
+    /// Forgets a keyboard, telling whether it was still live
+    fn retire(&self, key: (ChatId, MessageId)) -> bool {
+        let live = self.live.lock().unwrap().remove(&key);
+        match live {
+            Some(live) => {
+                self.forget(live.keys);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forget(&self, keys: Vec<String>) {
+        let mut stored = self.stored.lock().unwrap();
+        for key in keys {
+            stored.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use serde_json::json;
+    use teloxide::prelude::*;
+
+    use super::{Button, Callbacks, MAX_DATA_LEN};
+    use crate::testing::fake_api;
+
+    fn press(data: &str) -> CallbackQuery {
+        serde_json::from_value(json!({
+            "id": "query",
+            "from": {"id": 7, "is_bot": false, "first_name": "user"},
+            "chat_instance": "instance",
+            "data": data,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": {"id": 7, "type": "private", "first_name": "user"},
+                "text": "pick one",
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn payloads_stay_with_the_keyboard_until_it_expires() {
+        let (bot, calls) = fake_api().await;
+        let callbacks = Arc::new(Callbacks::default());
+        let long = "x".repeat(100);
+        let rows = vec![vec![Button::new("Short", "pick", &(1, "a")), Button::new("Long", "pick", &long)]];
+
+        let request = bot.send_message(ChatId(7), "pick one");
+        let ttl = Duration::from_millis(200);
+        callbacks.send(&Arc::default(), &bot, request, rows, ttl, "Too late.").await.unwrap();
+
+        let keyboard = calls.lock().unwrap()[0].1["reply_markup"]["inline_keyboard"][0].clone();
+        let short = keyboard[0]["callback_data"].as_str().unwrap();
+        let stored = keyboard[1]["callback_data"].as_str().unwrap();
+        for data in [short, stored] {
+            assert!(data.starts_with("pick#") && data.len() <= MAX_DATA_LEN);
+        }
+
+        let short = press(short);
+        let short = callbacks.press(&short).unwrap();
+        assert_eq!(short.prefix(), "pick");
+        assert_eq!(short.data::<(u32, String)>(), Some((1, "a".to_string())));
+        assert_eq!(callbacks.press(&press(stored)).unwrap().data::<String>(), Some(long));
+
+        // Data the client made up doesn't reach a payload
+        assert!(callbacks.press(&press(r#"pick:[2,"b"]"#)).is_none());
+        assert!(callbacks.press(&press("pick#0199f4e2d6c07c3a8b1e5f2a9d4c6b10")).is_none());
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(callbacks.press(&press(stored)).is_none());
+        assert!(callbacks.stored.lock().unwrap().is_empty());
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[1].0, "EditMessageText");
+        assert_eq!(calls[1].1["text"], "pick one\n\nToo late.");
+    }
+}
@@ -0,0 +1,674 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use serde::Serialize;
+
+use crate::{
+    db::{self, Admin, DbError},
+    export::ConfigDocument,
+    storage::{AdminStore, RequestStore, SnapshotStore, WhitelistStore},
+};
+
+pub const USAGE: &str = "Usage:
+  telos [--restore <snapshot>]       Run the bot, optionally restoring the database first
+  telos --check-config               Validate the config and print it without connecting
+  telos export-config [file]         Write the config document to a file or stdout
+  telos import-config <file> [--yes] Show the changes in a config document and apply them
+  telos admin list
+  telos admin add <user_id> (--by <admin_id> | --superadmin) [--name <name>]
+  telos admin remove <user_id> [--by <admin_id>] [--yes]
+  telos admin promote <user_id> [--yes]
+  telos whitelist list
+  telos whitelist add <group_id> [thread_id] --by <admin_id> [--name <name>]
+  telos whitelist remove <group_id> [thread_id] [--yes]
+  telos requests list
+  telos requests approve <request_id> --by <admin_id>
+  telos requests reject <request_id>
+  telos migrate                      Apply the pending database migrations
+  telos replay <file>                Handle a recording again and show the calls that changed
+  telos simulate                     Chat with the bot in the terminal, against a scratch database
+  telos --help                       Show this message
+
+Lists are printed as a table, or as JSON with --json. Removing an admin without
+--by acts as a superadmin.";
+
+/// Flags that take a value, the others are switches
+const VALUE_FLAGS: &[&str] = &["--by", "--name"];
+const SWITCHES: &[&str] = &["--json", "--yes", "--superadmin"];
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+/// An offline subcommand, checked before the database is opened
+pub enum Subcommand {
+    Help,
+    Migrate,
+    ExportConfig(Option<String>),
+    ImportConfig(Vec<String>),
+    Manage(Vec<String>),
+}
+
+impl Subcommand {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            return Ok(Self::Help);
+        }
+
+        match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["help"] => Ok(Self::Help),
+            ["migrate"] => Ok(Self::Migrate),
+            ["export-config"] => Ok(Self::ExportConfig(None)),
+            ["export-config", path] => Ok(Self::ExportConfig(Some(path.to_string()))),
+            ["import-config", ..] => {
+                let rest = &args[1..];
+                match rest.iter().filter(|arg| *arg != "--yes").count() {
+                    1 => Ok(Self::ImportConfig(rest.to_vec())),
+                    _ => Err(USAGE.into()),
+                }
+            }
+            ["admin" | "whitelist" | "requests", ..] => {
+                Args::parse(args)?.check()?;
+                Ok(Self::Manage(args.to_vec()))
+            }
+            _ => Err(USAGE.into()),
+        }
+    }
+}
+
+/// Runs an offline subcommand against the database instead of starting the bot
+pub async fn run(db: &db::DB, command: &Subcommand) -> CliResult {
+    if let Subcommand::Migrate = command {
+        return migrate(db).await;
+    }
+
+    db.migrate().await?;
+
+    match command {
+        Subcommand::ExportConfig(path) => export_config(db, path.as_ref()).await,
+        Subcommand::ImportConfig(args) => import_config(db, args).await,
+        Subcommand::Manage(args) => manage(db, &Args::parse(args)?, &mut io::stdout()).await,
+        Subcommand::Help | Subcommand::Migrate => Ok(()),
+    }
+}
+
+/// Positional arguments and flags of a management subcommand
+struct Args<'a> {
+    positional: Vec<&'a str>,
+    values: HashMap<&'a str, &'a str>,
+    switches: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> Result<Self, String> {
+        let mut parsed = Self { positional: Vec::new(), values: HashMap::new(), switches: Vec::new() };
+
+        let mut args = args.iter().map(String::as_str);
+        while let Some(arg) = args.next() {
+            if VALUE_FLAGS.contains(&arg) {
+                let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+                parsed.values.insert(arg, value);
+            } else if SWITCHES.contains(&arg) {
+                parsed.switches.push(arg);
+            } else if arg.starts_with("--") {
+                return Err(format!("Unknown flag {arg}\n\n{USAGE}"));
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn has(&self, switch: &str) -> bool {
+        self.switches.contains(&switch)
+    }
+
+    fn arg(&self, index: usize) -> Result<&'a str, String> {
+        self.positional.get(index).copied().ok_or_else(|| USAGE.to_string())
+    }
+
+    /// The admin the change is made on behalf of
+    fn by(&self) -> Result<Option<i64>, String> {
+        self.values.get("--by").map(|id| user_id(id)).transpose()
+    }
+
+    /// Checks what can be told without the database: the action, how many
+    /// arguments it takes and their types
+    fn check(&self) -> Result<(), String> {
+        let max = match (self.arg(0)?, self.arg(1)?) {
+            ("admin" | "whitelist" | "requests", "list") => 2,
+            ("admin", "add") => {
+                user_id(self.arg(2)?)?;
+                if self.by()?.is_some() == self.has("--superadmin") {
+                    return Err("Use either --by <admin_id> or --superadmin".into());
+                }
+                3
+            }
+            ("admin", "remove") => {
+                user_id(self.arg(2)?)?;
+                self.by()?;
+                3
+            }
+            ("admin", "promote") => user_id(self.arg(2)?).map(|_| 3)?,
+            ("whitelist", action @ ("add" | "remove")) => {
+                group_id(self.arg(2)?)?;
+                if let Some(thread) = self.positional.get(3) {
+                    thread_id(thread)?;
+                }
+                if action == "add" {
+                    self.by()?.ok_or("--by <admin_id> is needed to whitelist")?;
+                }
+                4
+            }
+            ("requests", "approve") => {
+                self.arg(2)?;
+                self.by()?.ok_or("--by <admin_id> is needed to approve a request")?;
+                3
+            }
+            ("requests", "reject") => self.arg(2).map(|_| 3)?,
+            _ => return Err(USAGE.into()),
+        };
+
+        match self.positional.len() <= max {
+            true => Ok(()),
+            false => Err(USAGE.into()),
+        }
+    }
+}
+
+fn user_id(text: &str) -> Result<i64, String> {
+    text.parse::<u64>()
+        .ok()
+        .and_then(|id| i64::try_from(id).ok())
+        .ok_or_else(|| format!("Invalid user id {text:?}"))
+}
+
+fn group_id(text: &str) -> Result<i64, String> {
+    text.parse().map_err(|_| format!("Invalid group id {text:?}"))
+}
+
+fn thread_id(text: &str) -> Result<i32, String> {
+    text.parse().map_err(|_| format!("Invalid thread id {text:?}"))
+}
+
+async fn manage(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    match (args.arg(0)?, args.arg(1)?) {
+        ("admin", "list") => list_admins(db, args, out).await,
+        ("admin", "add") => add_admin(db, args, out).await,
+        ("admin", "remove") => remove_admin(db, args, out).await,
+        ("admin", "promote") => promote_admin(db, args, out).await,
+        ("whitelist", "list") => list_whitelist(db, args, out).await,
+        ("whitelist", "add") => whitelist(db, args, out).await,
+        ("whitelist", "remove") => unwhitelist(db, args, out).await,
+        ("requests", "list") => list_requests(db, args, out).await,
+        ("requests", "approve") => approve_request(db, args, out).await,
+        ("requests", "reject") => reject_request(db, args, out).await,
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// Fails unless `admin_id` is an admin, returning it
+async fn existing_admin(db: &db::DB, admin_id: i64) -> Result<Admin, Box<dyn std::error::Error>> {
+    db.get_admin(admin_id)
+        .await?
+        .ok_or_else(|| format!("Admin {admin_id} does not exist").into())
+}
+
+async fn list_admins(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let admins = db.get_admins().await?;
+    if args.has("--json") {
+        return print_json(out, &admins);
+    }
+
+    let rows = admins
+        .iter()
+        .map(|admin| {
+            vec![
+                admin.user_id.to_string(),
+                text(&admin.name),
+                admin.added_by.map_or("superadmin".to_string(), |id| id.to_string()),
+                time(&admin.added_at),
+            ]
+        })
+        .collect();
+    print_table(out, &["USER ID", "NAME", "ADDED BY", "ADDED AT"], rows)
+}
+
+async fn add_admin(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let user_id = user_id(args.arg(2)?)?;
+    let added_by = match (args.by()?, args.has("--superadmin")) {
+        (Some(admin_id), false) => Some(existing_admin(db, admin_id).await?.user_id),
+        (None, true) => None,
+        _ => return Err("Use either --by <admin_id> or --superadmin".into()),
+    };
+
+    match db.add_admin(user_id, added_by, args.values.get("--name").copied()).await {
+        Ok(()) => writeln!(out, "Admin added!")?,
+        Err(DbError::AlreadyExists) => return Err(format!("User {user_id} is already an admin").into()),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+async fn remove_admin(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let user_id = user_id(args.arg(2)?)?;
+    let remover = match args.by()? {
+        Some(admin_id) => Some(existing_admin(db, admin_id).await?),
+        None => None,
+    };
+
+    let target = db.get_admin(user_id).await?.ok_or("User is not an admin")?;
+    let subtree = db.count_admin_subtree(user_id).await?;
+
+    let summary = format!(
+        "Remove admin {} ({})? This also removes {} admin(s) they added. Groups they whitelisted stay whitelisted.",
+        target.name.as_deref().unwrap_or("<unnamed>"),
+        user_id,
+        subtree,
+    );
+    if !confirm(out, &summary, args.has("--yes"))? {
+        return Ok(());
+    }
+
+    let result = match remover {
+        Some(remover) if !remover.is_superadmin() => db.remove_admin_with_traversal(user_id, remover.user_id).await,
+        _ => db.remove_admin(user_id).await,
+    };
+
+    match result {
+        Ok(()) => writeln!(out, "Admin removed!")?,
+        Err(DbError::PermissionDenied) => {
+            return Err("Admins can only remove admins they added, directly or through others".into());
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+async fn promote_admin(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let user_id = user_id(args.arg(2)?)?;
+
+    let target = db.get_admin(user_id).await?.ok_or("User is not an admin")?;
+    if target.is_superadmin() {
+        return Err("User is already a superadmin".into());
+    }
+
+    let summary = format!(
+        "Promote {} ({}) to superadmin? They will be able to remove any admin.",
+        target.name.as_deref().unwrap_or("<unnamed>"),
+        user_id,
+    );
+    if !confirm(out, &summary, args.has("--yes"))? {
+        return Ok(());
+    }
+
+    db.make_superadmin(user_id).await?;
+    writeln!(out, "Superadmin made!")?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Whitelist {
+    groups: Vec<db::WhitelistedGroup>,
+    threads: Vec<db::WhitelistedThread>,
+}
+
+async fn list_whitelist(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let whitelist = Whitelist {
+        groups: db.get_whitelisted_groups().await?,
+        threads: db.get_all_whitelisted_threads().await?,
+    };
+    if args.has("--json") {
+        return print_json(out, &whitelist);
+    }
+
+    let rows = whitelist
+        .groups
+        .iter()
+        .map(|group| {
+            vec![
+                group.group_id.to_string(),
+                "-".to_string(),
+                text(&group.group_name),
+                added_by(group.added_by),
+                time(&group.created_at),
+            ]
+        })
+        .chain(whitelist.threads.iter().map(|thread| {
+            vec![
+                thread.group_id.to_string(),
+                thread.thread_id.to_string(),
+                text(&thread.thread_name),
+                added_by(thread.added_by),
+                time(&thread.created_at),
+            ]
+        }))
+        .collect();
+    print_table(out, &["GROUP ID", "THREAD ID", "NAME", "ADDED BY", "ADDED AT"], rows)
+}
+
+async fn whitelist(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let group_id = group_id(args.arg(2)?)?;
+    let admin_id = args.by()?.ok_or("--by <admin_id> is needed to whitelist")?;
+    existing_admin(db, admin_id).await?;
+    let name = args.values.get("--name").copied();
+
+    match args.positional.get(3) {
+        None => match db.add_whitelisted_group(group_id, admin_id, name).await {
+            Ok(()) => writeln!(out, "Group whitelisted!")?,
+            Err(DbError::AlreadyExists) => return Err("This group is already whitelisted".into()),
+            Err(e) => return Err(e.into()),
+        },
+        Some(thread) => {
+            let thread_id = thread_id(thread)?;
+            match db.add_whitelisted_thread(thread_id, group_id, admin_id, None, name).await {
+                Ok(()) => writeln!(out, "Thread whitelisted!")?,
+                Err(DbError::AlreadyExists) => return Err("This thread is already whitelisted".into()),
+                Err(DbError::NotFound) => return Err(format!("Whitelist group {group_id} first").into()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn unwhitelist(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let group_id = group_id(args.arg(2)?)?;
+
+    if let Some(thread) = args.positional.get(3) {
+        return match db.remove_whitelisted_thread(thread_id(thread)?, group_id).await {
+            Ok(()) => Ok(writeln!(out, "Thread unwhitelisted!")?),
+            Err(DbError::NotFound) => Err("Thread is not whitelisted".into()),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    let group = db.get_whitelisted_group(group_id).await?.ok_or("Group is not whitelisted")?;
+    let threads = db.get_whitelisted_threads(group_id).await?.len();
+
+    let summary = format!(
+        "Unwhitelist group {} ({})? This also removes {} whitelisted thread(s).",
+        group.group_name.as_deref().unwrap_or("<unnamed>"),
+        group_id,
+        threads,
+    );
+    if !confirm(out, &summary, args.has("--yes"))? {
+        return Ok(());
+    }
+
+    db.remove_whitelisted_group(group_id).await?;
+    writeln!(out, "Group unwhitelisted!")?;
+
+    Ok(())
+}
+
+async fn list_requests(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let requests = db.get_become_admin_requests().await?;
+    if args.has("--json") {
+        return print_json(out, &requests);
+    }
+
+    let rows = requests
+        .iter()
+        .map(|request| {
+            let status = match (request.pending, request.accepted) {
+                (Some(true), _) => "pending",
+                (_, Some(true)) => "approved",
+                _ => "rejected",
+            };
+            vec![
+                request.request_id.clone(),
+                request.user_id.to_string(),
+                text(&request.user_name),
+                status.to_string(),
+                time(&request.created_at),
+            ]
+        })
+        .collect();
+    print_table(out, &["REQUEST ID", "USER ID", "NAME", "STATUS", "CREATED AT"], rows)
+}
+
+async fn approve_request(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let request_id = args.arg(2)?;
+    let admin_id = args.by()?.ok_or("--by <admin_id> is needed to approve a request")?;
+    existing_admin(db, admin_id).await?;
+
+    match db.approve_become_admin_request(request_id, admin_id).await {
+        Ok(()) => writeln!(out, "Admin approved!")?,
+        Err(e) => return Err(request_error(request_id, e)),
+    }
+
+    Ok(())
+}
+
+async fn reject_request(db: &db::DB, args: &Args<'_>, out: &mut dyn Write) -> CliResult {
+    let request_id = args.arg(2)?;
+
+    match db.reject_become_admin_request(request_id).await {
+        Ok(()) => writeln!(out, "Admin rejected!")?,
+        Err(e) => return Err(request_error(request_id, e)),
+    }
+
+    Ok(())
+}
+
+fn request_error(request_id: &str, error: DbError) -> Box<dyn std::error::Error> {
+    match error {
+        DbError::NotFound => format!("No request with id {request_id}").into(),
+        DbError::NotPending => "This request was already answered".into(),
+        DbError::AlreadyExists => "This user is already an admin".into(),
+        e => e.into(),
+    }
+}
+
+async fn migrate(db: &db::DB) -> CliResult {
+    let pending = db.pending_migrations().await?;
+    if pending.is_empty() {
+        println!("Database is up to date");
+        return Ok(());
+    }
+
+    db.migrate().await?;
+    println!("Applied {} migration(s):", pending.len());
+    for migration in pending {
+        println!("  {migration}");
+    }
+
+    Ok(())
+}
+
+/// Asks before a destructive change, unless `--yes` was given
+fn confirm(out: &mut dyn Write, question: &str, yes: bool) -> io::Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+
+    write!(out, "{question} [y/N] ")?;
+    out.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        writeln!(out, "Aborted, nothing was changed")?;
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+fn print_json(out: &mut dyn Write, value: &impl Serialize) -> CliResult {
+    serde_json::to_writer_pretty(&mut *out, value)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn print_table(out: &mut dyn Write, columns: &[&str], rows: Vec<Vec<String>>) -> CliResult {
+    let mut widths: Vec<usize> = columns.iter().map(|column| column.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let header = columns.iter().map(|column| column.to_string()).collect();
+    for row in std::iter::once(header).chain(rows) {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{cell:width$}")).collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+
+    Ok(())
+}
+
+fn text(value: &Option<String>) -> String {
+    value.clone().unwrap_or_else(|| "-".to_string())
+}
+
+fn time(value: &Option<chrono::DateTime<chrono::Utc>>) -> String {
+    value.map_or("-".to_string(), |time| time.format("%Y-%m-%d %H:%M").to_string())
+}
+
+fn added_by(value: Option<i64>) -> String {
+    value.map_or("-".to_string(), |id| id.to_string())
+}
+
+async fn export_config(db: &db::DB, path: Option<&String>) -> CliResult {
+    let doc = ConfigDocument::from_db(db).await?;
+
+    match path {
+        Some(path) => {
+            std::fs::write(path, doc.to_json())?;
+            eprintln!("Config exported to {path}");
+        }
+        None => {
+            io::stdout().write_all(&doc.to_json())?;
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+async fn import_config(db: &db::DB, args: &[String]) -> CliResult {
+    let yes = args.iter().any(|arg| arg == "--yes");
+    let path = args.iter().find(|arg| *arg != "--yes").ok_or(USAGE)?;
+
+    let doc = ConfigDocument::from_json(&std::fs::read(path)?)?;
+    let current = ConfigDocument::from_db(db).await?;
+
+    let diff = current.diff(&doc);
+    if diff.is_empty() {
+        println!("Config is already up to date, nothing to import");
+        return Ok(());
+    }
+
+    println!("{} change(s):", diff.len());
+    for line in &diff {
+        println!("{line}");
+    }
+
+    if !confirm(&mut io::stdout(), "Apply?", yes)? {
+        return Ok(());
+    }
+
+    db.replace_config(&doc).await?;
+    println!("Config imported");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, Subcommand, manage};
+    use crate::{db, storage::{AdminStore, RequestStore}, testing::test_db};
+
+    const SUPERADMIN: i64 = 640129894;
+
+    async fn run(db: &db::DB, line: &str) -> Result<String, String> {
+        let args: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        let args = Args::parse(&args)?;
+        args.check()?;
+        let mut out = Vec::new();
+        manage(db, &args, &mut out).await.map_err(|e| e.to_string())?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[tokio::test]
+    async fn admins_can_be_managed_offline() {
+        let db = test_db().await;
+
+        run(&db, &format!("admin add 2 --by {SUPERADMIN} --name two")).await.unwrap();
+        run(&db, "admin add 3 --by 2").await.unwrap();
+        assert_eq!(run(&db, "admin add 3 --by 2").await.unwrap_err(), "User 3 is already an admin");
+        assert_eq!(run(&db, "admin add 4 --by 5").await.unwrap_err(), "Admin 5 does not exist");
+        assert_eq!(run(&db, "admin add 4").await.unwrap_err(), "Use either --by <admin_id> or --superadmin");
+
+        let table = run(&db, "admin list").await.unwrap();
+        assert!(table.starts_with("USER ID    NAME       ADDED BY    ADDED AT\n"));
+        assert!(table.contains(&format!("\n2          two        {SUPERADMIN}")));
+
+        let json: serde_json::Value = serde_json::from_str(&run(&db, "admin list --json").await.unwrap()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 3);
+
+        assert_eq!(
+            run(&db, "admin remove 2 --by 3 --yes").await.unwrap_err(),
+            "Admins can only remove admins they added, directly or through others"
+        );
+        run(&db, "admin promote 3 --yes").await.unwrap();
+        assert_eq!(run(&db, "admin promote 3 --yes").await.unwrap_err(), "User is already a superadmin");
+        run(&db, "admin remove 2 --by 3 --yes").await.unwrap();
+        assert!(db.get_admin(2).await.unwrap().is_none());
+
+        // A lost superadmin can be replaced from the command line
+        run(&db, &format!("admin remove {SUPERADMIN} --yes")).await.unwrap();
+        run(&db, "admin remove 3 --yes").await.unwrap();
+        run(&db, "admin add 7 --superadmin").await.unwrap();
+        assert!(db.get_admin(7).await.unwrap().unwrap().is_superadmin());
+    }
+
+    #[tokio::test]
+    async fn whitelist_and_requests_are_validated() {
+        let db = test_db().await;
+
+        assert_eq!(run(&db, "whitelist add -100 5 --by 640129894").await.unwrap_err(), "Whitelist group -100 first");
+        run(&db, "whitelist add -100 --by 640129894 --name gelos").await.unwrap();
+        run(&db, "whitelist add -100 5 --by 640129894").await.unwrap();
+        assert_eq!(run(&db, "whitelist add -100 --by 640129894").await.unwrap_err(), "This group is already whitelisted");
+        assert_eq!(run(&db, "whitelist list").await.unwrap().lines().count(), 3);
+        run(&db, "whitelist remove -100 --yes").await.unwrap();
+        assert_eq!(run(&db, "whitelist list").await.unwrap().lines().count(), 1);
+
+        let request_id = db.create_become_admin_request(9, Some("nine")).await.unwrap();
+        assert!(run(&db, "requests list").await.unwrap().contains("pending"));
+        assert_eq!(
+            run(&db, &format!("requests approve {request_id}")).await.unwrap_err(),
+            "--by <admin_id> is needed to approve a request"
+        );
+        run(&db, &format!("requests approve {request_id} --by 640129894")).await.unwrap();
+        assert_eq!(
+            run(&db, &format!("requests reject {request_id}")).await.unwrap_err(),
+            "This request was already answered"
+        );
+        assert!(db.get_admin(9).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn subcommands_are_checked_without_a_database() {
+        let parse = |line: &str| Subcommand::parse(&line.split_whitespace().map(str::to_string).collect::<Vec<_>>());
+
+        assert!(matches!(parse("--help"), Ok(Subcommand::Help)));
+        assert!(matches!(parse("admin add 2 --help"), Ok(Subcommand::Help)));
+        assert!(matches!(parse("whitelist add -100 5 --by 1"), Ok(Subcommand::Manage(_))));
+        assert!(matches!(parse("import-config doc.json --yes"), Ok(Subcommand::ImportConfig(_))));
+
+        for line in ["", "frobnicate", "migrate now", "admin", "admin add", "admin add two --superadmin", "admin list extra", "requests reject"] {
+            assert!(parse(line).is_err(), "{line:?} was accepted");
+        }
+        assert_eq!(parse("admin add 2").err().unwrap(), "Use either --by <admin_id> or --superadmin");
+        assert_eq!(parse("admin add 2 --nmae x").err().unwrap().lines().next(), Some("Unknown flag --nmae"));
+    }
+}
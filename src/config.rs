@@ -0,0 +1,550 @@
+use std::{env, fmt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::{backup::BackupSchedule, db, logging::LogFormat, metrics::MetricsConfig, retention::RetentionPolicy, webhook::WebhookConfig};
+
+const DEFAULT_CONFIG_FILE: &str = "telos.toml";
+
+const MIGUEL: &str = "
+PAROU, PAROU A DISCUSSÃO
+
+miguwu ><
+";
+
+/// Everything the bot can be tuned with. Values are layered: defaults, then
+/// the TOML file, then environment variables, then command-line flags.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Bot API token, only needed to run the bot
+    pub token: Option<String>,
+    /// Bot API server, for a local one or tests
+    pub api_url: Option<String>,
+    pub database: DatabaseSettings,
+    pub miguel: MiguelSettings,
+    pub admin: AdminSettings,
+    pub webhook: WebhookSettings,
+    pub metrics: MetricsSettings,
+    pub backup: BackupSettings,
+    pub retention: RetentionSettings,
+    pub shutdown: ShutdownSettings,
+    pub logging: LoggingSettings,
+    pub report: ReportSettings,
+    pub queue: QueueSettings,
+    pub record: RecordSettings,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    /// SQLite file, or a PostgreSQL URL with the postgres feature
+    pub path: String,
+    pub cache_refresh_secs: u64,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        Self { path: db::DEFAULT_DATABASE_PATH.to_string(), cache_refresh_secs: 60 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiguelSettings {
+    /// Sent in reply to commands matching `pattern`
+    pub text: String,
+    /// Username whose miguel messages get a reaction
+    pub user: String,
+    pub pattern: String,
+    pub reaction: String,
+}
+
+impl Default for MiguelSettings {
+    fn default() -> Self {
+        Self {
+            text: MIGUEL.to_string(),
+            user: "migeyel".to_string(),
+            pattern: r"/[A-z0-9À-ÿ]*?miguel[A-z0-9À-ÿ]*".to_string(),
+            reaction: "🗿".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings {
+    /// How long Confirm buttons of destructive commands stay valid
+    pub confirmation_ttl_secs: u64,
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        Self { confirmation_ttl_secs: 60 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    /// Enables webhook mode instead of long polling
+    pub url: Option<String>,
+    pub address: String,
+    /// Defaults to the path of `url`
+    pub path: Option<String>,
+    pub secret: Option<String>,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self { url: None, address: "127.0.0.1:8443".to_string(), path: None, secret: None }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSettings {
+    /// Enables the `/metrics` and `/healthz` server
+    pub address: Option<String>,
+    pub max_poll_age_secs: u64,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self { address: None, max_poll_age_secs: 60 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupSettings {
+    /// Enables scheduled backups
+    pub dir: Option<PathBuf>,
+    pub interval_hours: u64,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self { dir: None, interval_hours: 24, keep_daily: 7, keep_weekly: 4 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSettings {
+    pub interval_hours: u64,
+    /// 0 keeps admin requests forever
+    pub request_days: u64,
+    /// 0 keeps expired ignore entries forever
+    pub ignore_days: u64,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        Self { interval_hours: 24, request_days: 90, ignore_days: 30 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSettings {
+    /// How long updates being handled and background tasks get to finish
+    pub deadline_secs: u64,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self { deadline_secs: 10 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    /// `tracing` filter directives, like `info` or `telos=debug,sqlx=warn`
+    pub filter: String,
+    pub format: LogFormat,
+    /// Log message text, which is redacted otherwise
+    pub content: bool,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self { filter: "info".to_string(), format: LogFormat::Human, content: false }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportSettings {
+    /// Enables posting errors and lifecycle events to this chat
+    pub chat_id: Option<i64>,
+    /// Forum topic of `chat_id` to post in
+    pub thread_id: Option<i32>,
+    /// How long the same error is folded into one report
+    pub dedup_secs: u64,
+    pub max_per_minute: usize,
+}
+
+impl Default for ReportSettings {
+    fn default() -> Self {
+        Self { chat_id: None, thread_id: None, dedup_secs: 600, max_per_minute: 10 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSettings {
+    /// Bot API calls sent per second, over all chats
+    pub global_per_sec: usize,
+    pub chat_per_sec: usize,
+    /// Messages sent per minute to one group or channel
+    pub group_per_min: usize,
+    /// Retries after `RetryAfter` or a network error
+    pub max_retries: u32,
+    /// How long a reaction may wait for its turn before it is dropped
+    pub reaction_wait_secs: u64,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self { global_per_sec: 30, chat_per_sec: 1, group_per_min: 20, max_retries: 3, reaction_wait_secs: 2 }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordSettings {
+    /// Enables recording updates and calls to this JSONL file
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Number,
+    /// A number that may be negative, like a chat id
+    Integer,
+    Bool,
+}
+
+/// A setting that can be overridden from the environment and the command
+/// line. The flag is the key with dots and underscores turned into dashes.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    kind: Kind,
+}
+
+const fn setting(key: &'static str, env: &'static str, kind: Kind) -> Setting {
+    Setting { key, env, kind }
+}
+
+const SETTINGS: &[Setting] = &[
+    setting("token", "TELOXIDE_TOKEN", Kind::Text),
+    setting("api_url", "TELOXIDE_API_URL", Kind::Text),
+    setting("database.path", "DATABASE_PATH", Kind::Text),
+    setting("database.cache_refresh_secs", "CACHE_REFRESH_SECS", Kind::Number),
+    setting("miguel.text", "MIGUEL_TEXT", Kind::Text),
+    setting("miguel.user", "MIGUEL_USER", Kind::Text),
+    setting("miguel.pattern", "MIGUEL_PATTERN", Kind::Text),
+    setting("miguel.reaction", "MIGUEL_REACTION", Kind::Text),
+    setting("admin.confirmation_ttl_secs", "CONFIRMATION_TTL_SECS", Kind::Number),
+    setting("webhook.url", "WEBHOOK_URL", Kind::Text),
+    setting("webhook.address", "WEBHOOK_ADDRESS", Kind::Text),
+    setting("webhook.path", "WEBHOOK_PATH", Kind::Text),
+    setting("webhook.secret", "WEBHOOK_SECRET", Kind::Text),
+    setting("metrics.address", "METRICS_ADDRESS", Kind::Text),
+    setting("metrics.max_poll_age_secs", "HEALTH_MAX_POLL_AGE_SECS", Kind::Number),
+    setting("backup.dir", "BACKUP_DIR", Kind::Text),
+    setting("backup.interval_hours", "BACKUP_INTERVAL_HOURS", Kind::Number),
+    setting("backup.keep_daily", "BACKUP_KEEP_DAILY", Kind::Number),
+    setting("backup.keep_weekly", "BACKUP_KEEP_WEEKLY", Kind::Number),
+    setting("retention.interval_hours", "RETENTION_INTERVAL_HOURS", Kind::Number),
+    setting("retention.request_days", "REQUEST_RETENTION_DAYS", Kind::Number),
+    setting("retention.ignore_days", "IGNORE_RETENTION_DAYS", Kind::Number),
+    setting("shutdown.deadline_secs", "SHUTDOWN_DEADLINE_SECS", Kind::Number),
+    setting("logging.filter", "RUST_LOG", Kind::Text),
+    setting("logging.format", "LOG_FORMAT", Kind::Text),
+    setting("logging.content", "LOG_MESSAGE_CONTENT", Kind::Bool),
+    setting("report.chat_id", "REPORT_CHAT_ID", Kind::Integer),
+    setting("report.thread_id", "REPORT_THREAD_ID", Kind::Integer),
+    setting("report.dedup_secs", "REPORT_DEDUP_SECS", Kind::Number),
+    setting("report.max_per_minute", "REPORT_MAX_PER_MINUTE", Kind::Number),
+    setting("queue.global_per_sec", "QUEUE_GLOBAL_PER_SEC", Kind::Number),
+    setting("queue.chat_per_sec", "QUEUE_CHAT_PER_SEC", Kind::Number),
+    setting("queue.group_per_min", "QUEUE_GROUP_PER_MIN", Kind::Number),
+    setting("queue.max_retries", "QUEUE_MAX_RETRIES", Kind::Number),
+    setting("queue.reaction_wait_secs", "QUEUE_REACTION_WAIT_SECS", Kind::Number),
+    setting("record.path", "RECORD_PATH", Kind::Text),
+];
+
+impl Setting {
+    fn flag(&self) -> String {
+        format!("--{}", self.key.replace(['.', '_'], "-"))
+    }
+
+    fn parse(&self, source: &str, value: &str) -> Result<Value, String> {
+        match self.kind {
+            Kind::Text => Ok(Value::String(value.to_string())),
+            Kind::Number => value
+                .parse::<u64>()
+                .ok()
+                .and_then(|number| i64::try_from(number).ok())
+                .map(Value::Integer)
+                .ok_or_else(|| format!("{source} must be a positive number, got {value:?}")),
+            Kind::Integer => value
+                .parse::<i64>()
+                .map(Value::Integer)
+                .map_err(|_| format!("{source} must be a number, got {value:?}")),
+            Kind::Bool => match value {
+                "true" | "1" => Ok(Value::Boolean(true)),
+                "false" | "0" => Ok(Value::Boolean(false)),
+                _ => Err(format!("{source} must be true or false, got {value:?}")),
+            },
+        }
+    }
+}
+
+/// Every problem found while loading or validating the config
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid config:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the layered config, taking `--config <file>` and the setting
+    /// flags out of `args`. Without `--config`, the file is read from
+    /// `TELOS_CONFIG` or `telos.toml` when it exists.
+    pub fn load(args: &mut Vec<String>) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        let mut table = Table::new();
+
+        let file = match take_flag(args, "--config") {
+            Ok(Some(file)) => Some(PathBuf::from(file)),
+            Ok(None) => env::var("TELOS_CONFIG")
+                .ok()
+                .map(PathBuf::from)
+                .or_else(|| std::path::Path::new(DEFAULT_CONFIG_FILE).exists().then(|| DEFAULT_CONFIG_FILE.into())),
+            Err(e) => {
+                problems.push(e);
+                None
+            }
+        };
+
+        if let Some(file) = file {
+            match std::fs::read_to_string(&file) {
+                Ok(text) => match Self::from_toml(&text) {
+                    Ok(file_table) => table = file_table,
+                    Err(e) => problems.push(format!("{}: {e}", file.display())),
+                },
+                Err(e) => problems.push(format!("Can't read {}: {e}", file.display())),
+            }
+        }
+
+        for setting in SETTINGS {
+            if let Ok(value) = env::var(setting.env) {
+                match setting.parse(setting.env, &value) {
+                    Ok(value) => insert(&mut table, setting.key, value),
+                    Err(e) => problems.push(e),
+                }
+            }
+        }
+
+        for setting in SETTINGS {
+            let flag = setting.flag();
+            match take_flag(args, &flag) {
+                Ok(Some(value)) => match setting.parse(&flag, &value) {
+                    Ok(value) => insert(&mut table, setting.key, value),
+                    Err(e) => problems.push(e),
+                },
+                Ok(None) => {}
+                Err(e) => problems.push(e),
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError(problems));
+        }
+
+        let config = Self::deserialize(Value::Table(table)).map_err(|e| ConfigError(vec![e.to_string()]))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Parses a config file, checking the types and names of its settings
+    fn from_toml(text: &str) -> Result<Table, toml::de::Error> {
+        toml::from_str::<Self>(text)?;
+        toml::from_str(text)
+    }
+
+    /// Checks every setting, without connecting to anything
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if let Some(url) = &self.api_url
+            && let Err(e) = url::Url::parse(url)
+        {
+            problems.push(format!("api_url is not a valid URL: {e}"));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.filter) {
+            problems.push(format!("logging.filter is invalid: {e}"));
+        }
+        if let Err(e) = regex::Regex::new(&self.miguel.pattern) {
+            problems.push(format!("miguel.pattern is not a valid regex: {e}"));
+        }
+        if self.database.cache_refresh_secs == 0 {
+            problems.push("database.cache_refresh_secs must be at least 1".to_string());
+        }
+        if self.admin.confirmation_ttl_secs == 0 {
+            problems.push("admin.confirmation_ttl_secs must be at least 1".to_string());
+        }
+        if self.report.thread_id.is_some() && self.report.chat_id.is_none() {
+            problems.push("report.thread_id needs report.chat_id".to_string());
+        }
+        if self.report.max_per_minute == 0 {
+            problems.push("report.max_per_minute must be at least 1".to_string());
+        }
+        for (key, limit) in [
+            ("queue.global_per_sec", self.queue.global_per_sec),
+            ("queue.chat_per_sec", self.queue.chat_per_sec),
+            ("queue.group_per_min", self.queue.group_per_min),
+        ] {
+            if limit == 0 {
+                problems.push(format!("{key} must be at least 1"));
+            }
+        }
+        if let Err(e) = WebhookConfig::from_config(&self.webhook) {
+            problems.push(e);
+        }
+        if let Err(e) = MetricsConfig::from_config(&self.metrics) {
+            problems.push(e);
+        }
+        if let Err(e) = BackupSchedule::from_config(&self.backup) {
+            problems.push(e);
+        }
+        if let Err(e) = RetentionPolicy::from_config(&self.retention) {
+            problems.push(e);
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError(problems)),
+        }
+    }
+
+    pub fn token(&self) -> Result<&str, ConfigError> {
+        match self.token.as_deref() {
+            Some(token) if !token.is_empty() => Ok(token),
+            _ => Err(ConfigError(vec![
+                "token is required to run the bot, set it in the config file, TELOXIDE_TOKEN or --token".to_string(),
+            ])),
+        }
+    }
+
+    /// The effective config as TOML, with the secrets hidden
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        for secret in [&mut config.token, &mut config.webhook.secret] {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+
+        toml::to_string_pretty(&config).unwrap_or_default()
+    }
+}
+
+/// Removes `--flag value` or `--flag=value` from `args`
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    let prefix = format!("{flag}=");
+
+    for i in 0..args.len() {
+        if let Some(value) = args[i].strip_prefix(&prefix) {
+            let value = value.to_string();
+            args.remove(i);
+            return Ok(Some(value));
+        }
+
+        if args[i] == flag {
+            if i + 1 >= args.len() {
+                return Err(format!("{flag} needs a value"));
+            }
+            let value = args.remove(i + 1);
+            args.remove(i);
+            return Ok(Some(value));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Sets a dotted `key` in `table`, creating the sections on the way
+fn insert(table: &mut Table, key: &str, value: Value) {
+    let mut table = table;
+    let mut parts = key.split('.').peekable();
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            table.insert(part.to_string(), value);
+            return;
+        }
+
+        let section = table.entry(part).or_insert_with(|| Value::Table(Table::new()));
+        if !section.is_table() {
+            *section = Value::Table(Table::new());
+        }
+        table = section.as_table_mut().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, insert};
+    use serde::Deserialize;
+
+    #[test]
+    fn flags_override_the_file() {
+        let mut table = Config::from_toml("[backup]\nkeep_daily = 3\nkeep_weekly = 2\n").unwrap();
+        insert(&mut table, "backup.keep_daily", toml::Value::Integer(5));
+
+        let config = Config::deserialize(toml::Value::Table(table)).unwrap();
+        assert_eq!(config.backup.keep_daily, 5);
+        assert_eq!(config.backup.keep_weekly, 2);
+        assert_eq!(config.backup.interval_hours, 24);
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        let e = Config::from_toml("[backup]\nkeep_dialy = 3\n").unwrap_err();
+        assert!(e.to_string().contains("keep_dialy"));
+
+        let mut args = vec!["--backup-keep-daily".to_string(), "soon".to_string(), "--check-config".to_string()];
+        let e = Config::load(&mut args).unwrap_err();
+        assert_eq!(e.0, ["--backup-keep-daily must be a positive number, got \"soon\""]);
+        assert_eq!(args, ["--check-config"]);
+
+        let mut config = Config::default();
+        config.miguel.pattern = "(".to_string();
+        config.webhook.url = Some("https://example.com/hook".to_string());
+        config.webhook.path = Some("hook".to_string());
+        let e = config.validate().unwrap_err();
+        assert_eq!(e.0.len(), 2);
+        assert!(e.0[0].starts_with("miguel.pattern"));
+        assert!(e.0[1].starts_with("webhook.path"));
+    }
+}
@@ -1,20 +1,43 @@
 use std::sync::Arc;
+#[cfg(not(feature = "postgres"))]
+use std::str::FromStr;
 
 use chrono::{DateTime, Utc};
-use sqlx::migrate::Migrator;
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::pool::PoolOptions;
+#[cfg(not(feature = "postgres"))]
+use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::Error;
+use sqlx::error::ErrorKind;
+use async_trait::async_trait;
 use uuid::Uuid;
 
+use crate::export::ConfigDocument;
+use crate::storage::{AdminStore, DialogStore, ForgetReport, IgnoreStore, ModuleStore, RequestStore, RetentionStore, SnapshotStore, WhitelistStore};
+
+#[cfg(not(feature = "postgres"))]
+type Database = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+type Database = sqlx::Postgres;
+
+#[cfg(not(feature = "postgres"))]
 static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+#[cfg(feature = "postgres")]
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations_postgres");
+
+#[cfg(not(feature = "postgres"))]
+pub const DEFAULT_DATABASE_PATH: &str = "sqlite://db.sqlite?mode=rwc";
+#[cfg(feature = "postgres")]
+pub const DEFAULT_DATABASE_PATH: &str = "postgres://localhost/telos";
 
 #[derive(Clone)]
 pub struct DB {
-    db: Arc<SqlitePool>,
+    db: Arc<sqlx::Pool<Database>>,
 }
 
 #[allow(unused)]
-#[derive(Clone, Debug, sqlx::FromRow)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct Admin {
     pub user_id: i64,
     pub name: Option<String>,
@@ -23,7 +46,7 @@ pub struct Admin {
 }
 
 #[allow(unused)]
-#[derive(Clone, Debug, sqlx::FromRow)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct WhitelistedGroup {
     pub group_id: i64,
     pub group_name: Option<String>,
@@ -32,7 +55,7 @@ pub struct WhitelistedGroup {
 }
 
 #[allow(unused)]
-#[derive(Clone, Debug, sqlx::FromRow)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct WhitelistedThread {
     pub thread_id: i32,
     pub group_id: i64,
@@ -43,7 +66,7 @@ pub struct WhitelistedThread {
 }
 
 #[allow(unused)]
-#[derive(Clone, Debug, sqlx::FromRow)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct BecomeAdminRequest {
     pub request_id: String,
     pub user_id: i64,
@@ -53,29 +76,229 @@ pub struct BecomeAdminRequest {
     pub accepted: Option<bool>,
 }
 
+#[allow(unused)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
+pub struct IgnoredUser {
+    pub user_id: i64,
+    pub chat_id: Option<i64>,
+    pub added_by: Option<i64>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[allow(unused)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
+pub struct ExemptedBot {
+    pub user_id: i64,
+    pub name: Option<String>,
+    pub added_by: Option<i64>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[allow(unused)]
+#[derive(Clone, Debug, sqlx::FromRow, Serialize, Deserialize)]
+pub struct DisabledModule {
+    pub chat_id: i64,
+    pub module: String,
+    pub added_by: Option<i64>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// A multi-step command a user is in the middle of, see `dialog`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenDialog {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub dialog: String,
+    /// The answers given so far, one per step
+    pub answers: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+type DialogRow = (i64, i64, String, String, DateTime<Utc>);
+
+impl OpenDialog {
+    fn from_row((chat_id, user_id, dialog, answers, expires_at): DialogRow) -> Result<Self, DbError> {
+        let answers = serde_json::from_str(&answers).map_err(|e| DbError::Storage(Error::Decode(Box::new(e))))?;
+        Ok(Self { chat_id, user_id, dialog, answers, expires_at })
+    }
+}
+
+/// What went wrong in a storage call, in terms the handlers can explain to
+/// users. Constraint violations reported by the database (SQLite's
+/// `SQLITE_CONSTRAINT_PRIMARYKEY`/`_UNIQUE` and `_FOREIGNKEY`, or their
+/// PostgreSQL counterparts) become `AlreadyExists` and `NotFound`.
+#[derive(Debug)]
+pub enum DbError {
+    /// The row doesn't exist, or a row it references doesn't
+    NotFound,
+    /// A row with the same key already exists
+    AlreadyExists,
+    /// The request was already approved or rejected
+    NotPending,
+    /// The actor isn't allowed to touch this row
+    PermissionDenied,
+    /// Anything else reported by the database driver
+    Storage(Error),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::NotFound => write!(f, "not found"),
+            DbError::AlreadyExists => write!(f, "already exists"),
+            DbError::NotPending => write!(f, "not pending"),
+            DbError::PermissionDenied => write!(f, "permission denied"),
+            DbError::Storage(e) => write!(f, "storage error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for DbError {
+    fn from(e: Error) -> Self {
+        match &e {
+            Error::RowNotFound => DbError::NotFound,
+            Error::Database(db_error) => match db_error.kind() {
+                ErrorKind::UniqueViolation => DbError::AlreadyExists,
+                ErrorKind::ForeignKeyViolation => DbError::NotFound,
+                _ => DbError::Storage(e),
+            },
+            _ => DbError::Storage(e),
+        }
+    }
+}
+
+/// Turns a write that matched no rows into `NotFound`
+fn affected(rows_affected: u64) -> Result<(), DbError> {
+    if rows_affected == 0 {
+        return Err(DbError::NotFound);
+    }
+    Ok(())
+}
+
 impl Admin {
     pub fn is_superadmin(&self) -> bool {
         self.added_by.is_none()
     }
+
+    /// A group acting for its anonymous admins, or a channel. Their ids are
+    /// negative, unlike user ids.
+    pub fn is_chat(&self) -> bool {
+        self.user_id < 0
+    }
 }
 
 impl DB {
+    #[cfg(not(feature = "postgres"))]
+    pub async fn new(path: &str) -> Result<Self, Error> {
+        // Foreign keys are a per-connection setting in SQLite, so set them
+        // explicitly for every connection the pool opens
+        let options = SqliteConnectOptions::from_str(path)?.foreign_keys(true);
+        let db = PoolOptions::<Database>::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    #[cfg(feature = "postgres")]
     pub async fn new(path: &str) -> Result<Self, Error> {
-        let db = SqlitePoolOptions::new()
+        let db = PoolOptions::<Database>::new()
             .max_connections(5)
             .connect(path)
             .await?;
         Ok(Self { db: Arc::new(db) })
     }
 
+    /// An empty, migrated database to try things against: in memory with
+    /// SQLite, a new schema of the database at `url` with PostgreSQL
+    #[cfg(not(feature = "postgres"))]
+    pub async fn scratch(_url: &str) -> Result<Self, Error> {
+        let db = Self::new("sqlite::memory:").await?;
+        db.migrate().await?;
+        Ok(db)
+    }
+
+    #[cfg(feature = "postgres")]
+    pub async fn scratch(url: &str) -> Result<Self, Error> {
+        let schema = format!("scratch_{}", Uuid::now_v7().simple());
+        let setup = sqlx::PgPool::connect(url).await?;
+        sqlx::query(&format!("CREATE SCHEMA {schema}")).execute(&setup).await?;
+        setup.close().await;
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let db = Self::new(&format!("{url}{separator}options=-c%20search_path%3D{schema}")).await?;
+        db.migrate().await?;
+        Ok(db)
+    }
+
+    /// Throws away a database made by `scratch`: nothing to do with SQLite,
+    /// its schema is dropped with PostgreSQL
+    #[cfg(not(feature = "postgres"))]
+    pub async fn discard(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    #[cfg(feature = "postgres")]
+    pub async fn discard(&self) -> Result<(), Error> {
+        let (schema,): (String,) = sqlx::query_as("SELECT current_schema()").fetch_one(&*self.db).await?;
+        // Never drop the schema of a real database by mistake
+        if schema.starts_with("scratch_") {
+            sqlx::query(&format!("DROP SCHEMA {schema} CASCADE")).execute(&*self.db).await?;
+        }
+        self.db.close().await;
+        Ok(())
+    }
+
+    /// Checks that the database answers
+    pub async fn ping(&self) -> Result<(), Error> {
+        sqlx::query("SELECT 1").execute(&*self.db).await?;
+        Ok(())
+    }
+
+    /// Lists the migrations that `migrate` would apply, as `version description`
+    pub async fn pending_migrations(&self) -> Result<Vec<String>, Error> {
+        let mut conn = self.db.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        let applied: Vec<i64> = conn
+            .list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect();
+
+        Ok(MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration() && !applied.contains(&migration.version))
+            .map(|migration| format!("{} {}", migration.version, migration.description))
+            .collect())
+    }
+
+    /// Waits for the connections in use to be returned and closes them all
+    pub async fn close(&self) {
+        self.db.close().await;
+    }
+
     pub async fn migrate(&self) -> Result<(), Error> {
         MIGRATOR.run(&*self.db).await?;
-        log::info!("Migrations applied successfully.");
+        tracing::info!("Migrations applied successfully.");
         Ok(())
     }
+}
 
-    pub async fn add_admin(&self, user_id: i64, added_by: i64, name: Option<&str>) -> Result<(), Error> {
-        sqlx::query("INSERT INTO admins (user_id, name, added_by) VALUES (?, ?, ?)")
+#[async_trait]
+impl AdminStore for DB {
+    async fn add_admin(&self, user_id: i64, added_by: Option<i64>, name: Option<&str>) -> Result<(), DbError> {
+        sqlx::query("INSERT INTO admins (user_id, name, added_by) VALUES ($1, $2, $3)")
             .bind(user_id)
             .bind(name)
             .bind(added_by)
@@ -84,8 +307,64 @@ impl DB {
         Ok(())
     }
 
-    pub async fn add_whitelisted_group(&self, group_id: i64, added_by: i64, group_name: Option<&str>) -> Result<(), Error> {
-        sqlx::query("INSERT INTO whitelisted_groups (group_id, group_name, added_by) VALUES (?, ?, ?)")
+    async fn remove_admin(&self, user_id: i64) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM admins WHERE user_id = $1")
+            .bind(user_id )
+            .execute(&*self.db)
+            .await?;
+        affected(result.rows_affected())
+    }
+
+    async fn make_superadmin(&self, user_id: i64) -> Result<(), DbError> {
+        let result = sqlx::query("UPDATE admins SET added_by = NULL WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&*self.db)
+            .await?;
+        affected(result.rows_affected())
+    }
+
+    async fn get_admin(&self, user_id: i64) -> Result<Option<Admin>, DbError> {
+        let admin = sqlx::query_as::<_, Admin>(
+            "SELECT user_id, name, added_by, added_at FROM admins WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&*self.db)
+        .await?;
+
+        Ok(admin)
+    }
+
+    async fn count_admin_subtree(&self, user_id: i64) -> Result<i64, DbError> {
+        let (count,): (i64,) = sqlx::query_as(
+            "WITH RECURSIVE subtree(user_id) AS (
+                SELECT user_id FROM admins WHERE added_by = $1
+                UNION
+                SELECT admins.user_id FROM admins JOIN subtree ON admins.added_by = subtree.user_id
+            )
+            SELECT COUNT(*) FROM subtree",
+        )
+        .bind(user_id)
+        .fetch_one(&*self.db)
+        .await?;
+
+        Ok(count)
+    }
+
+    async fn get_admins(&self) -> Result<Vec<Admin>, DbError> {
+        let admins = sqlx::query_as::<_, Admin>(
+            "SELECT user_id, name, added_by, added_at FROM admins",
+        )
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(admins)
+    }
+}
+
+#[async_trait]
+impl WhitelistStore for DB {
+    async fn add_whitelisted_group(&self, group_id: i64, added_by: i64, group_name: Option<&str>) -> Result<(), DbError> {
+        sqlx::query("INSERT INTO whitelisted_groups (group_id, group_name, added_by) VALUES ($1, $2, $3)")
             .bind(group_id)
             .bind(group_name)
             .bind(added_by)
@@ -94,15 +373,15 @@ impl DB {
         Ok(())
     }
 
-    pub async fn add_whitelisted_thread(
+    async fn add_whitelisted_thread(
         &self,
         thread_id: i32,
         group_id: i64,
         added_by: i64,
         group_name: Option<&str>,
         thread_name: Option<&str>,
-    ) -> Result<(), Error> {
-        sqlx::query("INSERT INTO whitelisted_threads (thread_id, group_id, added_by, group_name, thread_name) VALUES (?, ?, ?, ?, ?)")
+    ) -> Result<(), DbError> {
+        sqlx::query("INSERT INTO whitelisted_threads (thread_id, group_id, added_by, group_name, thread_name) VALUES ($1, $2, $3, $4, $5)")
             .bind(thread_id)
             .bind(group_id)
             .bind(added_by)
@@ -113,71 +392,45 @@ impl DB {
         Ok(())
     }
 
-    pub async fn remove_whitelisted_group(&self, group_id: i64) -> Result<(), Error> {
-        sqlx::query("DELETE FROM whitelisted_groups WHERE group_id = ?")
+    async fn remove_whitelisted_group(&self, group_id: i64) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM whitelisted_groups WHERE group_id = $1")
             .bind(group_id)
             .execute(&*self.db)
             .await?;
-        Ok(())
+        affected(result.rows_affected())
     }
 
-    pub async fn remove_whitelisted_thread(&self, thread_id: i32, group_id: i64) -> Result<(), Error> {
-        sqlx::query("DELETE FROM whitelisted_threads WHERE thread_id = ? AND group_id = ?")
+    async fn remove_whitelisted_thread(&self, thread_id: i32, group_id: i64) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM whitelisted_threads WHERE thread_id = $1 AND group_id = $2")
             .bind(thread_id)
             .bind(group_id)
             .execute(&*self.db)
             .await?;
-        Ok(())
+        affected(result.rows_affected())
     }
 
-    pub async fn remove_admin(&self, user_id: i64) -> Result<(), Error> {
-        sqlx::query("DELETE FROM admins WHERE user_id = ?")
-            .bind(user_id )
-            .execute(&*self.db)
-            .await?;
-        Ok(())
-    }
-
-    pub async fn remove_admin_with_traversal(&self, user_id: i64, remover_id: i64) -> Result<bool, Error> {
-        let mut admin = match self.get_admin(user_id).await? {
-            Some(a) => a,
-            None => return Ok(false),
-        };
-
-        while let Some(adder) = admin.added_by {
-            if adder == remover_id {
-                self.remove_admin(user_id).await?;
-                return Ok(true);
-            }
-            admin = match self.get_admin(adder).await? {
-                Some(a) => a,
-                None => return Ok(false),
-            };
-        }
-
-        Ok(false)
-    }
+    async fn get_whitelisted_threads(&self, group_id: i64) -> Result<Vec<WhitelistedThread>, DbError> {
+        let threads = sqlx::query_as::<_, WhitelistedThread>(
+            "SELECT thread_id, group_id, group_name, thread_name, added_by, created_at FROM whitelisted_threads WHERE group_id = $1",
+        )
+        .bind(group_id)
+        .fetch_all(&*self.db)
+        .await?;
 
-    pub async fn make_superadmin(&self, user_id: i64) -> Result<(), Error> {
-        sqlx::query("UPDATE admins SET added_by = NULL WHERE user_id = ?")
-            .bind(user_id)
-            .execute(&*self.db)
-            .await?;
-        Ok(())
+        Ok(threads)
     }
 
-    pub async fn get_whitelisted_threads(&self, group_id: i64) -> Result<Vec<WhitelistedThread>, Error> {
+    async fn get_all_whitelisted_threads(&self) -> Result<Vec<WhitelistedThread>, DbError> {
         let threads = sqlx::query_as::<_, WhitelistedThread>(
-            "SELECT thread_id, group_id, group_name, thread_name, added_by, created_at FROM whitelisted_threads WHERE group_id = ?",
+            "SELECT thread_id, group_id, group_name, thread_name, added_by, created_at FROM whitelisted_threads",
         )
-        .bind(group_id)
         .fetch_all(&*self.db)
         .await?;
 
         Ok(threads)
     }
 
-    pub async fn get_whitelisted_groups(&self) -> Result<Vec<WhitelistedGroup>, Error> {
+    async fn get_whitelisted_groups(&self) -> Result<Vec<WhitelistedGroup>, DbError> {
         let groups = sqlx::query_as::<_, WhitelistedGroup>(
             "SELECT group_id, group_name, added_by, created_at FROM whitelisted_groups",
         )
@@ -187,29 +440,19 @@ impl DB {
         Ok(groups)
     }
 
-    pub async fn get_admin(&self, user_id: i64) -> Result<Option<Admin>, Error> {
-        let admin = sqlx::query_as::<_, Admin>(
-            "SELECT user_id, name, added_by, added_at FROM admins WHERE user_id = ?",
+    async fn get_whitelisted_group(&self, group_id: i64) -> Result<Option<WhitelistedGroup>, DbError> {
+        let group = sqlx::query_as::<_, WhitelistedGroup>(
+            "SELECT group_id, group_name, added_by, created_at FROM whitelisted_groups WHERE group_id = $1",
         )
-        .bind(user_id)
+        .bind(group_id)
         .fetch_optional(&*self.db)
         .await?;
 
-        Ok(admin)
+        Ok(group)
     }
 
-    pub async fn get_admins(&self) -> Result<Vec<Admin>, Error> {
-        let admins = sqlx::query_as::<_, Admin>(
-            "SELECT user_id, name, added_by, added_at FROM admins",
-        )
-        .fetch_all(&*self.db)
-        .await?;
-
-        Ok(admins)
-    }
-
-    pub async fn is_group_whitelisted(&self, group_id: i64) -> Result<bool, Error> {
-        let exists: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM whitelisted_groups WHERE group_id = ?")
+    async fn is_group_whitelisted(&self, group_id: i64) -> Result<bool, DbError> {
+        let exists: Option<(i64,)> = sqlx::query_as("SELECT group_id FROM whitelisted_groups WHERE group_id = $1")
             .bind(group_id)
             .fetch_optional(&*self.db)
             .await?;
@@ -217,9 +460,9 @@ impl DB {
         Ok(exists.is_some())
     }
 
-    pub async fn is_thread_whitelisted(&self, thread_id: i32, group_id: i64) -> Result<bool, Error> {
+    async fn is_thread_whitelisted(&self, thread_id: i32, group_id: i64) -> Result<bool, DbError> {
         let exists: Option<(i64,)> = sqlx::query_as(
-            "SELECT 1 FROM whitelisted_threads WHERE thread_id = ? AND group_id = ?",
+            "SELECT group_id FROM whitelisted_threads WHERE thread_id = $1 AND group_id = $2",
         )
         .bind(thread_id)
         .bind(group_id)
@@ -228,33 +471,36 @@ impl DB {
 
         Ok(exists.is_some())
     }
+}
 
-    pub async fn create_become_admin_request(&self, user_id: i64, user_name: Option<&str>) -> Result<Option<String>, Error> {
+#[async_trait]
+impl RequestStore for DB {
+    async fn create_become_admin_request(&self, user_id: i64, user_name: Option<&str>) -> Result<String, DbError> {
         let request_id = Uuid::now_v7();
 
         // Check if there is a pending request for this user
         let exists: Option<(String,)> = sqlx::query_as(
-            "SELECT request_id FROM become_admin_requests WHERE user_id = ?",
+            "SELECT request_id FROM become_admin_requests WHERE user_id = $1 AND pending",
         )
         .bind(user_id)
         .fetch_optional(&*self.db)
         .await?;
 
         if exists.is_some() {
-            return Ok(None)
+            return Err(DbError::AlreadyExists)
         }
 
-        sqlx::query("INSERT INTO become_admin_requests (request_id, user_id, user_name) VALUES (?, ?, ?)")
+        sqlx::query("INSERT INTO become_admin_requests (request_id, user_id, user_name) VALUES ($1, $2, $3)")
             .bind(request_id.to_string())
             .bind(user_id)
             .bind(user_name)
             .execute(&*self.db)
             .await?;
         
-        Ok(Some(request_id.to_string()))
+        Ok(request_id.to_string())
     }
 
-    pub async fn get_become_admin_requests(&self) -> Result<Vec<BecomeAdminRequest>, Error> {
+    async fn get_become_admin_requests(&self) -> Result<Vec<BecomeAdminRequest>, DbError> {
         let requests = sqlx::query_as::<_, BecomeAdminRequest>(
             "SELECT request_id, user_id, user_name, created_at, pending, accepted FROM become_admin_requests",
         )
@@ -264,66 +510,707 @@ impl DB {
         Ok(requests)
     }
 
-    pub async fn approve_become_admin_request(&self, request_id: &str, admin_id: i64) -> Result<(), Error> {
-        // Create transaction, approve the request and create the admin
-        // if the request is not pending, return an error
+    async fn approve_become_admin_request(&self, request_id: &str, admin_id: i64) -> Result<(), DbError> {
+        // Answer the request and create the admin together, so a failure
+        // leaves the request pending
+        let mut tx = self.db.begin().await?;
+
         let request = sqlx::query_as::<_, BecomeAdminRequest>(
-            "SELECT request_id, user_id, user_name, created_at, pending, accepted FROM become_admin_requests WHERE request_id = ?",
+            "SELECT request_id, user_id, user_name, created_at, pending, accepted FROM become_admin_requests WHERE request_id = $1",
         )
             .bind(request_id)
-            .fetch_optional(&*self.db)
-            .await?;
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(DbError::NotFound)?;
 
-        if request.is_none() {
-            return Err(Error::RowNotFound);
+        if !request.pending.unwrap_or(false) {
+            return Err(DbError::NotPending);
         }
 
-        let request = request.unwrap();
+        sqlx::query("UPDATE become_admin_requests SET pending = FALSE, accepted = TRUE WHERE request_id = $1")
+            .bind(request_id)
+            .execute(&mut *tx)
+            .await?;
 
-        if request.pending.unwrap_or(false) {
-            sqlx::query("UPDATE become_admin_requests SET accepted = TRUE WHERE request_id = ?")
-                .bind(request_id)
-                .execute(&*self.db)
-                .await?;
+        sqlx::query("INSERT INTO admins (user_id, name, added_by) VALUES ($1, $2, $3)")
+            .bind(request.user_id)
+            .bind(request.user_name.as_deref())
+            .bind(admin_id)
+            .execute(&mut *tx)
+            .await?;
 
-            self.add_admin(request.user_id, admin_id, request.user_name.as_deref()).await?;
+        tx.commit().await?;
+        Ok(())
+    }
 
-            return Ok(())
+    async fn reject_become_admin_request(&self, request_id: &str) -> Result<(), DbError> {
+        let request = sqlx::query_as::<_, BecomeAdminRequest>(
+            "SELECT request_id, user_id, user_name, created_at, pending, accepted FROM become_admin_requests WHERE request_id = $1",
+        )
+            .bind(request_id)
+            .fetch_optional(&*self.db)
+            .await?
+            .ok_or(DbError::NotFound)?;
+
+        if !request.pending.unwrap_or(false) {
+            return Err(DbError::NotPending);
         }
 
-        Err(Error::RowNotFound)
+        sqlx::query("DELETE FROM become_admin_requests WHERE request_id = $1")
+            .bind(request_id)
+            .execute(&*self.db)
+            .await?;
+
+        Ok(())
     }
+}
 
-    pub async fn reject_become_admin_request(&self, request_id: &str) -> Result<(), Error> {
-        // Create transaction, reject the request and delete the request
-        // if the request is not pending, return an error
-        let request = sqlx::query_as::<_, BecomeAdminRequest>(
-            "SELECT request_id, user_id, user_name, created_at, pending, accepted FROM become_admin_requests WHERE request_id = ?",
+#[async_trait]
+impl IgnoreStore for DB {
+    async fn ignore_user(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        added_by: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DbError> {
+        // Replace any previous entry with the same scope, so that re-ignoring
+        // a user updates the expiry instead of failing
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query("DELETE FROM ignored_users WHERE user_id = $1 AND chat_id IS NOT DISTINCT FROM $2")
+            .bind(user_id)
+            .bind(chat_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("INSERT INTO ignored_users (user_id, chat_id, added_by, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(user_id)
+            .bind(chat_id)
+            .bind(added_by)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn unignore_user(&self, user_id: i64, chat_id: Option<i64>) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM ignored_users WHERE user_id = $1 AND chat_id IS NOT DISTINCT FROM $2")
+            .bind(user_id)
+            .bind(chat_id)
+            .execute(&*self.db)
+            .await?;
+        affected(result.rows_affected())
+    }
+
+    async fn is_user_ignored(&self, user_id: i64, chat_id: i64) -> Result<bool, DbError> {
+        let exists: Option<(i64,)> = sqlx::query_as(
+            "SELECT user_id FROM ignored_users WHERE user_id = $1 AND (chat_id IS NULL OR chat_id = $2) AND (expires_at IS NULL OR expires_at > $3)",
         )
-            .bind(request_id)
+        .bind(user_id)
+        .bind(chat_id)
+        .bind(Utc::now())
+        .fetch_optional(&*self.db)
+        .await?;
+
+        Ok(exists.is_some())
+    }
+
+    async fn get_ignored_users(&self) -> Result<Vec<IgnoredUser>, DbError> {
+        let users = sqlx::query_as::<_, IgnoredUser>(
+            "SELECT user_id, chat_id, added_by, created_at, expires_at FROM ignored_users WHERE expires_at IS NULL OR expires_at > $1",
+        )
+        .bind(Utc::now())
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(users)
+    }
+
+    async fn add_exempted_bot(&self, user_id: i64, added_by: i64, name: Option<&str>) -> Result<(), DbError> {
+        sqlx::query("INSERT INTO exempted_bots (user_id, name, added_by) VALUES ($1, $2, $3)")
+            .bind(user_id)
+            .bind(name)
+            .bind(added_by)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_exempted_bot(&self, user_id: i64) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM exempted_bots WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&*self.db)
+            .await?;
+        affected(result.rows_affected())
+    }
+
+    async fn is_bot_exempted(&self, user_id: i64) -> Result<bool, DbError> {
+        let exists: Option<(i64,)> = sqlx::query_as("SELECT user_id FROM exempted_bots WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(&*self.db)
+            .await?;
+
+        Ok(exists.is_some())
+    }
+
+    async fn get_exempted_bots(&self) -> Result<Vec<ExemptedBot>, DbError> {
+        let bots = sqlx::query_as::<_, ExemptedBot>(
+            "SELECT user_id, name, added_by, created_at FROM exempted_bots",
+        )
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(bots)
+    }
+}
+
+#[async_trait]
+impl ModuleStore for DB {
+    async fn disable_module(&self, chat_id: i64, module: &str, added_by: i64) -> Result<(), DbError> {
+        sqlx::query("INSERT INTO disabled_modules (chat_id, module, added_by) VALUES ($1, $2, $3)")
+            .bind(chat_id)
+            .bind(module)
+            .bind(added_by)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_module(&self, chat_id: i64, module: &str) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM disabled_modules WHERE chat_id = $1 AND module = $2")
+            .bind(chat_id)
+            .bind(module)
+            .execute(&*self.db)
+            .await?;
+        affected(result.rows_affected())
+    }
+
+    async fn is_module_disabled(&self, chat_id: i64, module: &str) -> Result<bool, DbError> {
+        let exists: Option<(i64,)> = sqlx::query_as("SELECT chat_id FROM disabled_modules WHERE chat_id = $1 AND module = $2")
+            .bind(chat_id)
+            .bind(module)
             .fetch_optional(&*self.db)
             .await?;
 
-        if request.is_none() {
-            return Err(Error::RowNotFound);
+        Ok(exists.is_some())
+    }
+
+    async fn get_disabled_modules(&self) -> Result<Vec<DisabledModule>, DbError> {
+        let modules = sqlx::query_as::<_, DisabledModule>(
+            "SELECT chat_id, module, added_by, created_at FROM disabled_modules",
+        )
+        .fetch_all(&*self.db)
+        .await?;
+
+        Ok(modules)
+    }
+}
+
+#[async_trait]
+impl DialogStore for DB {
+    async fn save_dialog(&self, dialog: &OpenDialog) -> Result<(), DbError> {
+        let answers = serde_json::to_string(&dialog.answers).expect("answers serialize to JSON");
+        sqlx::query(
+            "INSERT INTO dialogs (chat_id, user_id, dialog, answers, expires_at) VALUES ($1, $2, $3, $4, $5) \
+             ON CONFLICT (chat_id, user_id) DO UPDATE SET dialog = $3, answers = $4, expires_at = $5",
+        )
+        .bind(dialog.chat_id)
+        .bind(dialog.user_id)
+        .bind(&dialog.dialog)
+        .bind(answers)
+        .bind(dialog.expires_at)
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_dialog(&self, chat_id: i64, user_id: i64) -> Result<Option<OpenDialog>, DbError> {
+        let row: Option<DialogRow> = sqlx::query_as(
+            "SELECT chat_id, user_id, dialog, answers, expires_at FROM dialogs WHERE chat_id = $1 AND user_id = $2",
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .fetch_optional(&*self.db)
+        .await?;
+
+        row.map(OpenDialog::from_row).transpose()
+    }
+
+    async fn remove_dialog(&self, chat_id: i64, user_id: i64) -> Result<(), DbError> {
+        let result = sqlx::query("DELETE FROM dialogs WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat_id)
+            .bind(user_id)
+            .execute(&*self.db)
+            .await?;
+        affected(result.rows_affected())
+    }
+
+    async fn get_dialogs(&self) -> Result<Vec<OpenDialog>, DbError> {
+        let rows: Vec<DialogRow> = sqlx::query_as("SELECT chat_id, user_id, dialog, answers, expires_at FROM dialogs")
+            .fetch_all(&*self.db)
+            .await?;
+
+        rows.into_iter().map(OpenDialog::from_row).collect()
+    }
+}
+
+/// Compares a timestamp column with `$1`. SQLite keeps timestamps as text
+/// written by both `CURRENT_TIMESTAMP` and sqlx, in different formats, so
+/// they are normalized first.
+#[cfg(not(feature = "postgres"))]
+fn earlier_than(column: &str) -> String {
+    format!("datetime({column}) < datetime($1)")
+}
+
+#[cfg(feature = "postgres")]
+fn earlier_than(column: &str) -> String {
+    format!("{column} < $1")
+}
+
+#[async_trait]
+impl RetentionStore for DB {
+    async fn forget_user(&self, user_id: i64) -> Result<ForgetReport, DbError> {
+        let mut tx = self.db.begin().await?;
+
+        let requests = sqlx::query("DELETE FROM become_admin_requests WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let admin = sqlx::query("UPDATE admins SET name = NULL WHERE user_id = $1 AND name IS NOT NULL")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let dialogs = sqlx::query("DELETE FROM dialogs WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let ignored: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM ignored_users WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(ForgetReport {
+            requests_deleted: requests.rows_affected(),
+            admin_name_removed: admin.rows_affected() > 0,
+            dialogs_deleted: dialogs.rows_affected(),
+            ignore_entries_kept: ignored.0 as u64,
+        })
+    }
+
+    async fn purge_requests(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let result = sqlx::query(&format!("DELETE FROM become_admin_requests WHERE {}", earlier_than("created_at")))
+            .bind(before)
+            .execute(&*self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn purge_expired_ignores(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let result = sqlx::query(&format!("DELETE FROM ignored_users WHERE {}", earlier_than("expires_at")))
+            .bind(before)
+            .execute(&*self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn purge_expired_dialogs(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let result = sqlx::query(&format!("DELETE FROM dialogs WHERE {}", earlier_than("expires_at")))
+            .bind(before)
+            .execute(&*self.db)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for DB {
+    #[cfg(not(feature = "postgres"))]
+    async fn vacuum_into(&self, path: &str) -> Result<(), DbError> {
+        sqlx::query("VACUUM INTO $1")
+            .bind(path)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "postgres")]
+    async fn vacuum_into(&self, _path: &str) -> Result<(), DbError> {
+        Err(DbError::Storage(Error::Protocol("snapshots are not supported on PostgreSQL, use pg_dump".to_string())))
+    }
+
+    async fn replace_config(&self, doc: &ConfigDocument) -> Result<(), DbError> {
+        let mut tx = self.db.begin().await?;
+
+        for table in ["whitelisted_threads", "whitelisted_groups", "ignored_users", "exempted_bots", "disabled_modules", "admins"] {
+            sqlx::query(&format!("DELETE FROM {table}"))
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Admins reference the admin that added them, so parents go first
+        for admin in doc.admins_in_insert_order() {
+            sqlx::query("INSERT INTO admins (user_id, name, added_by, added_at) VALUES ($1, $2, $3, COALESCE($4, CURRENT_TIMESTAMP))")
+                .bind(admin.user_id)
+                .bind(&admin.name)
+                .bind(admin.added_by)
+                .bind(admin.added_at)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        for group in &doc.whitelisted_groups {
+            sqlx::query("INSERT INTO whitelisted_groups (group_id, group_name, added_by, created_at) VALUES ($1, $2, $3, COALESCE($4, CURRENT_TIMESTAMP))")
+                .bind(group.group_id)
+                .bind(&group.group_name)
+                .bind(group.added_by)
+                .bind(group.created_at)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        for thread in &doc.whitelisted_threads {
+            sqlx::query("INSERT INTO whitelisted_threads (thread_id, group_id, group_name, thread_name, added_by, created_at) VALUES ($1, $2, $3, $4, $5, COALESCE($6, CURRENT_TIMESTAMP))")
+                .bind(thread.thread_id)
+                .bind(thread.group_id)
+                .bind(&thread.group_name)
+                .bind(&thread.thread_name)
+                .bind(thread.added_by)
+                .bind(thread.created_at)
+                .execute(&mut *tx)
+                .await?;
         }
 
-        let request = request.unwrap();
+        for user in &doc.ignored_users {
+            sqlx::query("INSERT INTO ignored_users (user_id, chat_id, added_by, created_at, expires_at) VALUES ($1, $2, $3, COALESCE($4, CURRENT_TIMESTAMP), $5)")
+                .bind(user.user_id)
+                .bind(user.chat_id)
+                .bind(user.added_by)
+                .bind(user.created_at)
+                .bind(user.expires_at)
+                .execute(&mut *tx)
+                .await?;
+        }
 
-        if request.pending.unwrap_or(false) {
-            sqlx::query("UPDATE become_admin_requests SET pending = FALSE WHERE request_id = ?")
-                .bind(request_id)
-                .execute(&*self.db)
+        for bot in &doc.exempted_bots {
+            sqlx::query("INSERT INTO exempted_bots (user_id, name, added_by, created_at) VALUES ($1, $2, $3, COALESCE($4, CURRENT_TIMESTAMP))")
+                .bind(bot.user_id)
+                .bind(&bot.name)
+                .bind(bot.added_by)
+                .bind(bot.created_at)
+                .execute(&mut *tx)
                 .await?;
+        }
 
-            sqlx::query("DELETE FROM become_admin_requests WHERE request_id = ?")
-                .bind(request_id)
-                .execute(&*self.db)
+        for module in &doc.disabled_modules {
+            sqlx::query("INSERT INTO disabled_modules (chat_id, module, added_by, created_at) VALUES ($1, $2, $3, COALESCE($4, CURRENT_TIMESTAMP))")
+                .bind(module.chat_id)
+                .bind(&module.module)
+                .bind(module.added_by)
+                .bind(module.created_at)
+                .execute(&mut *tx)
                 .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::{DbError, OpenDialog};
+    use crate::export::ConfigDocument;
+    use crate::testing::test_db;
+    use crate::storage::{AdminStore, DialogStore, IgnoreStore, ModuleStore, RequestStore, RetentionStore, SnapshotStore, WhitelistStore};
+
+    const SUPERADMIN: i64 = 640129894;
+
+    #[tokio::test]
+    async fn removing_an_admin_removes_the_admins_they_added() {
+        let db = test_db().await;
+        db.add_admin(2, Some(SUPERADMIN), Some("two")).await.unwrap();
+        db.add_admin(3, Some(2), Some("three")).await.unwrap();
+        db.add_admin(4, Some(SUPERADMIN), Some("four")).await.unwrap();
+
+        assert_eq!(db.count_admin_subtree(SUPERADMIN).await.unwrap(), 3);
+        assert_eq!(db.count_admin_subtree(2).await.unwrap(), 1);
+
+        assert!(matches!(db.remove_admin_with_traversal(2, 4).await, Err(DbError::PermissionDenied)));
+        db.remove_admin_with_traversal(2, SUPERADMIN).await.unwrap();
+        assert!(matches!(db.remove_admin_with_traversal(2, SUPERADMIN).await, Err(DbError::NotFound)));
 
-            return Ok(())
+        assert!(db.get_admin(2).await.unwrap().is_none());
+        assert!(db.get_admin(3).await.unwrap().is_none());
+        assert!(db.get_admin(4).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn removing_an_admin_keeps_the_groups_they_whitelisted() {
+        let db = test_db().await;
+        db.add_admin(2, Some(SUPERADMIN), Some("two")).await.unwrap();
+        db.add_whitelisted_group(-100, 2, Some("group")).await.unwrap();
+        db.add_whitelisted_thread(7, -100, 2, Some("group"), None).await.unwrap();
+
+        db.remove_admin(2).await.unwrap();
+
+        let group = db.get_whitelisted_group(-100).await.unwrap().unwrap();
+        assert_eq!(group.added_by, None);
+        assert!(db.is_thread_whitelisted(7, -100).await.unwrap());
+    }
+
+    /// Rows written while foreign keys weren't enforced are cleaned up by the
+    /// foreign keys migration
+    #[cfg(not(feature = "postgres"))]
+    #[tokio::test]
+    async fn the_foreign_keys_migration_removes_orphans() {
+        use sqlx::migrate::Migrate;
+
+        use super::{DB, MIGRATOR};
+
+        const FOREIGN_KEYS: i64 = 20251101090000;
+
+        // A file, so every pooled connection sees the same database
+        let dir = std::env::temp_dir().join(format!("telos-fk-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir(&dir).unwrap();
+        let db = DB::new(&format!("sqlite://{}?mode=rwc", dir.join("db.sqlite").display())).await.unwrap();
+
+        let mut conn = db.db.acquire().await.unwrap();
+        conn.ensure_migrations_table().await.unwrap();
+        for migration in MIGRATOR.iter().filter(|migration| migration.version < FOREIGN_KEYS) {
+            conn.apply(migration).await.unwrap();
         }
 
-        Err(Error::RowNotFound)
+        // The orphans were written by connections that didn't enforce foreign keys
+        sqlx::raw_sql(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO admins (user_id, added_by) VALUES (2, 640129894), (3, 99), (4, 3);
+             INSERT INTO whitelisted_groups (group_id, added_by) VALUES (-100, 2), (-200, 3), (-400, 99);
+             INSERT INTO whitelisted_threads (thread_id, group_id, added_by) VALUES (7, -100, 2), (8, -300, 2), (9, -400, 99);
+             INSERT INTO exempted_bots (user_id, added_by) VALUES (50, 99);
+             PRAGMA foreign_keys = ON;",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        drop(conn);
+
+        db.migrate().await.unwrap();
+        let admins: Vec<i64> = db.get_admins().await.unwrap().iter().map(|a| a.user_id).collect();
+        assert_eq!(admins.len(), 2);
+        assert!(admins.contains(&2));
+
+        let groups = db.get_whitelisted_groups().await.unwrap();
+        assert_eq!(groups.len(), 3);
+        assert!(groups.iter().any(|g| g.group_id == -200 && g.added_by.is_none()));
+        assert!(groups.iter().any(|g| g.group_id == -400 && g.added_by.is_none()));
+
+        let mut threads = db.get_all_whitelisted_threads().await.unwrap();
+        threads.sort_by_key(|t| t.thread_id);
+        assert_eq!(threads.len(), 2);
+        assert_eq!((threads[0].thread_id, threads[0].added_by), (7, Some(2)));
+        assert_eq!((threads[1].thread_id, threads[1].added_by), (9, None));
+
+        assert_eq!(db.get_exempted_bots().await.unwrap()[0].added_by, None);
+
+        let violations: Vec<(String,)> = sqlx::query_as("SELECT \"table\" FROM pragma_foreign_key_check")
+            .fetch_all(&*db.db)
+            .await
+            .unwrap();
+        assert!(violations.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removing_a_group_removes_its_threads() {
+        let db = test_db().await;
+        db.add_whitelisted_group(-100, SUPERADMIN, Some("group")).await.unwrap();
+        db.add_whitelisted_thread(7, -100, SUPERADMIN, Some("group"), None).await.unwrap();
+
+        assert!(db.is_group_whitelisted(-100).await.unwrap());
+        assert!(db.is_thread_whitelisted(7, -100).await.unwrap());
+        assert!(!db.is_thread_whitelisted(8, -100).await.unwrap());
+        assert!(matches!(db.add_whitelisted_group(-100, SUPERADMIN, None).await, Err(DbError::AlreadyExists)));
+        assert!(matches!(db.add_whitelisted_thread(7, -200, SUPERADMIN, None, None).await, Err(DbError::NotFound)));
+
+        db.remove_whitelisted_group(-100).await.unwrap();
+
+        assert!(!db.is_group_whitelisted(-100).await.unwrap());
+        assert!(db.get_all_whitelisted_threads().await.unwrap().is_empty());
+        assert!(matches!(db.remove_whitelisted_group(-100).await, Err(DbError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn requests_can_only_be_answered_once() {
+        let db = test_db().await;
+
+        let request_id = db.create_become_admin_request(2, Some("two")).await.unwrap();
+        assert!(matches!(db.create_become_admin_request(2, Some("two")).await, Err(DbError::AlreadyExists)));
+
+        db.approve_become_admin_request(&request_id, SUPERADMIN).await.unwrap();
+        assert_eq!(db.get_admin(2).await.unwrap().unwrap().added_by, Some(SUPERADMIN));
+        assert!(matches!(db.approve_become_admin_request(&request_id, SUPERADMIN).await, Err(DbError::NotPending)));
+        assert!(matches!(db.reject_become_admin_request(&request_id).await, Err(DbError::NotPending)));
+
+        // A second request from an admin can't be approved, and leaves no trace
+        let request_id = db.create_become_admin_request(2, Some("two")).await.unwrap();
+        assert!(matches!(db.approve_become_admin_request(&request_id, SUPERADMIN).await, Err(DbError::AlreadyExists)));
+        db.reject_become_admin_request(&request_id).await.unwrap();
+
+        let request_id = db.create_become_admin_request(3, None).await.unwrap();
+        db.reject_become_admin_request(&request_id).await.unwrap();
+        assert!(matches!(db.reject_become_admin_request(&request_id).await, Err(DbError::NotFound)));
+        assert!(db.get_admin(3).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn ignore_entries_respect_scope_and_expiry() {
+        let db = test_db().await;
+
+        db.ignore_user(2, Some(-100), SUPERADMIN, None).await.unwrap();
+        db.ignore_user(3, None, SUPERADMIN, Some(Utc::now() - Duration::minutes(1))).await.unwrap();
+
+        assert!(db.is_user_ignored(2, -100).await.unwrap());
+        assert!(!db.is_user_ignored(2, -200).await.unwrap());
+        assert!(!db.is_user_ignored(3, -100).await.unwrap());
+        assert_eq!(db.get_ignored_users().await.unwrap().len(), 1);
+
+        // Ignoring again with the same scope replaces the entry
+        db.ignore_user(3, None, SUPERADMIN, None).await.unwrap();
+        assert!(db.is_user_ignored(3, -200).await.unwrap());
+
+        db.unignore_user(2, Some(-100)).await.unwrap();
+        assert!(matches!(db.unignore_user(2, None).await, Err(DbError::NotFound)));
+        assert!(!db.is_user_ignored(2, -100).await.unwrap());
+
+        db.add_exempted_bot(50, SUPERADMIN, Some("bot")).await.unwrap();
+        assert!(db.is_bot_exempted(50).await.unwrap());
+        db.remove_exempted_bot(50).await.unwrap();
+        assert!(!db.is_bot_exempted(50).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn old_requests_and_expired_ignores_are_purged() {
+        let db = test_db().await;
+
+        sqlx::query("INSERT INTO become_admin_requests (request_id, user_id, created_at) VALUES ('old', 2, $1)")
+            .bind(Utc::now() - Duration::days(100))
+            .execute(&*db.db)
+            .await
+            .unwrap();
+        db.create_become_admin_request(3, None).await.unwrap();
+
+        db.ignore_user(4, None, SUPERADMIN, Some(Utc::now() - Duration::days(40))).await.unwrap();
+        db.ignore_user(5, None, SUPERADMIN, Some(Utc::now() - Duration::days(1))).await.unwrap();
+        db.ignore_user(6, None, SUPERADMIN, None).await.unwrap();
+
+        assert_eq!(db.purge_requests(Utc::now() - Duration::days(90)).await.unwrap(), 1);
+        assert_eq!(db.purge_expired_ignores(Utc::now() - Duration::days(30)).await.unwrap(), 1);
+
+        let requests = db.get_become_admin_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].user_id, 3);
+    }
+
+    #[tokio::test]
+    async fn forgetting_a_user_keeps_their_admin_rights() {
+        let db = test_db().await;
+        db.add_admin(2, Some(SUPERADMIN), Some("two")).await.unwrap();
+        db.create_become_admin_request(3, Some("three")).await.unwrap();
+        db.ignore_user(3, None, 2, None).await.unwrap();
+        for (chat_id, user_id) in [(2, 2), (-100, 2), (-100, 3)] {
+            let answers = vec!["my answer".to_string()];
+            let dialog = OpenDialog { chat_id, user_id, dialog: "name".to_string(), answers, expires_at: Utc::now() + Duration::hours(1) };
+            db.save_dialog(&dialog).await.unwrap();
+        }
+
+        let report = db.forget_user(2).await.unwrap();
+        assert!(report.admin_name_removed);
+        assert_eq!(report.dialogs_deleted, 2);
+        assert_eq!(db.get_admin(2).await.unwrap().unwrap().name, None);
+        assert!(db.get_dialog(-100, 3).await.unwrap().is_some());
+
+        let report = db.forget_user(3).await.unwrap();
+        assert_eq!(report.requests_deleted, 1);
+        assert_eq!(report.ignore_entries_kept, 1);
+        assert_eq!(report.dialogs_deleted, 1);
+        assert!(db.get_become_admin_requests().await.unwrap().is_empty());
+        assert!(db.get_dialogs().await.unwrap().is_empty());
+        assert!(db.is_user_ignored(3, -100).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_dialogs_are_purged() {
+        let db = test_db().await;
+        for (user_id, expires_in) in [(2, Duration::days(-2)), (3, Duration::hours(-1)), (4, Duration::hours(1))] {
+            let dialog = OpenDialog { chat_id: -100, user_id, dialog: "name".to_string(), answers: vec![], expires_at: Utc::now() + expires_in };
+            db.save_dialog(&dialog).await.unwrap();
+        }
+
+        assert_eq!(db.purge_expired_dialogs(Utc::now() - Duration::days(1)).await.unwrap(), 1);
+        assert_eq!(db.purge_expired_dialogs(Utc::now()).await.unwrap(), 1);
+
+        let dialogs = db.get_dialogs().await.unwrap();
+        assert_eq!(dialogs.len(), 1);
+        assert_eq!(dialogs[0].user_id, 4);
+    }
+
+    #[tokio::test]
+    async fn replacing_the_config_round_trips() {
+        let db = test_db().await;
+        db.add_admin(2, Some(SUPERADMIN), Some("two")).await.unwrap();
+        db.add_admin(3, Some(2), Some("three")).await.unwrap();
+        db.add_whitelisted_group(-100, 3, Some("group")).await.unwrap();
+        db.add_whitelisted_thread(7, -100, 3, Some("group"), None).await.unwrap();
+        db.ignore_user(9, None, 2, None).await.unwrap();
+        db.disable_module(-100, "miguel", 3).await.unwrap();
+
+        let exported = ConfigDocument::from_db(&db).await.unwrap();
+        assert!(exported.validate().is_empty());
+        assert_eq!(exported.disabled_modules.len(), 1);
+
+        let other = test_db().await;
+        other.replace_config(&exported).await.unwrap();
+
+        let imported = ConfigDocument::from_db(&other).await.unwrap();
+        assert!(exported.diff(&imported).is_empty());
+        assert_eq!(other.get_admin(3).await.unwrap().unwrap().added_by, Some(2));
+        assert!(other.is_module_disabled(-100, "miguel").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_new_dialog_replaces_the_open_one() {
+        let db = test_db().await;
+        let mut dialog = OpenDialog {
+            chat_id: -100,
+            user_id: 2,
+            dialog: "first".to_string(),
+            answers: vec!["one".to_string()],
+            expires_at: chrono::DateTime::from_timestamp(2_000_000_000, 0).unwrap(),
+        };
+        db.save_dialog(&dialog).await.unwrap();
+
+        dialog.dialog = "second".to_string();
+        dialog.answers = vec![];
+        db.save_dialog(&dialog).await.unwrap();
+        assert_eq!(db.get_dialog(-100, 2).await.unwrap(), Some(dialog));
+        assert!(db.get_dialog(-100, 3).await.unwrap().is_none());
+
+        db.remove_dialog(-100, 2).await.unwrap();
+        assert!(matches!(db.remove_dialog(-100, 2).await, Err(DbError::NotFound)));
+        assert!(db.get_dialogs().await.unwrap().is_empty());
     }
 }
@@ -0,0 +1,82 @@
+//! Multi-step commands. A dialog asks a user, or a chat acting as one, for
+//! one input at a time, checks each answer before asking the next question,
+//! and hands every answer to its module once the last one is in. Where each
+//! user is in their dialog is kept in the database, so a dialog carries on
+//! after a restart. `/cancel` ends it, and so does staying silent past its
+//! timeout.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use teloxide::prelude::*;
+
+use crate::{db::OpenDialog, identity::Identity, queue::Queue, storage::SharedStorage};
+
+pub const CANCEL: &str = "/cancel";
+
+/// One question of a dialog
+pub struct Step {
+    pub prompt: &'static str,
+    /// Tells what's wrong with an answer, which is then asked again
+    pub check: fn(&str) -> Result<(), &'static str>,
+}
+
+pub struct Dialog {
+    /// Unique across modules, it is how open dialogs are routed back
+    pub name: &'static str,
+    pub steps: &'static [Step],
+    /// How long the user has to answer each step
+    pub timeout: Duration,
+}
+
+impl Dialog {
+    /// Where `answers` leave the user, with what to send them next
+    pub fn next(&self, answers: &[String]) -> Option<&Step> {
+        self.steps.get(answers.len())
+    }
+}
+
+/// Accepts answers that are whole numbers of type `T`
+pub fn number<T: std::str::FromStr>(answer: &str) -> Result<(), &'static str> {
+    match answer.trim().parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err("That isn't a number."),
+    }
+}
+
+/// Puts the sender of `msg` in `dialog`, replacing any dialog they were in
+/// in this chat, and asks the first question
+pub async fn start(db: &SharedStorage, queue: &Queue, bot: &Bot, msg: &Message, dialog: &Dialog) -> ResponseResult<()> {
+    let Some(identity) = Identity::of(msg) else {
+        return Ok(());
+    };
+
+    let open = OpenDialog {
+        chat_id: msg.chat.id.0,
+        user_id: identity.id(),
+        dialog: dialog.name.to_string(),
+        answers: Vec::new(),
+        expires_at: expires_at(dialog),
+    };
+
+    if let Err(e) = db.save_dialog(&open).await {
+        tracing::error!("Error starting dialog {}: {:?}", dialog.name, e);
+        return reply(queue, bot, msg, "Error starting the dialog!").await;
+    }
+
+    let prompt = dialog.steps.first().map(|step| step.prompt).unwrap_or_default();
+    reply(queue, bot, msg, format!("{prompt}\n\nSend {CANCEL} to stop.")).await
+}
+
+pub fn expires_at(dialog: &Dialog) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::from_std(dialog.timeout).unwrap_or(chrono::Duration::MAX)
+}
+
+pub async fn reply(queue: &Queue, bot: &Bot, msg: &Message, text: impl Into<String>) -> ResponseResult<()> {
+    let mut reply = bot.send_message(msg.chat.id, text);
+    if let Some(thread_id) = msg.thread_id {
+        reply = reply.message_thread_id(thread_id);
+    }
+    queue.send(reply).await?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{db, storage::Storage};
+
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Everything an admin would otherwise have to retype when moving the bot:
+/// admins, whitelists, the ignore list, exempted bots and the modules
+/// disabled in each chat
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfigDocument {
+    pub version: u32,
+    #[serde(default)]
+    pub admins: Vec<db::Admin>,
+    #[serde(default)]
+    pub whitelisted_groups: Vec<db::WhitelistedGroup>,
+    #[serde(default)]
+    pub whitelisted_threads: Vec<db::WhitelistedThread>,
+    #[serde(default)]
+    pub ignored_users: Vec<db::IgnoredUser>,
+    #[serde(default)]
+    pub exempted_bots: Vec<db::ExemptedBot>,
+    #[serde(default)]
+    pub disabled_modules: Vec<db::DisabledModule>,
+}
+
+impl ConfigDocument {
+    pub async fn from_db(db: &dyn Storage) -> Result<Self, db::DbError> {
+        Ok(Self {
+            version: CONFIG_VERSION,
+            admins: db.get_admins().await?,
+            whitelisted_groups: db.get_whitelisted_groups().await?,
+            whitelisted_threads: db.get_all_whitelisted_threads().await?,
+            ignored_users: db.get_ignored_users().await?,
+            exempted_bots: db.get_exempted_bots().await?,
+            disabled_modules: db.get_disabled_modules().await?,
+        })
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self, String> {
+        let doc: Self = serde_json::from_slice(data).map_err(|e| format!("Invalid config document: {e}"))?;
+
+        let errors = doc.validate();
+        if !errors.is_empty() {
+            return Err(format!("Invalid config document:\n{}", errors.join("\n")));
+        }
+
+        Ok(doc)
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("config document is always serializable")
+    }
+
+    /// Returns every problem that would make the document fail to import
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if self.version != CONFIG_VERSION {
+            errors.push(format!("Unsupported version {}, expected {}", self.version, CONFIG_VERSION));
+        }
+
+        let admins: HashSet<i64> = self.admins.iter().map(|a| a.user_id).collect();
+        check_unique("admin", self.admins.iter().map(|a| a.user_id), &mut errors);
+        check_unique("group", self.whitelisted_groups.iter().map(|g| g.group_id), &mut errors);
+        check_unique("thread", self.whitelisted_threads.iter().map(|t| format!("{}/{}", t.group_id, t.thread_id)), &mut errors);
+        check_unique("ignored user", self.ignored_users.iter().map(|u| format!("{}@{:?}", u.user_id, u.chat_id)), &mut errors);
+        check_unique("exempted bot", self.exempted_bots.iter().map(|b| b.user_id), &mut errors);
+        check_unique("disabled module", self.disabled_modules.iter().map(|m| format!("{} in {}", m.module, m.chat_id)), &mut errors);
+
+        if !self.admins.iter().any(|a| a.is_superadmin()) {
+            errors.push("There must be at least one superadmin".to_string());
+        }
+
+        let ordered = self.admins_in_insert_order().len();
+        if ordered != self.admins.len() {
+            errors.push(format!(
+                "{} admin(s) were added by unknown admins or form a cycle",
+                self.admins.len() - ordered,
+            ));
+        }
+
+        let mut check_adder = |what: String, added_by: Option<i64>| {
+            if let Some(added_by) = added_by
+                && !admins.contains(&added_by)
+            {
+                errors.push(format!("{what} was added by unknown admin {added_by}"));
+            }
+        };
+
+        for group in &self.whitelisted_groups {
+            check_adder(format!("Group {}", group.group_id), group.added_by);
+        }
+        for thread in &self.whitelisted_threads {
+            check_adder(format!("Thread {}/{}", thread.group_id, thread.thread_id), thread.added_by);
+        }
+        for user in &self.ignored_users {
+            check_adder(format!("Ignored user {}", user.user_id), user.added_by);
+        }
+        for bot in &self.exempted_bots {
+            check_adder(format!("Exempted bot {}", bot.user_id), bot.added_by);
+        }
+        for module in &self.disabled_modules {
+            check_adder(format!("Module {} disabled in {}", module.module, module.chat_id), module.added_by);
+        }
+
+        let groups: HashSet<i64> = self.whitelisted_groups.iter().map(|g| g.group_id).collect();
+        for thread in &self.whitelisted_threads {
+            if !groups.contains(&thread.group_id) {
+                errors.push(format!("Thread {}/{} belongs to a group that is not whitelisted", thread.group_id, thread.thread_id));
+            }
+        }
+
+        errors
+    }
+
+    /// Admins ordered so that every admin comes after the admin that added
+    /// them. Admins that can't be reached from a superadmin are left out.
+    pub fn admins_in_insert_order(&self) -> Vec<&db::Admin> {
+        let mut children: HashMap<Option<i64>, Vec<&db::Admin>> = HashMap::new();
+        for admin in &self.admins {
+            children.entry(admin.added_by).or_default().push(admin);
+        }
+
+        let mut ordered = vec![];
+        let mut seen = HashSet::new();
+        let mut queue: Vec<&db::Admin> = children.get(&None).cloned().unwrap_or_default();
+        while let Some(admin) = queue.pop() {
+            if !seen.insert(admin.user_id) {
+                continue;
+            }
+            ordered.push(admin);
+            if let Some(added) = children.get(&Some(admin.user_id)) {
+                queue.extend(added.iter().copied());
+            }
+        }
+
+        ordered
+    }
+
+    /// Human readable list of what importing `new` over `self` would change
+    pub fn diff(&self, new: &ConfigDocument) -> Vec<String> {
+        let mut lines = vec![];
+
+        diff_section(
+            "admin",
+            &self.admins,
+            &new.admins,
+            |a| a.user_id,
+            |a| format!("{} (added by {})", a.name.as_deref().unwrap_or("<unnamed>"), describe_adder(a.added_by)),
+            &mut lines,
+        );
+        diff_section(
+            "group",
+            &self.whitelisted_groups,
+            &new.whitelisted_groups,
+            |g| g.group_id,
+            |g| g.group_name.clone().unwrap_or_else(|| "<unnamed>".to_string()),
+            &mut lines,
+        );
+        diff_section(
+            "thread",
+            &self.whitelisted_threads,
+            &new.whitelisted_threads,
+            |t| format!("{}/{}", t.group_id, t.thread_id),
+            |t| t.group_name.clone().unwrap_or_else(|| "<unnamed>".to_string()),
+            &mut lines,
+        );
+        diff_section(
+            "ignored user",
+            &self.ignored_users,
+            &new.ignored_users,
+            |u| match u.chat_id {
+                Some(chat_id) => format!("{} in {}", u.user_id, chat_id),
+                None => format!("{} everywhere", u.user_id),
+            },
+            |u| match u.expires_at {
+                Some(expires_at) => format!("until {}", expires_at.format("%Y-%m-%d %H:%M UTC")),
+                None => "forever".to_string(),
+            },
+            &mut lines,
+        );
+        diff_section(
+            "exempted bot",
+            &self.exempted_bots,
+            &new.exempted_bots,
+            |b| b.user_id,
+            |b| b.name.clone().unwrap_or_else(|| "<unnamed>".to_string()),
+            &mut lines,
+        );
+        diff_section(
+            "disabled module",
+            &self.disabled_modules,
+            &new.disabled_modules,
+            |m| format!("{} in {}", m.module, m.chat_id),
+            |m| format!("disabled by {}", describe_adder(m.added_by)),
+            &mut lines,
+        );
+
+        lines
+    }
+}
+
+fn describe_adder(added_by: Option<i64>) -> String {
+    match added_by {
+        Some(added_by) => added_by.to_string(),
+        None => "nobody, superadmin".to_string(),
+    }
+}
+
+fn check_unique<K: Display + Eq + std::hash::Hash>(what: &str, keys: impl Iterator<Item = K>, errors: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    for key in keys {
+        if seen.contains(&key) {
+            errors.push(format!("Duplicate {what} {key}"));
+        } else {
+            seen.insert(key);
+        }
+    }
+}
+
+fn diff_section<T, K: Ord + Display>(
+    what: &str,
+    current: &[T],
+    new: &[T],
+    key: impl Fn(&T) -> K,
+    describe: impl Fn(&T) -> String,
+    lines: &mut Vec<String>,
+) {
+    let current: BTreeMap<K, String> = current.iter().map(|item| (key(item), describe(item))).collect();
+    let new: BTreeMap<K, String> = new.iter().map(|item| (key(item), describe(item))).collect();
+
+    for (key, description) in &current {
+        match new.get(key) {
+            None => lines.push(format!("- {what} {key}: {description}")),
+            Some(new_description) if new_description != description => {
+                lines.push(format!("~ {what} {key}: {description} -> {new_description}"));
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (key, description) in &new {
+        if !current.contains_key(key) {
+            lines.push(format!("+ {what} {key}: {description}"));
+        }
+    }
+}
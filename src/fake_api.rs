@@ -0,0 +1,128 @@
+//! A stand-in for the Telegram Bot API on a local port. It answers every
+//! method with a plausible result and records the calls it received, so
+//! handlers can run against a `Bot` pointed at it with `set_api_url`.
+//! Chats have no administrators until they are added.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, Uri, header::CONTENT_TYPE},
+};
+use serde_json::{Value, json};
+use teloxide::Bot;
+use tokio::net::TcpListener;
+
+/// Every call received so far, as the method name and its parameters
+pub type Calls = Arc<Mutex<Vec<(String, Value)>>>;
+
+pub struct FakeApi {
+    address: SocketAddr,
+    state: Arc<ApiState>,
+}
+
+#[derive(Default)]
+struct ApiState {
+    calls: Calls,
+    /// The members listed by GetChatAdministrators, by chat
+    administrators: Mutex<HashMap<i64, Vec<Value>>>,
+}
+
+impl FakeApi {
+    /// Starts serving on a free local port until the runtime stops
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(ApiState::default());
+
+        let app = Router::new().fallback(answer).with_state(Arc::clone(&state));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Ok(Self { address, state })
+    }
+
+    /// A bot whose requests go to this API
+    pub fn bot(&self) -> Bot {
+        let url = format!("http://{}", self.address).parse().expect("a socket address is a valid URL");
+        Bot::new("TOKEN").set_api_url(url)
+    }
+
+    pub fn calls(&self) -> Calls {
+        Arc::clone(&self.state.calls)
+    }
+
+    /// Removes and returns the calls recorded so far
+    pub fn take_calls(&self) -> Vec<(String, Value)> {
+        std::mem::take(&mut *self.state.calls.lock().unwrap())
+    }
+
+    /// Lists `user_id` among the administrators of `chat_id`, as one of its
+    /// owners, which need no rights spelled out
+    pub fn add_administrator(&self, chat_id: i64, user_id: i64, anonymous: bool) {
+        let member = json!({
+            "status": "creator",
+            "user": {"id": user_id, "is_bot": false, "first_name": format!("user{user_id}")},
+            "is_anonymous": anonymous,
+        });
+        self.state.administrators.lock().unwrap().entry(chat_id).or_default().push(member);
+    }
+}
+
+async fn answer(State(state): State<Arc<ApiState>>, uri: Uri, headers: HeaderMap, body: Bytes) -> Json<Value> {
+    let method = uri.path().rsplit('/').next().unwrap_or_default().to_string();
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let body = match content_type.split_once("boundary=") {
+        Some((_, boundary)) => multipart_fields(&body, boundary.trim()),
+        None => serde_json::from_slice(&body).unwrap_or(Value::Null),
+    };
+
+    let result = match method.as_str() {
+        "SendMessage" | "EditMessageText" | "SendDocument" => json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": body["chat_id"].as_i64().unwrap_or(0), "type": "private", "first_name": "chat"},
+            "text": body["text"].as_str().unwrap_or_default(),
+        }),
+        "GetMe" => json!({
+            "id": 42,
+            "is_bot": true,
+            "first_name": "telos",
+            "username": "telos_bot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": true,
+            "supports_inline_queries": false,
+            "has_main_web_app": false,
+        }),
+        "GetUpdates" => json!([]),
+        "GetChatAdministrators" => {
+            let chat_id = body["chat_id"].as_i64().unwrap_or(0);
+            json!(state.administrators.lock().unwrap().get(&chat_id).cloned().unwrap_or_default())
+        }
+        _ => json!(true),
+    };
+    state.calls.lock().unwrap().push((method, body));
+
+    Json(json!({"ok": true, "result": result}))
+}
+
+/// The text fields of a multipart form, as a JSON object of strings
+fn multipart_fields(body: &[u8], boundary: &str) -> Value {
+    let body = String::from_utf8_lossy(body);
+    let mut fields = serde_json::Map::new();
+    for part in body.split(&format!("--{boundary}")) {
+        let Some((headers, value)) = part.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let Some(name) = headers.split("name=\"").nth(1).and_then(|n| n.split('"').next()) else {
+            continue;
+        };
+        fields.insert(name.to_string(), Value::String(value.trim_end_matches("\r\n").to_string()));
+    }
+    Value::Object(fields)
+}
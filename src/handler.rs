@@ -1,39 +1,98 @@
-use teloxide::{prelude::*};
-use crate::{admin::AdminHandler, miguel::MiguelHandler};
+use std::{sync::Arc, time::Instant};
+
+use teloxide::{dispatching::UpdateHandler, prelude::*};
+use tracing::Instrument;
+
+use crate::{logging, metrics::Metrics, module::Registry, record::{self, Recorder}, report};
+
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The update handler tree, shared by long polling and webhook mode
+pub fn schema(the_handler: Arc<Handler>) -> UpdateHandler<HandlerError> {
+    let message_handler = Arc::clone(&the_handler); // move clone into closure
+    let callback_handler = Arc::clone(&the_handler);
+    let metrics = Arc::clone(&the_handler.metrics);
+    let recorder = the_handler.recorder.clone();
+
+    dptree::entry()
+        .inspect(move |update: Update| {
+            metrics.update(&update);
+            if let Some(recorder) = &recorder {
+                recorder.update(&update);
+            }
+        })
+        .branch(Update::filter_message().branch(
+            Message::filter_text().endpoint(
+                move |bot: Bot, msg: Message, update: Update| {
+                    let handler_clone = Arc::clone(&message_handler); // clone inside closure
+
+                    let handling = async move {
+                        let start = Instant::now();
+                        let result = handler_clone.handle(&bot, &msg).await;
+                        handler_clone.finished("message", start, &result);
+                        Ok::<(), HandlerError>(())
+                    };
+                    record::within(update.id, handling.instrument(logging::update_span(&update)))
+                },
+            ),
+        ))
+        .branch(Update::filter_callback_query().endpoint(
+            move |bot: Bot, q: CallbackQuery, update: Update| {
+                let handler_clone = Arc::clone(&callback_handler);
+
+                let handling = async move {
+                    let start = Instant::now();
+                    let result = handler_clone.handle_callback_query(&bot, &q).await;
+                    handler_clone.finished("callback_query", start, &result);
+                    Ok::<(), HandlerError>(())
+                };
+                record::within(update.id, handling.instrument(logging::update_span(&update)))
+            },
+        ))
+}
 
 
 pub struct Handler {
-    miguel_handler: MiguelHandler,
-    admin_handler: AdminHandler,
+    registry: Registry,
+    metrics: Arc<Metrics>,
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl Handler {
-    pub fn new(miguel_handler: MiguelHandler, admin_handler: AdminHandler) -> Self {
-        Self { miguel_handler, admin_handler }
+    pub fn new(registry: Registry) -> Self {
+        Self { registry, metrics: Arc::default(), recorder: None }
     }
 
+    /// Records into `metrics`, which should also be passed to the registry and its modules
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 
+    /// Records every update received, which the queue should share to
+    /// record the calls made for them
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
 
-    pub async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
-        // Admin commands are immune to whitelisting
-        self.admin_handler.handle(bot, msg).await?;
-
-        // Check whitelisting
-        match self.admin_handler.check_whitelist(msg).await {
-            Ok(true) => {}
-            Ok(false) => {
-                log::trace!("Message is not whitelisted: {:?}", msg);
-                return Ok(());
-            }
-            Err(e) => {
-                log::error!("Error checking if message is whitelisted: {:?}", e);
-                return Ok(());
-            }
+    /// Errors are logged here, inside the update span, so their reports say
+    /// which chat and command failed
+    fn finished(&self, handler: &'static str, start: Instant, result: &ResponseResult<()>) {
+        self.metrics.handler_latency(handler, start.elapsed());
+        if let Err(e) = result {
+            self.metrics.api_error(e);
+            tracing::error!("Error handling {}: {}", handler, report::chain(e));
         }
+    }
 
-        self.miguel_handler.handle(bot, msg).await?;
 
-        Ok(())
+
+    pub async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        self.registry.handle(bot, msg).await
     }
-}
 
+    pub async fn handle_callback_query(&self, bot: &Bot, q: &CallbackQuery) -> ResponseResult<()> {
+        self.registry.handle_callback_query(bot, q).await
+    }
+}
@@ -0,0 +1,117 @@
+//! Who a message comes from. Besides users, Telegram lets the anonymous
+//! admins of a group post as the group itself, and channels post in groups,
+//! like the channel linked to a discussion group. Those messages come from
+//! placeholder bots (GroupAnonymousBot, Channel_Bot) with the chat in
+//! `sender_chat`, so the chat acts in their place. Chats are stored in the
+//! admin tables under their ids, which are negative and never collide with
+//! user ids.
+
+use teloxide::{prelude::*, types::ChatId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Identity {
+    User(UserId),
+    /// One of the anonymous admins of the group, who can't be told apart
+    AnonymousAdmin(ChatId),
+    /// A channel posting in a group
+    Channel(ChatId),
+}
+
+impl Identity {
+    /// Who sent `msg`, None for service messages with no sender
+    pub fn of(msg: &Message) -> Option<Self> {
+        match &msg.sender_chat {
+            Some(chat) if chat.id == msg.chat.id && !chat.is_channel() => Some(Self::AnonymousAdmin(chat.id)),
+            Some(chat) => Some(Self::Channel(chat.id)),
+            None => msg.from.as_ref().map(|from| Self::User(from.id)),
+        }
+    }
+
+    /// The id the identity is stored under
+    pub fn id(&self) -> i64 {
+        match self {
+            Self::User(user_id) => user_id.0 as i64,
+            Self::AnonymousAdmin(chat_id) | Self::Channel(chat_id) => chat_id.0,
+        }
+    }
+
+    /// Checks that an anonymous admin still is one, against the chat's
+    /// administrators as Telegram lists them. Users and channels can't be
+    /// impersonated, so they always pass.
+    pub async fn verify(&self, bot: &Bot) -> ResponseResult<bool> {
+        match self {
+            Self::AnonymousAdmin(chat_id) => {
+                let admins = bot.get_chat_administrators(*chat_id).await?;
+                Ok(admins.iter().any(|admin| admin.is_anonymous()))
+            }
+            Self::User(_) | Self::Channel(_) => Ok(true),
+        }
+    }
+}
+
+/// The username of who sent `msg`, the chat's when it was sent as one
+pub fn username(msg: &Message) -> Option<&str> {
+    match &msg.sender_chat {
+        Some(chat) => chat.username(),
+        None => msg.from.as_ref()?.username.as_deref(),
+    }
+}
+
+/// A name to list who sent `msg` under: a user's username, a chat's title
+pub fn name(msg: &Message) -> Option<&str> {
+    match &msg.sender_chat {
+        Some(chat) => chat.title().or(chat.username()),
+        None => msg.from.as_ref()?.username.as_deref(),
+    }
+}
+
+/// Whether `user_id` administers `chat_id`, for buttons sent to a chat that
+/// acts as an admin, which any of its admins may press
+pub async fn is_chat_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> ResponseResult<bool> {
+    let admins = bot.get_chat_administrators(chat_id).await?;
+    Ok(admins.iter().any(|admin| admin.user.id == user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use teloxide::{
+        prelude::*,
+        types::{ChatId, UserId},
+    };
+
+    use super::{Identity, name, username};
+
+    const GROUP: i64 = -100;
+    const CHANNEL: i64 = -200;
+
+    fn message(sender_chat: Option<serde_json::Value>) -> Message {
+        let mut message = json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": GROUP, "type": "supergroup", "title": "group"},
+            "from": {"id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot"},
+            "text": "hi",
+        });
+        match sender_chat {
+            Some(chat) => message["sender_chat"] = chat,
+            None => message["from"] = json!({"id": 7, "is_bot": false, "first_name": "user", "username": "seven"}),
+        }
+        serde_json::from_value(message).unwrap()
+    }
+
+    #[test]
+    fn chats_sending_messages_act_in_place_of_the_placeholder_bots() {
+        let user = message(None);
+        assert_eq!(Identity::of(&user), Some(Identity::User(UserId(7))));
+        assert_eq!((Identity::of(&user).unwrap().id(), username(&user)), (7, Some("seven")));
+
+        let anonymous = message(Some(json!({"id": GROUP, "type": "supergroup", "title": "group"})));
+        assert_eq!(Identity::of(&anonymous), Some(Identity::AnonymousAdmin(ChatId(GROUP))));
+        assert_eq!(name(&anonymous), Some("group"));
+
+        let channel = message(Some(json!({"id": CHANNEL, "type": "channel", "title": "news", "username": "news"})));
+        assert_eq!(Identity::of(&channel), Some(Identity::Channel(ChatId(CHANNEL))));
+        assert_eq!((Identity::of(&channel).unwrap().id(), username(&channel)), (CHANNEL, Some("news")));
+    }
+}
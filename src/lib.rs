@@ -0,0 +1,27 @@
+pub mod admin;
+pub mod backup;
+pub mod callback;
+pub mod cli;
+pub mod config;
+pub mod db;
+pub mod dialog;
+pub mod export;
+pub mod fake_api;
+pub mod handler;
+pub mod identity;
+pub mod logging;
+pub mod metrics;
+pub mod miguel;
+pub mod module;
+pub mod polling;
+pub mod queue;
+pub mod record;
+pub mod replay;
+pub mod report;
+pub mod retention;
+pub mod shutdown;
+pub mod simulate;
+pub mod storage;
+pub mod webhook;
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,172 @@
+//! Logging through `tracing`, with one span per update. Log lines from
+//! dependencies that use `log` end up in the same output.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+use teloxide::types::{Update, UpdateKind};
+use tracing::{Span, field::Empty};
+use tracing_subscriber::{EnvFilter, Layer, filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{config::LoggingSettings, report::ReportLayer};
+
+/// Whether message text may be logged, off unless opted in
+static LOG_CONTENT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+/// Installs the global subscriber, posting reports through `report` when
+/// given. Fails if the filter is invalid or a subscriber was already
+/// installed.
+pub fn init(settings: &LoggingSettings, report: Option<ReportLayer>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    LOG_CONTENT.store(settings.content, Ordering::Relaxed);
+
+    let filter = EnvFilter::try_new(&settings.filter)?;
+    let output = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let output = match settings.format {
+        LogFormat::Human => output.boxed(),
+        LogFormat::Json => output.json().with_current_span(true).with_span_list(false).boxed(),
+    };
+
+    // Reports don't depend on the log filter
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(report.with_filter(LevelFilter::INFO))
+        .try_init()?;
+
+    Ok(())
+}
+
+/// The span every update is handled in. `handler` is filled in by the
+/// handler that acts on it. The command is recorded without its arguments.
+pub fn update_span(update: &Update) -> Span {
+    let kind = match &update.kind {
+        UpdateKind::Message(_) => "message",
+        UpdateKind::CallbackQuery(_) => "callback_query",
+        _ => "other",
+    };
+
+    let span = tracing::info_span!(
+        "update",
+        update_id = update.id.0,
+        kind,
+        chat_id = Empty,
+        thread_id = Empty,
+        user_id = Empty,
+        handler = Empty,
+        command = Empty,
+        text = Empty,
+    );
+
+    if let Some(chat) = update.chat() {
+        span.record("chat_id", chat.id.0);
+    }
+    if let Some(user) = update.from() {
+        span.record("user_id", user.id.0);
+    }
+
+    let (thread_id, text) = match &update.kind {
+        UpdateKind::Message(msg) => (msg.thread_id, msg.text()),
+        UpdateKind::CallbackQuery(q) => (None, q.data.as_deref()),
+        _ => (None, None),
+    };
+    if let Some(thread_id) = thread_id {
+        span.record("thread_id", thread_id.0.0);
+    }
+    if let UpdateKind::Message(msg) = &update.kind
+        && let Some(command) = msg.text().and_then(|text| text.split_whitespace().next())
+        && command.starts_with('/')
+    {
+        span.record("command", command.split('@').next().unwrap_or(command));
+    }
+    if let Some(text) = text
+        && LOG_CONTENT.load(Ordering::Relaxed)
+    {
+        span.record("text", text);
+    }
+
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex, atomic::Ordering},
+    };
+
+    use serde_json::{Value, json};
+    use teloxide::types::Update;
+
+    use super::{LOG_CONTENT, update_span};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logged_span(update: &Update) -> Value {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _entered = update_span(update).entered();
+            tracing::Span::current().record("handler", "miguel");
+            tracing::info!("handled");
+        });
+
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        serde_json::from_str::<Value>(&line).unwrap()["span"].clone()
+    }
+
+    #[test]
+    fn updates_are_logged_with_ids_and_without_text() {
+        // Update only deserializes from text
+        let update: Update = serde_json::from_str(&json!({
+            "update_id": 7,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "message_thread_id": 3,
+                "chat": { "id": -100, "type": "supergroup", "title": "gelos" },
+                "from": { "id": 42, "is_bot": false, "first_name": "user" },
+                "text": "/miguel secret",
+            },
+        })
+        .to_string())
+        .unwrap();
+
+        let span = logged_span(&update);
+        assert_eq!(span["update_id"], 7);
+        assert_eq!(span["chat_id"], -100);
+        assert_eq!(span["thread_id"], 3);
+        assert_eq!(span["user_id"], 42);
+        assert_eq!(span["handler"], "miguel");
+        assert_eq!(span["command"], "/miguel");
+        assert!(span.get("text").is_none());
+
+        LOG_CONTENT.store(true, Ordering::Relaxed);
+        let span = logged_span(&update);
+        LOG_CONTENT.store(false, Ordering::Relaxed);
+        assert_eq!(span["text"], "/miguel secret");
+    }
+}
@@ -1,85 +1,359 @@
-use std::{env, process::exit, sync::Arc};
+use std::{env, process::exit, sync::Arc, time::Duration};
 
 use dotenv::dotenv;
 use teloxide::prelude::*;
 
-mod db;
-mod handler;
-mod admin;
-mod miguel;
+use telos::{
+    admin, backup, cli, config::Config, db, export::ConfigDocument, handler, logging, metrics, miguel, module, polling, queue,
+    record, replay, report, retention, shutdown, simulate,
+    storage::{cache::CachedStorage, metered::MeteredStorage},
+    webhook,
+};
 
-async fn create_db() -> Result<db::DB, Box<dyn std::error::Error>> {
-    let path = env::var("DATABASE_PATH").unwrap_or_else(|_| "sqlite://db.sqlite?mode=rwc".to_string());
+fn restore_db(config: &Config, snapshot: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = &config.database.path;
+    let database = backup::database_file(path).ok_or_else(|| format!("{path:?} is not a database file"))?;
 
-    let db = db::DB::new(&path).await?;
+    backup::restore(std::path::Path::new(snapshot), &database)
+}
+
+async fn create_db(config: &Config) -> Result<db::DB, Box<dyn std::error::Error>> {
+    let path = &config.database.path;
+
+    let db = db::DB::new(path).await?;
+    let pending = db.pending_migrations().await?;
     db.migrate().await?;
+    if !pending.is_empty() {
+        tracing::info!(report = true, "Applied migrations: {}", pending.join(", "));
+    }
 
-    log::info!("Database created at {:?}", path);
+    tracing::info!("Database created at {:?}", path);
 
     Ok(db)
 }
 
+/// Exits once the reports of what went wrong were posted
+async fn fail(reporter: Option<&report::Reporter>) -> ! {
+    if let Some(reporter) = reporter {
+        reporter.flush(Duration::from_secs(5)).await;
+    }
+    exit(1)
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    pretty_env_logger::init();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // Logging is configured too, so this can only be reported on stderr
+    let config = match Config::load(&mut args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(1);
+        }
+    };
+
+    let (report_layer, outbox) = report::layer(&config.report).unzip();
+    if let Err(e) = logging::init(&config.logging, report_layer) {
+        eprintln!("Error setting up logging: {e}");
+        exit(1);
+    }
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--check-config") {
+        args.remove(pos);
+        if let Err(e) = config.token() {
+            eprintln!("{e}");
+            exit(1);
+        }
+
+        print!("{}", config.to_redacted_toml());
+        eprintln!("Config is valid");
+        return;
+    }
+
+    let restore = match args.iter().position(|arg| arg == "--restore") {
+        Some(pos) => {
+            let Some(snapshot) = args.get(pos + 1).cloned() else {
+                tracing::error!("--restore needs a snapshot file");
+                exit(1);
+            };
+            args.drain(pos..=pos + 1);
+            Some(snapshot)
+        }
+        None => None,
+    };
+
+    // Offline subcommands are checked before anything touches the database
+    let command = match args.first().map(String::as_str) {
+        None | Some("replay" | "simulate") => None,
+        Some(_) => match cli::Subcommand::parse(&args) {
+            Ok(cli::Subcommand::Help) => {
+                println!("{}", cli::USAGE);
+                return;
+            }
+            Ok(command) => Some(command),
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        },
+    };
 
-    log::info!("Starting bot...");
+    if let Some(snapshot) = restore
+        && let Err(e) = restore_db(&config, &snapshot)
+    {
+        tracing::error!("Error restoring database: {:?}", e);
+        exit(1);
+    }
+
+    if args.first().map(String::as_str) == Some("replay") {
+        let Some(path) = args.get(1) else {
+            eprintln!("Usage: telos replay <file>");
+            exit(1);
+        };
 
-    let token = match env::var("TELOXIDE_TOKEN") {
-        Ok(token) => token,
+        match replay::run(Arc::new(config), std::path::Path::new(path)).await {
+            Ok(replay) => {
+                println!("{replay}");
+                if !replay.differences.is_empty() {
+                    exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error replaying {path}: {e}");
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.first().map(String::as_str) == Some("simulate") {
+        if let Err(e) = simulate::run(Arc::new(config), std::io::stdin().lock(), &mut std::io::stdout()).await {
+            eprintln!("Error simulating: {e}");
+            exit(1);
+        }
+        return;
+    }
+
+    if let Some(command) = command {
+        // Subcommands migrate themselves, so `migrate` can report what it applied
+        let db = match db::DB::new(&config.database.path).await {
+            Ok(db) => db,
+            Err(e) => {
+                tracing::error!("Error opening database: {:?}", e);
+                exit(1);
+            }
+        };
+
+        if let Err(e) = cli::run(&db, &command).await {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
+    }
+
+    tracing::info!("Starting bot...");
+
+    let token = match config.token() {
+        Ok(token) => token.to_string(),
         Err(e) => {
-            log::error!("Error getting TELOXIDE_TOKEN: {:?}", e);
+            tracing::error!("{}", e);
             exit(1);
         }
     };
 
-    let bot = Bot::new(token);
+    let mut bot = Bot::new(token);
+    if let Some(url) = &config.api_url {
+        // Checked when the config was loaded
+        bot = bot.set_api_url(url.parse().expect("api_url is a valid URL"));
+    }
+    let recorder = match &config.record.path {
+        Some(path) => match record::Recorder::open(path) {
+            Ok(recorder) => Some(Arc::new(recorder)),
+            Err(e) => {
+                tracing::error!("Error opening recording {}: {}", path.display(), e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let metrics = Arc::new(metrics::Metrics::default());
+    let mut queue = queue::Queue::new(&config.queue).with_metrics(Arc::clone(&metrics));
+    if let Some(recorder) = &recorder {
+        queue = queue.with_recorder(Arc::clone(recorder));
+    }
+    let queue = Arc::new(queue);
+    let reporter = outbox.map(|outbox| outbox.spawn(bot.clone(), Arc::clone(&queue)));
 
-    let db = match create_db().await {
+    let webhook_config = match webhook::WebhookConfig::from_config(&config.webhook) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Error reading webhook config: {}", e);
+            fail(reporter.as_ref()).await;
+        }
+    };
+
+    let db = match create_db(&config).await {
         Ok(db) => db,
         Err(e) => {
-            log::error!("Error creating database: {:?}", e);
-            exit(1);
+            tracing::error!("Error creating database: {:?}", e);
+            fail(reporter.as_ref()).await;
         }
     };
 
+    let shutdown = shutdown::Shutdown::default();
+    let deadline = Duration::from_secs(config.shutdown.deadline_secs);
+
+    match backup::BackupSchedule::from_config(&config.backup) {
+        Ok(Some(schedule)) => {
+            shutdown.spawn("backups", backup::run_schedule(db.clone(), schedule, shutdown.clone()));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Error reading backup schedule: {}", e);
+            fail(reporter.as_ref()).await;
+        }
+    }
 
-    let miguel_handler = match miguel::MiguelHandler::new() {
-        Some(handler) => handler,
+    let config = Arc::new(config);
+
+    match metrics::MetricsConfig::from_config(&config.metrics) {
+        Ok(Some(config)) => {
+            let listener = match tokio::net::TcpListener::bind(config.address).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("Error binding metrics server to {}: {:?}", config.address, e);
+                    fail(reporter.as_ref()).await;
+                }
+            };
+
+            let max_poll_age = webhook_config.is_none().then_some(config.max_poll_age);
+            let router = metrics::router(Arc::clone(&metrics), db.clone(), max_poll_age);
+            tracing::info!("Serving metrics on {}", config.address);
+            let stop = shutdown.clone();
+            shutdown.spawn("metrics server", async move {
+                let server = axum::serve(listener, router).with_graceful_shutdown(async move { stop.triggered().await });
+                if let Err(e) = server.await {
+                    tracing::error!("Metrics server error: {:?}", e);
+                }
+            });
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Error reading metrics config: {}", e);
+            fail(reporter.as_ref()).await;
+        }
+    }
+
+    let miguel_handler = match miguel::MiguelHandler::new(Arc::clone(&config)) {
+        Some(handler) => handler.with_metrics(Arc::clone(&metrics)).with_queue(Arc::clone(&queue)),
         None => {
-            log::error!("Error creating miguel handler");
-            exit(1);
+            tracing::error!("Error creating miguel handler");
+            fail(reporter.as_ref()).await;
         }
     };
 
-    let admin_handler = match admin::AdminHandler::new(db.clone()) {
-        Some(handler) => handler,
+    let metered = Arc::new(MeteredStorage::new(Arc::new(db.clone()), Arc::clone(&metrics)));
+    let storage = Arc::new(CachedStorage::new(metered).with_metrics(Arc::clone(&metrics)));
+    storage.spawn_refresh(Duration::from_secs(config.database.cache_refresh_secs), &shutdown);
+    let cache_stats = storage.stats();
+
+    if let Some(recorder) = &recorder {
+        match ConfigDocument::from_db(&*storage).await {
+            Ok(doc) => recorder.config(doc),
+            Err(e) => {
+                tracing::error!("Error reading the config to record: {:?}", e);
+                fail(reporter.as_ref()).await;
+            }
+        }
+    }
+
+    match retention::RetentionPolicy::from_config(&config.retention) {
+        Ok(policy) => {
+            shutdown.spawn("retention", retention::run_schedule(storage.clone(), policy, shutdown.clone()));
+        }
+        Err(e) => {
+            tracing::error!("Error reading retention policy: {}", e);
+            fail(reporter.as_ref()).await;
+        }
+    }
+
+    let admin_handler = match admin::AdminHandler::new(storage.clone(), Arc::clone(&config)) {
+        Some(handler) => handler
+            .with_cache_stats(cache_stats)
+            .with_metrics(Arc::clone(&metrics))
+            .with_queue(Arc::clone(&queue)),
         None => {
-            log::error!("Error creating admin handler");
-            exit(1);
+            tracing::error!("Error creating admin handler");
+            fail(reporter.as_ref()).await;
         }
     };
 
-    let the_handler = Arc::new(handler::Handler::new(miguel_handler, admin_handler));
+    // Modules see each message in this order
+    let registry = module::Registry::new(storage)
+        .with_metrics(Arc::clone(&metrics))
+        .with_queue(queue)
+        .register(admin_handler)
+        .register(miguel_handler);
 
-    let schema = {
-        let handler_clone = Arc::clone(&the_handler); // move clone into closure
+    let mut the_handler = handler::Handler::new(registry).with_metrics(Arc::clone(&metrics));
+    if let Some(recorder) = recorder {
+        the_handler = the_handler.with_recorder(recorder);
+    }
+    let the_handler = Arc::new(the_handler);
 
-        Update::filter_message().branch(
-            Message::filter_text().endpoint(
-                move |bot: Bot, msg: Message| {
-                    let handler_clone = Arc::clone(&handler_clone); // clone inside closure
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler::schema(the_handler)).build();
+    let token = dispatcher.shutdown_token();
 
-                    async move {
-                        handler_clone.handle(&bot, &msg).await?;
-                        Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
-                    }
-                },
-            ),
-        )
-    };
+    let signalled = shutdown.clone();
+    tokio::spawn(async move {
+        match shutdown::signal().await {
+            Ok(signal) => tracing::info!(report = true, "Received {}, shutting down...", signal),
+            Err(e) => {
+                tracing::error!("Error listening for signals: {:?}", e);
+                return;
+            }
+        }
+        signalled.trigger();
+    });
+
+    let mode = if webhook_config.is_some() { "webhook" } else { "long polling" };
+    tracing::info!(report = true, "Bot started with {} (telos {})", mode, env!("CARGO_PKG_VERSION"));
+
+    match webhook_config {
+        Some(config) => {
+            let (listener, server) = match webhook::listen(bot, &config).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("Error starting webhook: {:?}", e);
+                    fail(reporter.as_ref()).await;
+                }
+            };
+
+            let error_handler = LoggingErrorHandler::with_custom_text("Error from the webhook listener");
+            let dispatching = dispatcher.dispatch_with_listener(listener, error_handler);
+            shutdown::dispatch(dispatching, token, &shutdown, deadline).await;
+
+            // Wait for the webhook to be deregistered
+            if let Err(e) = server.await {
+                tracing::error!("Error stopping webhook: {:?}", e);
+            }
+        }
+        None => {
+            let listener = polling::polling(bot, metrics).await;
+            let error_handler = LoggingErrorHandler::with_custom_text("Error from the update listener");
+            let dispatching = dispatcher.dispatch_with_listener(listener, error_handler);
+            shutdown::dispatch(dispatching, token, &shutdown, deadline).await;
+        }
+    }
 
-    Dispatcher::builder(bot, schema).build().dispatch().await;
+    shutdown.drain(deadline).await;
+    db.close().await;
+    tracing::info!(report = true, "Bot stopped");
+    if let Some(reporter) = &reporter {
+        reporter.flush(deadline).await;
+    }
 }
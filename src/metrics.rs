@@ -0,0 +1,316 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use chrono::Utc;
+use teloxide::{
+    RequestError,
+    types::{Update, UpdateKind},
+};
+
+use crate::{config::MetricsSettings, db};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Counters and latency histograms, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    updates: Mutex<BTreeMap<&'static str, u64>>,
+    commands: Mutex<BTreeMap<String, u64>>,
+    triggers: Mutex<BTreeMap<&'static str, u64>>,
+    reactions: Mutex<u64>,
+    api_errors: Mutex<BTreeMap<&'static str, u64>>,
+    outgoing: Mutex<BTreeMap<&'static str, u64>>,
+    handler_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    db_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    cache_lookups: Mutex<BTreeMap<&'static str, u64>>,
+    // Unix time in milliseconds, 0 until the first poll
+    last_poll: AtomicI64,
+}
+
+impl Metrics {
+    pub fn update(&self, update: &Update) {
+        *self.updates.lock().unwrap().entry(update_type(update)).or_default() += 1;
+    }
+
+    pub fn command(&self, command: &str) {
+        *self.commands.lock().unwrap().entry(command.to_string()).or_default() += 1;
+    }
+
+    pub fn trigger(&self, trigger: &'static str) {
+        *self.triggers.lock().unwrap().entry(trigger).or_default() += 1;
+    }
+
+    pub fn reaction(&self) {
+        *self.reactions.lock().unwrap() += 1;
+    }
+
+    pub fn api_error(&self, error: &RequestError) {
+        let kind = match error {
+            RequestError::Api(_) => "api",
+            RequestError::MigrateToChatId(_) => "migrate_to_chat_id",
+            RequestError::RetryAfter(_) => "retry_after",
+            RequestError::Network(_) => "network",
+            RequestError::InvalidJson { .. } => "invalid_json",
+            RequestError::Io(_) => "io",
+        };
+        *self.api_errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Records what the outgoing queue did with a call besides sending it,
+    /// like `retried` or `dropped`
+    pub fn outgoing(&self, outcome: &'static str) {
+        *self.outgoing.lock().unwrap().entry(outcome).or_default() += 1;
+    }
+
+    pub fn handler_latency(&self, handler: &'static str, elapsed: Duration) {
+        self.handler_latency.lock().unwrap().entry(handler).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    pub fn db_latency(&self, operation: &'static str, elapsed: Duration) {
+        self.db_latency.lock().unwrap().entry(operation).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    /// Records whether a lookup was answered from the cache, `hit` or `miss`
+    pub fn cache_lookup(&self, outcome: &'static str) {
+        *self.cache_lookups.lock().unwrap().entry(outcome).or_default() += 1;
+    }
+
+    /// Records that Telegram answered a request for updates
+    pub fn poll(&self) {
+        self.last_poll.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Time since the last successful poll, `None` before the first one
+    pub fn since_last_poll(&self) -> Option<Duration> {
+        match self.last_poll.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(Duration::from_millis((Utc::now().timestamp_millis() - last).max(0) as u64)),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        counters(&mut out, "telos_updates_total", "Updates received, by type", "type", &self.updates.lock().unwrap());
+        counters(&mut out, "telos_commands_total", "Commands handled, by name", "command", &self.commands.lock().unwrap());
+        counters(&mut out, "telos_triggers_total", "Triggers fired, by trigger", "trigger", &self.triggers.lock().unwrap());
+        header(&mut out, "telos_reactions_sent_total", "Reactions sent", "counter");
+        writeln!(out, "telos_reactions_sent_total {}", self.reactions.lock().unwrap()).unwrap();
+        counters(&mut out, "telos_api_errors_total", "Failed Bot API calls, by kind", "kind", &self.api_errors.lock().unwrap());
+        counters(
+            &mut out,
+            "telos_outgoing_total",
+            "Outgoing Bot API calls retried, coalesced or dropped by the queue",
+            "outcome",
+            &self.outgoing.lock().unwrap(),
+        );
+        histograms(
+            &mut out,
+            "telos_handler_duration_seconds",
+            "Time spent handling an update, by handler",
+            "handler",
+            &self.handler_latency.lock().unwrap(),
+        );
+        histograms(
+            &mut out,
+            "telos_db_duration_seconds",
+            "Time spent in database calls, by operation",
+            "operation",
+            &self.db_latency.lock().unwrap(),
+        );
+        counters(
+            &mut out,
+            "telos_cache_lookups_total",
+            "Per-message lookups, by whether the cache answered them",
+            "outcome",
+            &self.cache_lookups.lock().unwrap(),
+        );
+
+        if let Some(since) = self.since_last_poll() {
+            header(&mut out, "telos_seconds_since_last_poll", "Seconds since Telegram last answered a poll", "gauge");
+            writeln!(out, "telos_seconds_since_last_poll {}", since.as_secs_f64()).unwrap();
+        }
+
+        out
+    }
+}
+
+fn update_type(update: &Update) -> &'static str {
+    match &update.kind {
+        UpdateKind::Message(_) => "message",
+        UpdateKind::EditedMessage(_) => "edited_message",
+        UpdateKind::ChannelPost(_) => "channel_post",
+        UpdateKind::EditedChannelPost(_) => "edited_channel_post",
+        UpdateKind::MessageReaction(_) => "message_reaction",
+        UpdateKind::CallbackQuery(_) => "callback_query",
+        UpdateKind::MyChatMember(_) => "my_chat_member",
+        UpdateKind::ChatMember(_) => "chat_member",
+        UpdateKind::ChatJoinRequest(_) => "chat_join_request",
+        _ => "other",
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn counters<K: AsRef<str>>(out: &mut String, name: &str, help: &str, label: &str, values: &BTreeMap<K, u64>) {
+    header(out, name, help, "counter");
+    for (key, value) in values {
+        writeln!(out, "{name}{{{label}=\"{}\"}} {value}", escape(key.as_ref())).unwrap();
+    }
+}
+
+fn histograms(out: &mut String, name: &str, help: &str, label: &str, values: &BTreeMap<&'static str, Histogram>) {
+    header(out, name, help, "histogram");
+    for (key, histogram) in values {
+        for (count, bound) in histogram.counts.iter().zip(BUCKETS) {
+            writeln!(out, "{name}_bucket{{{label}=\"{key}\",le=\"{bound}\"}} {count}").unwrap();
+        }
+        writeln!(out, "{name}_bucket{{{label}=\"{key}\",le=\"+Inf\"}} {}", histogram.count).unwrap();
+        writeln!(out, "{name}_sum{{{label}=\"{key}\"}} {}", histogram.sum).unwrap();
+        writeln!(out, "{name}_count{{{label}=\"{key}\"}} {}", histogram.count).unwrap();
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[derive(Clone, Debug)]
+pub struct MetricsConfig {
+    pub address: SocketAddr,
+    /// `/healthz` fails when the last successful poll is older than this
+    pub max_poll_age: Duration,
+}
+
+impl MetricsConfig {
+    /// Builds the config from the `[metrics]` settings. The server is
+    /// disabled unless `metrics.address` is set.
+    pub fn from_config(settings: &MetricsSettings) -> Result<Option<Self>, String> {
+        let address = match &settings.address {
+            Some(address) => address
+                .parse()
+                .map_err(|_| format!("metrics.address must be an ip:port address, got {address:?}"))?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self { address, max_poll_age: Duration::from_secs(settings.max_poll_age_secs) }))
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    metrics: Arc<Metrics>,
+    db: db::DB,
+    /// `None` in webhook mode, where Telegram is never polled
+    max_poll_age: Option<Duration>,
+}
+
+/// Routes for `/metrics` and `/healthz`
+pub fn router(metrics: Arc<Metrics>, db: db::DB, max_poll_age: Option<Duration>) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .route("/healthz", get(health))
+        .with_state(ServerState { metrics, db, max_poll_age })
+}
+
+async fn render(State(state): State<ServerState>) -> String {
+    state.metrics.render()
+}
+
+async fn health(State(state): State<ServerState>) -> (StatusCode, String) {
+    let mut problems = vec![];
+
+    if let Err(e) = state.db.ping().await {
+        problems.push(format!("database: {e}"));
+    }
+
+    if let Some(max_poll_age) = state.max_poll_age {
+        match state.metrics.since_last_poll() {
+            None => problems.push("polling: Telegram has not been polled yet".to_string()),
+            Some(since) if since > max_poll_age => {
+                problems.push(format!("polling: last successful poll {}s ago", since.as_secs()));
+            }
+            Some(_) => {}
+        }
+    }
+
+    if problems.is_empty() {
+        (StatusCode::OK, "ok\n".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n") + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{Metrics, router};
+    use crate::testing::test_db;
+
+    #[test]
+    fn counters_and_histograms_are_rendered() {
+        let metrics = Metrics::default();
+        metrics.command("/help");
+        metrics.command("/help");
+        metrics.reaction();
+        metrics.db_latency("get_admin", Duration::from_millis(2));
+
+        let text = metrics.render();
+        assert!(text.contains("telos_commands_total{command=\"/help\"} 2\n"));
+        assert!(text.contains("telos_reactions_sent_total 1\n"));
+        assert!(text.contains("telos_db_duration_seconds_bucket{operation=\"get_admin\",le=\"0.001\"} 0\n"));
+        assert!(text.contains("telos_db_duration_seconds_bucket{operation=\"get_admin\",le=\"0.0025\"} 1\n"));
+        assert!(text.contains("telos_db_duration_seconds_count{operation=\"get_admin\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn health_requires_a_recent_poll() {
+        let metrics = Arc::new(Metrics::default());
+        let db = test_db().await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/healthz", listener.local_addr().unwrap());
+        let app = router(Arc::clone(&metrics), db, Some(Duration::from_secs(60)));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert!(response.text().await.unwrap().contains("not been polled"));
+
+        metrics.poll();
+        assert_eq!(reqwest::get(&url).await.unwrap().status(), 200);
+    }
+}
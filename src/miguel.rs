@@ -1,31 +1,45 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
 use teloxide::{prelude::*, types::ReactionType};
 
 use regex::Regex;
 
-const MIGUEL: &str = "
-PAROU, PAROU A DISCUSSÃO
-
-miguwu ><
-";
+use crate::{config::Config, identity, metrics::Metrics, module::{Command, Module, Permission}, queue::Queue};
 
-const MIGUEL_USER: &str = "migeyel";
+const COMMANDS: &[Command] = &[
+    Command { group: "🗿 Miguel", usage: "/miguel", description: "Stop the discussion.", permission: Permission::Anyone },
+];
 
 #[derive(Clone)]
 pub struct MiguelHandler {
-    miguel_re: Regex
+    config: Arc<Config>,
+    miguel_re: Regex,
+    metrics: Arc<Metrics>,
+    queue: Arc<Queue>,
 }
 
 impl MiguelHandler {
-    pub fn new() -> Option<Self> {
-        let miguel_re: Regex = match Regex::new(r"/[A-z0-9À-ÿ]*?miguel[A-z0-9À-ÿ]*") {
+    pub fn new(config: Arc<Config>) -> Option<Self> {
+        let miguel_re: Regex = match Regex::new(&config.miguel.pattern) {
             Ok(re) => re,
             Err(e) => {
-                log::error!("Error creating regex: {:?}", e);
+                tracing::error!("Error creating regex: {:?}", e);
                 return None;
             }
         };
 
-        Some(Self { miguel_re })
+        Some(Self { config, miguel_re, metrics: Arc::default(), queue: Arc::default() })
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_queue(mut self, queue: Arc<Queue>) -> Self {
+        self.queue = queue;
+        self
     }
 
     pub async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
@@ -43,35 +57,22 @@ impl MiguelHandler {
             return Ok(());
         }
 
-        log::info!("Received **miguel** command: {:?}", msg);
+        tracing::Span::current().record("handler", "miguel");
+        tracing::info!("Received **miguel** command in chat {}", msg.chat.id);
+        self.metrics.trigger("miguel_command");
 
-        match msg.thread_id {
-            Some(thread_id) => {
-                bot.send_message(msg.chat.id, MIGUEL)
-                    .message_thread_id(thread_id)
-                    .await?;
-            }
-            None => {
-                bot.send_message(msg.chat.id, MIGUEL).await?;
-            }
+        let mut reply = bot.send_message(msg.chat.id, &self.config.miguel.text);
+        if let Some(thread_id) = msg.thread_id {
+            reply = reply.message_thread_id(thread_id);
         }
+        self.queue.send(reply).await?;
 
         Ok(())
     }
 
     pub async fn handle_miguel_message(&self, bot: &Bot, msg: &Message, text: &str) -> ResponseResult<()> {
-        // Extract message sender
-        if msg.from.is_none() {
-            return Ok(());
-        }
-
-        let from = msg.from.as_ref().unwrap().clone();
-
-        // Check if message is from miguel
-        if 
-            from.username.is_none()
-            || from.username.unwrap() != MIGUEL_USER
-        {
+        // Check if message is from miguel, who may also post as a channel
+        if identity::username(msg) != Some(self.config.miguel.user.as_str()) {
             return Ok(());
         }
 
@@ -84,14 +85,36 @@ impl MiguelHandler {
 
 
         // Received a miguel, react with 🗿
-        log::info!("A wild **miguel** appeared: {:?}", msg);
-
-        bot.set_message_reaction(msg.chat.id, msg.id)
-            .reaction([ReactionType::Emoji { emoji: "🗿".to_string() }])
-            .await?;
+        tracing::Span::current().record("handler", "miguel");
+        tracing::info!("A wild **miguel** appeared in chat {}", msg.chat.id);
+        self.metrics.trigger("miguel_message");
+
+        let reaction = bot.set_message_reaction(msg.chat.id, msg.id)
+            .reaction([ReactionType::Emoji { emoji: self.config.miguel.reaction.clone() }]);
+        // Under pressure the reaction is dropped instead of holding up replies
+        if self.queue.react(reaction).await? {
+            self.metrics.reaction();
+        }
 
         Ok(())
     }
 }
 
+#[async_trait]
+impl Module for MiguelHandler {
+    fn name(&self) -> &'static str {
+        "miguel"
+    }
+
+    fn filter(&self, msg: &Message) -> bool {
+        msg.text().is_some()
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        COMMANDS
+    }
 
+    async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        MiguelHandler::handle(self, bot, msg).await
+    }
+}
@@ -0,0 +1,738 @@
+//! Feature modules and the registry that routes messages to them. Modules
+//! run in the order they were registered. The registry drops messages from
+//! ignored users, keeps optional modules out of chats that aren't
+//! whitelisted or that disabled them, and checks who may use each module.
+//! It also answers `/help` from the commands the modules declare, routes
+//! button presses to the module whose prefix the button carries, and routes
+//! the messages of users in a dialog to the module that started it.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use teloxide::{prelude::*, types::ParseMode};
+
+use crate::{
+    callback::{self, Answer},
+    db::DbError,
+    dialog::{self, Dialog},
+    identity::Identity,
+    metrics::Metrics,
+    queue::Queue,
+    storage::SharedStorage,
+};
+
+/// Who may use a module or command, from least to most privileged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Anyone,
+    Admin,
+    Superadmin,
+}
+
+/// A command as listed by `/help`
+pub struct Command {
+    /// Heading the command is listed under
+    pub group: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub permission: Permission,
+}
+
+#[async_trait]
+pub trait Module: Send + Sync {
+    /// Unique name, used to enable and disable the module per chat
+    fn name(&self) -> &'static str;
+
+    /// Required modules run in every chat, whitelisted or not, and can't be
+    /// disabled
+    fn required(&self) -> bool {
+        false
+    }
+
+    /// Who may use the module at all
+    fn permission(&self) -> Permission {
+        Permission::Anyone
+    }
+
+    /// Whether the module wants the message, checked before anything is
+    /// looked up
+    fn filter(&self, _msg: &Message) -> bool {
+        true
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        &[]
+    }
+
+    async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()>;
+
+    /// Prefixes of the buttons the module sends, whose presses are routed to it
+    fn buttons(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Handles a press of one of its buttons, telling how to answer it
+    async fn press(&self, _bot: &Bot, _q: &CallbackQuery) -> ResponseResult<Answer> {
+        Ok(Answer::expired())
+    }
+
+    /// Dialogs the module starts, whose answers are routed back to it
+    fn dialogs(&self) -> &'static [Dialog] {
+        &[]
+    }
+
+    /// Acts on the answers of a dialog, `msg` being the last one
+    async fn finish(&self, _bot: &Bot, _msg: &Message, _dialog: &str, _answers: &[String]) -> ResponseResult<()> {
+        Ok(())
+    }
+}
+
+const GENERAL: &str = "ℹ️ General";
+const MODULES: &str = "🧩 Modules";
+
+const COMMANDS: &[Command] = &[
+    Command { group: MODULES, usage: "/modules", description: "Show which modules run in this chat.", permission: Permission::Admin },
+    Command { group: MODULES, usage: "/enable_module <name>", description: "Run a module in this chat again.", permission: Permission::Admin },
+    Command { group: MODULES, usage: "/disable_module <name>", description: "Stop a module in this chat.", permission: Permission::Admin },
+    Command { group: GENERAL, usage: "/help", description: "Show this help message.", permission: Permission::Admin },
+    Command { group: GENERAL, usage: "/cancel", description: "Stop answering the questions of a command.", permission: Permission::Admin },
+];
+
+pub struct Registry {
+    db: SharedStorage,
+    modules: Vec<Arc<dyn Module>>,
+    metrics: Arc<Metrics>,
+    queue: Arc<Queue>,
+}
+
+impl Registry {
+    pub fn new(db: SharedStorage) -> Self {
+        Self { db, modules: Vec::new(), metrics: Arc::default(), queue: Arc::default() }
+    }
+
+    /// Adds a module after the ones already registered
+    pub fn register(mut self, module: impl Module + 'static) -> Self {
+        assert!(
+            self.module(module.name()).is_none(),
+            "module {} was registered twice",
+            module.name()
+        );
+        self.modules.push(Arc::new(module));
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_queue(mut self, queue: Arc<Queue>) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    fn module(&self, name: &str) -> Option<&Arc<dyn Module>> {
+        self.modules.iter().find(|module| module.name() == name)
+    }
+
+    pub async fn handle(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        // Ignored users and bots are dropped before anything else
+        if self.should_ignore(msg).await {
+            return Ok(());
+        }
+
+        let mut caller = self.caller(msg).await;
+        if self.continue_dialog(bot, msg, &mut caller).await? {
+            return Ok(());
+        }
+
+        if self.handle_command(bot, msg, &mut caller).await? {
+            return Ok(());
+        }
+
+        let whitelisted = match self.check_whitelist(msg).await {
+            Ok(whitelisted) => whitelisted,
+            Err(e) => {
+                tracing::error!("Error checking if message is whitelisted: {:?}", e);
+                false
+            }
+        };
+
+        for module in &self.modules {
+            if !module.filter(msg) || caller.stored < module.permission() {
+                continue;
+            }
+
+            if !module.required() {
+                if !whitelisted {
+                    tracing::trace!("Message is not whitelisted for {}", module.name());
+                    continue;
+                }
+
+                match self.db.is_module_disabled(msg.chat.id.0, module.name()).await {
+                    Ok(false) => {}
+                    Ok(true) => continue,
+                    Err(e) => {
+                        tracing::error!("Error checking if module {} is disabled: {:?}", module.name(), e);
+                        continue;
+                    }
+                }
+            }
+
+            if !caller.allows(bot, module.permission()).await {
+                continue;
+            }
+
+            module.handle(bot, msg).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Routes the press to the module that sent the button and answers the
+    /// query, even when the module failed
+    pub async fn handle_callback_query(&self, bot: &Bot, q: &CallbackQuery) -> ResponseResult<()> {
+        let prefix = callback::prefix(q.data.as_deref().unwrap_or_default());
+        let module = self.modules.iter().find(|module| module.buttons().contains(&prefix));
+
+        let (answer, result) = match module {
+            Some(module) => match module.press(bot, q).await {
+                Ok(answer) => (answer, Ok(())),
+                Err(e) => (Answer::text("Something went wrong, try again"), Err(e)),
+            },
+            None => {
+                tracing::debug!("No module handles buttons prefixed {:?}", prefix);
+                (Answer::expired(), Ok(()))
+            }
+        };
+
+        let mut request = bot.answer_callback_query(q.id.clone());
+        if let Some(text) = answer.text {
+            request = request.text(text).show_alert(answer.alert);
+        }
+        self.queue.send(request).await?;
+
+        result
+    }
+
+    /// Takes the message as the next answer of the dialog its sender is in,
+    /// telling whether it did
+    async fn continue_dialog(&self, bot: &Bot, msg: &Message, caller: &mut Caller) -> ResponseResult<bool> {
+        let Some(identity) = Identity::of(msg) else {
+            return Ok(false);
+        };
+
+        let (chat_id, user_id) = (msg.chat.id.0, identity.id());
+        let mut open = match self.db.get_dialog(chat_id, user_id).await {
+            Ok(Some(open)) => open,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                tracing::error!("Error looking up dialog: {:?}", e);
+                return Ok(false);
+            }
+        };
+
+        let text = msg.text().unwrap_or_default().trim();
+        let expired = open.expires_at <= Utc::now();
+        // The module may have been removed, or the user lost the right to use it
+        let owner = self
+            .modules
+            .iter()
+            .find_map(|module| Some((module, module.dialogs().iter().find(|dialog| dialog.name == open.dialog)?)));
+        let owner = match owner {
+            Some((module, _)) if !caller.allows(bot, module.permission()).await => None,
+            owner => owner,
+        };
+
+        let Some((module, dialog)) = owner.filter(|_| !expired && text != dialog::CANCEL) else {
+            if let Err(e) = self.db.remove_dialog(chat_id, user_id).await {
+                tracing::error!("Error ending dialog {}: {:?}", open.dialog, e);
+            }
+
+            return match (text == dialog::CANCEL, expired) {
+                (true, _) => self.reply(bot, msg, "Cancelled, nothing was changed.").await.map(|()| true),
+                // Commands are run as usual, anything else was meant as an answer
+                (false, true) if !text.starts_with('/') => {
+                    self.reply(bot, msg, "Too late, the question timed out and nothing was changed.").await.map(|()| true)
+                }
+                _ => Ok(false),
+            };
+        };
+
+        tracing::Span::current().record("handler", module.name());
+        let Some(step) = dialog.next(&open.answers) else {
+            return Ok(false);
+        };
+        let problem = match text {
+            "" => Some("Answer with text."),
+            text => (step.check)(text).err(),
+        };
+        if let Some(problem) = problem {
+            self.reply(bot, msg, format!("{problem}\n\n{}", step.prompt)).await?;
+            return Ok(true);
+        }
+
+        open.answers.push(text.to_string());
+        match dialog.next(&open.answers) {
+            Some(step) => {
+                open.expires_at = dialog::expires_at(dialog);
+                match self.db.save_dialog(&open).await {
+                    Ok(()) => self.reply(bot, msg, step.prompt).await?,
+                    Err(e) => {
+                        tracing::error!("Error saving answer to dialog {}: {:?}", dialog.name, e);
+                        self.reply(bot, msg, "Error saving your answer!").await?;
+                    }
+                }
+            }
+            None => match self.db.remove_dialog(chat_id, user_id).await {
+                Ok(()) => module.finish(bot, msg, dialog.name, &open.answers).await?,
+                // Already finished by another message
+                Err(DbError::NotFound) => {}
+                Err(e) => {
+                    tracing::error!("Error ending dialog {}: {:?}", dialog.name, e);
+                    self.reply(bot, msg, "Error saving your answer!").await?;
+                }
+            },
+        }
+
+        Ok(true)
+    }
+
+    /// Whether a message should be dropped before reaching any module, either
+    /// because its sender is ignored or because it is an unexempted bot
+    pub async fn should_ignore(&self, msg: &Message) -> bool {
+        let Some(identity) = Identity::of(msg) else {
+            return false;
+        };
+
+        let user_id = identity.id();
+
+        // Chats sending as themselves come through placeholder bots, which
+        // aren't what exemptions are about
+        if matches!(identity, Identity::User(_)) && msg.from.as_ref().is_some_and(|from| from.is_bot) {
+            match self.db.is_bot_exempted(user_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::trace!("Ignoring message from bot {}", user_id);
+                    return true;
+                }
+                Err(e) => {
+                    tracing::error!("Error checking if bot is exempted: {:?}", e);
+                    return true;
+                }
+            }
+        }
+
+        match self.db.is_user_ignored(user_id, msg.chat.id.0).await {
+            Ok(ignored) => {
+                if ignored {
+                    tracing::trace!("Ignoring message from user {}", user_id);
+                }
+                ignored
+            }
+            Err(e) => {
+                tracing::error!("Error checking if user is ignored: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Whether optional modules may act on the message: private chats always,
+    /// groups once whitelisted, and threads of a group once whitelisted too
+    pub async fn check_whitelist(&self, msg: &Message) -> Result<bool, DbError> {
+        if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+            return Ok(true);
+        }
+
+        if !self.db.is_group_whitelisted(msg.chat.id.0).await? {
+            tracing::trace!("Group is not whitelisted");
+            return Ok(false);
+        }
+
+        if let Some(thread_id) = msg.thread_id
+            && !self.db.is_thread_whitelisted(thread_id.0.0, msg.chat.id.0).await?
+        {
+            tracing::trace!("Thread is not whitelisted");
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Who sent the message, with the permission stored for them
+    async fn caller(&self, msg: &Message) -> Caller {
+        let identity = Identity::of(msg);
+        let Some(id) = identity.map(|identity| identity.id()) else {
+            return Caller { identity, stored: Permission::Anyone, verified: None };
+        };
+
+        let stored = match self.db.get_admin(id).await {
+            Ok(Some(admin)) if admin.is_superadmin() => Permission::Superadmin,
+            Ok(Some(_)) => Permission::Admin,
+            Ok(None) => Permission::Anyone,
+            Err(e) => {
+                tracing::error!("Error checking if user is admin: {:?}", e);
+                Permission::Anyone
+            }
+        };
+
+        Caller { identity, stored, verified: None }
+    }
+
+    async fn reply(&self, bot: &Bot, msg: &Message, text: impl Into<String>) -> ResponseResult<()> {
+        let mut reply = bot.send_message(msg.chat.id, text);
+        if let Some(thread_id) = msg.thread_id {
+            reply = reply.message_thread_id(thread_id);
+        }
+        self.queue.send(reply).await?;
+        Ok(())
+    }
+
+    /// Answers the registry's own commands, telling whether it did
+    async fn handle_command(&self, bot: &Bot, msg: &Message, caller: &mut Caller) -> ResponseResult<bool> {
+        let text = msg.text().unwrap_or_default().to_lowercase();
+        let mut args = text.split_whitespace();
+        let cmd = args.next().unwrap_or_default();
+        if !COMMANDS.iter().any(|command| command.usage.split(' ').next() == Some(cmd)) {
+            return Ok(false);
+        }
+
+        let permission = caller.permission(bot).await;
+        if permission < Permission::Admin {
+            return Ok(false);
+        }
+
+        self.metrics.command(cmd);
+        tracing::Span::current().record("handler", "modules");
+
+        match (cmd, args.next()) {
+            ("/help", _) => {
+                let mut reply = bot.send_message(msg.chat.id, self.help(permission)).parse_mode(ParseMode::Html);
+                if let Some(thread_id) = msg.thread_id {
+                    reply = reply.message_thread_id(thread_id);
+                }
+                self.queue.send(reply).await?;
+            }
+            ("/modules", _) => self.list_modules(bot, msg).await?,
+            // Dialogs take their /cancel before commands are looked at
+            ("/cancel", _) => self.reply(bot, msg, "There is nothing to cancel").await?,
+            ("/enable_module" | "/disable_module", None) => {
+                self.reply(bot, msg, format!("Invalid command, use {cmd} <name>")).await?;
+            }
+            (cmd, Some(name)) => self.toggle_module(bot, msg, name, cmd == "/enable_module").await?,
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    async fn list_modules(&self, bot: &Bot, msg: &Message) -> ResponseResult<()> {
+        let mut lines = vec!["Modules in this chat:".to_string()];
+        for module in &self.modules {
+            let state = if module.required() {
+                "always on"
+            } else {
+                match self.db.is_module_disabled(msg.chat.id.0, module.name()).await {
+                    Ok(true) => "off",
+                    Ok(false) => "on",
+                    Err(e) => {
+                        tracing::error!("Error checking if module {} is disabled: {:?}", module.name(), e);
+                        self.reply(bot, msg, "Error listing modules!").await?;
+                        return Ok(());
+                    }
+                }
+            };
+            lines.push(format!("{}: {}", module.name(), state));
+        }
+
+        self.reply(bot, msg, lines.join("\n")).await
+    }
+
+    async fn toggle_module(&self, bot: &Bot, msg: &Message, name: &str, enable: bool) -> ResponseResult<()> {
+        let Some(module) = self.module(name) else {
+            let names: Vec<&str> = self.modules.iter().map(|module| module.name()).collect();
+            return self.reply(bot, msg, format!("No module named {name}, try one of: {}", names.join(", "))).await;
+        };
+        if module.required() {
+            return self.reply(bot, msg, format!("The {name} module is always on")).await;
+        }
+
+        let chat_id = msg.chat.id.0;
+        let from = Identity::of(msg).map(|identity| identity.id()).unwrap_or_default();
+        let result = match enable {
+            true => self.db.enable_module(chat_id, name).await,
+            false => self.db.disable_module(chat_id, name, from).await,
+        };
+
+        match (result, enable) {
+            (Ok(()), true) => self.reply(bot, msg, format!("Module {name} enabled in this chat!")).await,
+            (Ok(()), false) => self.reply(bot, msg, format!("Module {name} disabled in this chat!")).await,
+            (Err(DbError::NotFound), true) => self.reply(bot, msg, format!("Module {name} is already enabled here")).await,
+            (Err(DbError::AlreadyExists), false) => self.reply(bot, msg, format!("Module {name} is already disabled here")).await,
+            (Err(e), _) => {
+                tracing::error!("Error toggling module {}: {:?}", name, e);
+                self.reply(bot, msg, "Error changing the module!").await
+            }
+        }
+    }
+
+    /// The commands the caller may use, grouped under their headings
+    fn help(&self, permission: Permission) -> String {
+        let commands = self.modules.iter().flat_map(|module| module.commands()).chain(COMMANDS);
+
+        let mut groups: Vec<(&str, Vec<String>)> = Vec::new();
+        for command in commands.filter(|command| command.permission <= permission) {
+            let line = format!("{} — {}", escape(command.usage), command.description);
+            match groups.iter_mut().find(|(group, _)| *group == command.group) {
+                Some((_, lines)) => lines.push(line),
+                None => groups.push((command.group, vec![line])),
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|(group, lines)| format!("<b>{group}</b>\n{}", lines.join("\n")))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// The sender of a message and what they may do. Anonymous admins are only
+/// checked with Telegram once something they send needs their rights, so
+/// their other messages cost no call.
+struct Caller {
+    identity: Option<Identity>,
+    /// What the database says, before any check
+    stored: Permission,
+    verified: Option<bool>,
+}
+
+impl Caller {
+    /// The permission of the sender, checked with Telegram the first time
+    async fn permission(&mut self, bot: &Bot) -> Permission {
+        let Some(identity) = self.identity.filter(|_| self.stored > Permission::Anyone) else {
+            return Permission::Anyone;
+        };
+
+        let verified = match self.verified {
+            Some(verified) => verified,
+            None => {
+                let verified = match identity.verify(bot).await {
+                    Ok(verified) => verified,
+                    Err(e) => {
+                        tracing::error!("Error verifying anonymous admin: {:?}", e);
+                        false
+                    }
+                };
+                if !verified {
+                    tracing::trace!("Chat {} has no anonymous admins", identity.id());
+                }
+                *self.verified.insert(verified)
+            }
+        };
+
+        if verified { self.stored } else { Permission::Anyone }
+    }
+
+    /// Whether the sender may use what requires `needed`
+    async fn allows(&mut self, bot: &Bot, needed: Permission) -> bool {
+        needed == Permission::Anyone || (self.stored >= needed && self.permission(bot).await >= needed)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+    use teloxide::prelude::*;
+
+    use super::Registry;
+    use crate::{
+        admin::AdminHandler,
+        miguel::MiguelHandler,
+        db::OpenDialog,
+        metrics::Metrics,
+        storage::{AdminStore, DialogStore, WhitelistStore, cache::CachedStorage, memory::MemoryStorage, metered::MeteredStorage},
+        testing::{Calls, fake_api},
+    };
+
+    const GROUP: i64 = -100;
+
+    fn group(from: u64, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": GROUP, "type": "supergroup", "title": "group"},
+            "from": {"id": from, "is_bot": false, "first_name": "user"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    fn private(from: u64, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": from, "type": "private", "first_name": "user"},
+            "from": {"id": from, "is_bot": false, "first_name": "user"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    fn registry(storage: &Arc<MemoryStorage>) -> Registry {
+        Registry::new(storage.clone())
+            .register(AdminHandler::new(storage.clone(), Arc::default()).unwrap())
+            .register(MiguelHandler::new(Arc::default()).unwrap())
+    }
+
+    fn setup() -> (Arc<MemoryStorage>, Registry) {
+        let storage = Arc::new(MemoryStorage::with_superadmin(1, Some("root")));
+        let registry = registry(&storage);
+        (storage, registry)
+    }
+
+    fn replies(calls: &Calls) -> Vec<String> {
+        let calls = calls.lock().unwrap();
+        calls
+            .iter()
+            .filter(|(method, _)| method == "SendMessage")
+            .map(|(_, body)| body["text"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn modules_can_be_disabled_per_chat() {
+        let (bot, calls) = fake_api().await;
+        let (storage, registry) = setup();
+
+        // Optional modules stay out of chats that aren't whitelisted
+        registry.handle(&bot, &group(2, "/miguel")).await.unwrap();
+        assert!(replies(&calls).is_empty());
+
+        storage.add_whitelisted_group(GROUP, 1, None).await.unwrap();
+        registry.handle(&bot, &group(2, "/miguel")).await.unwrap();
+        assert_eq!(replies(&calls).len(), 1);
+
+        registry.handle(&bot, &group(1, "/disable_module miguel")).await.unwrap();
+        registry.handle(&bot, &group(2, "/miguel")).await.unwrap();
+        registry.handle(&bot, &group(1, "/disable_module admin")).await.unwrap();
+        registry.handle(&bot, &group(1, "/modules")).await.unwrap();
+
+        let replies = replies(&calls);
+        assert_eq!(replies[1..], [
+            "Module miguel disabled in this chat!",
+            "The admin module is always on",
+            "Modules in this chat:\nadmin: always on\nmiguel: off",
+        ]);
+
+        // Only admins manage modules
+        registry.handle(&bot, &group(2, "/enable_module miguel")).await.unwrap();
+        assert!(storage.is_group_whitelisted(GROUP).await.unwrap());
+        assert_eq!(self::replies(&calls).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn help_lists_what_the_caller_may_use() {
+        let (bot, calls) = fake_api().await;
+        let (storage, registry) = setup();
+        storage.add_admin(2, Some(1), None).await.unwrap();
+
+        registry.handle(&bot, &group(2, "/help")).await.unwrap();
+        registry.handle(&bot, &group(1, "/help")).await.unwrap();
+        registry.handle(&bot, &group(3, "/help")).await.unwrap();
+
+        let replies = replies(&calls);
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].contains("/remove_admin &lt;user_id&gt; — Remove an admin."));
+        assert!(replies[0].contains("<b>🗿 Miguel</b>\n/miguel"));
+        for superadmin_only in ["/make_superadmin", "/export_config", "/import_config", "/backup"] {
+            assert!(!replies[0].contains(superadmin_only));
+            assert!(replies[1].contains(superadmin_only));
+        }
+    }
+
+    #[tokio::test]
+    async fn dialogs_ask_until_every_answer_is_in() {
+        let (bot, calls) = fake_api().await;
+        let (storage, registry) = setup();
+        storage.add_whitelisted_group(GROUP, 1, None).await.unwrap();
+        storage.add_whitelisted_thread(7, GROUP, 1, None, None).await.unwrap();
+
+        registry.handle(&bot, &private(1, "/unwhitelist_thread")).await.unwrap();
+        registry.handle(&bot, &private(1, "the big one")).await.unwrap();
+        registry.handle(&bot, &private(1, &GROUP.to_string())).await.unwrap();
+
+        // Answers are kept in storage, so a restart doesn't lose them
+        let registry = self::registry(&storage);
+        registry.handle(&bot, &private(1, "7")).await.unwrap();
+        registry.handle(&bot, &private(1, "7")).await.unwrap();
+
+        let replies = replies(&calls);
+        assert!(replies[0].starts_with("Which group is the thread in?"));
+        assert!(replies[0].ends_with("Send /cancel to stop."));
+        assert!(replies[1].starts_with("That isn't a number.\n\nWhich group"));
+        assert_eq!(replies[2], "Which thread? Send the thread id.");
+        assert_eq!(replies[3], "Thread unwhitelisted!");
+        assert_eq!(replies.len(), 4);
+        assert!(!storage.is_thread_whitelisted(7, GROUP).await.unwrap());
+        assert!(storage.get_dialog(1, 1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn dialogs_end_on_cancel_and_timeout() {
+        let (bot, calls) = fake_api().await;
+        let (storage, registry) = setup();
+
+        registry.handle(&bot, &private(1, "/unwhitelist_thread")).await.unwrap();
+        registry.handle(&bot, &private(1, "/cancel")).await.unwrap();
+        registry.handle(&bot, &private(1, "/cancel")).await.unwrap();
+
+        storage
+            .save_dialog(&OpenDialog {
+                chat_id: 1,
+                user_id: 1,
+                dialog: "unwhitelist_thread".to_string(),
+                answers: vec![GROUP.to_string()],
+                expires_at: Utc::now() - Duration::minutes(1),
+            })
+            .await
+            .unwrap();
+        registry.handle(&bot, &private(1, "7")).await.unwrap();
+        assert!(storage.get_dialog(1, 1).await.unwrap().is_none());
+
+        let replies = replies(&calls);
+        assert_eq!(replies[1..], [
+            "Cancelled, nothing was changed.",
+            "There is nothing to cancel",
+            "Too late, the question timed out and nothing was changed.",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn messages_outside_dialogs_stay_in_the_cache() {
+        let (bot, calls) = fake_api().await;
+        let metrics = Arc::new(Metrics::default());
+        let storage = Arc::new(MemoryStorage::with_superadmin(1, Some("root")));
+        let metered = Arc::new(MeteredStorage::new(storage.clone(), Arc::clone(&metrics)));
+        let registry = Registry::new(Arc::new(CachedStorage::new(metered)));
+
+        for from in [1, 2, 2, 3] {
+            registry.handle(&bot, &group(from, "just talking")).await.unwrap();
+            registry.handle(&bot, &private(from, "just talking")).await.unwrap();
+        }
+
+        // The set of open dialogs was loaded once, and no dialog was read
+        let text = metrics.render();
+        assert!(text.contains("telos_db_duration_seconds_count{operation=\"get_dialogs\"} 1\n"));
+        assert!(!text.contains("operation=\"get_dialog\""));
+        assert!(replies(&calls).is_empty());
+    }
+}
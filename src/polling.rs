@@ -0,0 +1,129 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use futures::Stream;
+use teloxide::{
+    RequestError,
+    prelude::*,
+    stop::{StopFlag, StopToken, mk_stop_token},
+    types::Update,
+    update_listeners::{StatefulListener, UpdateListener},
+};
+
+use crate::metrics::Metrics;
+
+/// How long Telegram holds a `getUpdates` call open when there is nothing new
+const POLL_TIMEOUT: u32 = 10;
+/// How long to wait before polling again after a failed poll
+const ERROR_BACKOFF: Duration = Duration::from_secs(5);
+
+struct Polling {
+    bot: Bot,
+    metrics: Arc<Metrics>,
+    offset: i32,
+    buffer: VecDeque<Update>,
+    token: StopToken,
+    flag: StopFlag,
+}
+
+/// Long polling that records every successful `getUpdates` call, so the
+/// health check can tell an idle bot from one that lost Telegram
+pub async fn polling(bot: Bot, metrics: Arc<Metrics>) -> impl UpdateListener<Err = RequestError> {
+    // Telegram refuses to serve updates while a webhook is set
+    if let Err(e) = bot.delete_webhook().await {
+        tracing::error!("Error deleting webhook: {:?}", e);
+    }
+
+    let (token, flag) = mk_stop_token();
+    let state = Polling { bot, metrics, offset: 0, buffer: VecDeque::new(), token, flag };
+
+    StatefulListener::new(state, updates, |state: &mut Polling| state.token.clone())
+}
+
+fn updates(state: &mut Polling) -> impl Stream<Item = Result<Update, RequestError>> + Send + '_ {
+    futures::stream::unfold(state, |state| async move {
+        loop {
+            if let Some(update) = state.buffer.pop_front() {
+                return Some((Ok(update), state));
+            }
+
+            if state.flag.is_stopped() {
+                // Confirm the updates already handed out, so they aren't
+                // delivered again after a restart
+                if state.offset != 0
+                    && let Err(e) = state.bot.get_updates().offset(state.offset).timeout(0).await
+                {
+                    tracing::error!("Error confirming updates: {:?}", e);
+                }
+                return None;
+            }
+
+            let request = state.bot.get_updates().offset(state.offset).timeout(POLL_TIMEOUT);
+            let result = tokio::select! {
+                result = request.send() => result,
+                _ = state.flag.clone() => continue,
+            };
+
+            match result {
+                Ok(updates) => {
+                    state.metrics.poll();
+                    if let Some(last) = updates.last() {
+                        state.offset = last.id.0 as i32 + 1;
+                    }
+                    state.buffer.extend(updates);
+                }
+                Err(e) => {
+                    state.metrics.api_error(&e);
+                    let backoff = match &e {
+                        RequestError::RetryAfter(seconds) => seconds.duration(),
+                        _ => ERROR_BACKOFF,
+                    };
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = state.flag.clone() => {}
+                    }
+                    return Some((Err(e), state));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::StreamExt;
+    use teloxide::update_listeners::{AsUpdateStream, UpdateListener};
+
+    use super::polling;
+    use crate::{metrics::Metrics, testing::fake_api};
+
+    #[tokio::test]
+    async fn empty_polls_count_as_successful() {
+        let (bot, calls) = fake_api().await;
+        let metrics = Arc::new(Metrics::default());
+
+        let mut listener = polling(bot, Arc::clone(&metrics)).await;
+        let stop = listener.stop_token();
+        let polling = tokio::spawn(async move {
+            let stream = listener.as_stream();
+            futures::pin_mut!(stream);
+            stream.next().await.is_none()
+        });
+
+        for _ in 0..100 {
+            if metrics.since_last_poll().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(metrics.since_last_poll().is_some());
+
+        stop.stop();
+        assert!(polling.await.unwrap());
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[0].0, "DeleteWebhook");
+        assert_eq!(calls[1].0, "GetUpdates");
+    }
+}
@@ -0,0 +1,460 @@
+//! The queue every message, edit, reaction and callback answer goes through.
+//! It keeps to Telegram's flood limits, per chat and overall, waits out
+//! `RetryAfter` before trying again and retries network failures with
+//! backoff, as long as a second copy can't reach the chat. Reactions are low priority: a second reaction to the same
+//! message is coalesced with the first, and reactions that would have to wait
+//! too long are dropped.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+use teloxide::{
+    RequestError,
+    payloads::{AnswerCallbackQuery, EditMessageText, SendDocument, SendMessage, SetMessageReaction},
+    requests::{HasPayload, JsonRequest, MultipartRequest, Output, Payload, Request},
+    types::{ChatId, MessageId, Recipient},
+};
+use tokio::time::Instant;
+
+use crate::{config::QueueSettings, metrics::Metrics, record::Recorder};
+
+const SECOND: Duration = Duration::from_secs(1);
+const MINUTE: Duration = Duration::from_secs(60);
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// A Bot API call that can go through the queue
+pub trait Outgoing: Request<Err = RequestError> {
+    /// The chat the call counts against, if any
+    fn chat(&self) -> Option<ChatId>;
+
+    /// The method and its parameters, as recorded
+    fn call(&self) -> (&'static str, Value);
+
+    /// Whether sending it twice does the same as sending it once, so it can
+    /// be resent when it may have reached Telegram
+    fn idempotent(&self) -> bool;
+}
+
+fn call<P: Payload + Serialize>(payload: &P) -> (&'static str, Value) {
+    (P::NAME, serde_json::to_value(payload).unwrap_or_default())
+}
+
+fn chat_id(recipient: &Recipient) -> Option<ChatId> {
+    match recipient {
+        Recipient::Id(id) => Some(*id),
+        Recipient::ChannelUsername(_) => None,
+    }
+}
+
+macro_rules! outgoing {
+    ($($request:ident<$payload:ident>: $idempotent:literal),* $(,)?) => {
+        $(
+            impl Outgoing for $request<$payload> {
+                fn chat(&self) -> Option<ChatId> {
+                    chat_id(&self.payload_ref().chat_id)
+                }
+
+                fn call(&self) -> (&'static str, Value) {
+                    call(self.payload_ref())
+                }
+
+                fn idempotent(&self) -> bool {
+                    $idempotent
+                }
+            }
+        )*
+    };
+}
+
+// Messages and documents sent twice show up twice
+outgoing!(
+    JsonRequest<SendMessage>: false,
+    JsonRequest<EditMessageText>: true,
+    JsonRequest<SetMessageReaction>: true,
+    MultipartRequest<SendDocument>: false,
+);
+
+impl Outgoing for JsonRequest<AnswerCallbackQuery> {
+    fn chat(&self) -> Option<ChatId> {
+        None
+    }
+
+    fn call(&self) -> (&'static str, Value) {
+        call(self.payload_ref())
+    }
+
+    fn idempotent(&self) -> bool {
+        true
+    }
+}
+
+struct Limits {
+    global_per_sec: usize,
+    chat_per_sec: usize,
+    group_per_min: usize,
+}
+
+#[derive(Default)]
+struct State {
+    /// When calls were sent in the last second
+    global: VecDeque<Instant>,
+    /// When calls to each chat were sent in the last minute
+    chats: HashMap<ChatId, VecDeque<Instant>>,
+    /// Set by a `RetryAfter` that isn't about one chat
+    frozen: Option<Instant>,
+    frozen_chats: HashMap<ChatId, Instant>,
+    /// Messages with a reaction on the way
+    reacting: HashSet<(ChatId, MessageId)>,
+}
+
+/// Earliest time another call fits in `sent`, with at most `limit` calls
+/// per `window`
+fn next_slot(sent: &VecDeque<Instant>, limit: usize, window: Duration) -> Option<Instant> {
+    (sent.len() >= limit).then(|| sent[sent.len() - limit] + window)
+}
+
+impl State {
+    /// Takes a slot for a call to `chat` at `now`, or tells when to ask again
+    fn reserve(&mut self, limits: Option<&Limits>, chat: Option<ChatId>, now: Instant) -> Result<(), Instant> {
+        let mut earliest = now;
+        earliest = earliest.max(self.frozen.unwrap_or(now));
+        if let Some(chat) = chat {
+            earliest = earliest.max(self.frozen_chats.get(&chat).copied().unwrap_or(now));
+        }
+
+        if let Some(limits) = limits {
+            while self.global.front().is_some_and(|sent| now.duration_since(*sent) >= SECOND) {
+                self.global.pop_front();
+            }
+            earliest = earliest.max(next_slot(&self.global, limits.global_per_sec, SECOND).unwrap_or(now));
+
+            if let Some(chat) = chat
+                && let Some(sent) = self.chats.get_mut(&chat)
+            {
+                while sent.front().is_some_and(|sent| now.duration_since(*sent) >= MINUTE) {
+                    sent.pop_front();
+                }
+                earliest = earliest.max(next_slot(sent, limits.chat_per_sec, SECOND).unwrap_or(now));
+                if !chat.is_user() {
+                    earliest = earliest.max(next_slot(sent, limits.group_per_min, MINUTE).unwrap_or(now));
+                }
+            }
+        }
+
+        if earliest > now {
+            return Err(earliest);
+        }
+
+        if limits.is_some() {
+            self.global.push_back(now);
+            if let Some(chat) = chat {
+                self.chats.entry(chat).or_default().push_back(now);
+            }
+        }
+        self.chats.retain(|_, sent| sent.back().is_some_and(|last| now.duration_since(*last) < MINUTE));
+        self.frozen_chats.retain(|_, until| *until > now);
+
+        Ok(())
+    }
+}
+
+pub struct Queue {
+    /// None sends as fast as calls come, only honouring `RetryAfter`
+    limits: Option<Limits>,
+    max_retries: u32,
+    reaction_wait: Duration,
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+    recorder: Option<Arc<Recorder>>,
+}
+
+/// Without flood limits, for tests
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            limits: None,
+            max_retries: 3,
+            reaction_wait: Duration::from_secs(2),
+            state: Mutex::default(),
+            metrics: Arc::default(),
+            recorder: None,
+        }
+    }
+}
+
+impl Queue {
+    pub fn new(settings: &QueueSettings) -> Self {
+        Self {
+            limits: Some(Limits {
+                global_per_sec: settings.global_per_sec,
+                chat_per_sec: settings.chat_per_sec,
+                group_per_min: settings.group_per_min,
+            }),
+            max_retries: settings.max_retries,
+            reaction_wait: Duration::from_secs(settings.reaction_wait_secs),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Records every call sent
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Sends `request` once the limits allow it, retrying after `RetryAfter`
+    /// and network errors. Messages and documents are only resent when they
+    /// couldn't have reached Telegram.
+    pub async fn send<R: Outgoing>(&self, request: R) -> Result<Output<R>, RequestError> {
+        let output = self.deliver(&request, None).await?;
+        Ok(output.expect("only calls with a deadline are dropped"))
+    }
+
+    /// Sends a reaction unless one to the same message is already on the
+    /// way or the chat is too busy, telling whether it was sent
+    pub async fn react(&self, request: JsonRequest<SetMessageReaction>) -> Result<bool, RequestError> {
+        let key = request.chat().map(|chat| (chat, request.payload_ref().message_id));
+        if let Some(key) = key
+            && !self.state.lock().unwrap().reacting.insert(key)
+        {
+            self.metrics.outgoing("coalesced");
+            return Ok(false);
+        }
+
+        let result = self.deliver(&request, Some(self.reaction_wait)).await;
+        if let Some(key) = key {
+            self.state.lock().unwrap().reacting.remove(&key);
+        }
+
+        match result? {
+            Some(_) => Ok(true),
+            None => {
+                self.metrics.outgoing("dropped");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Sends `request`, giving up with None if it can't go within `patience`
+    async fn deliver<R: Outgoing>(&self, request: &R, patience: Option<Duration>) -> Result<Option<Output<R>>, RequestError> {
+        let chat = request.chat();
+        let deadline = patience.map(|patience| Instant::now() + patience);
+        let mut backoff = FIRST_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            loop {
+                let reserved = self.state.lock().unwrap().reserve(self.limits.as_ref(), chat, Instant::now());
+                match reserved {
+                    Ok(()) => break,
+                    Err(at) if deadline.is_some_and(|deadline| at > deadline) => return Ok(None),
+                    Err(at) => tokio::time::sleep_until(at).await,
+                }
+            }
+
+            let error = match request.send_ref().await {
+                Ok(output) => {
+                    if let Some(recorder) = &self.recorder {
+                        let (method, body) = request.call();
+                        recorder.call(method, body);
+                    }
+                    return Ok(Some(output));
+                }
+                Err(e) => e,
+            };
+            if retries >= self.max_retries {
+                return Err(error);
+            }
+
+            match &error {
+                RequestError::RetryAfter(after) => {
+                    let until = Instant::now() + after.duration();
+                    let mut state = self.state.lock().unwrap();
+                    match chat {
+                        Some(chat) => _ = state.frozen_chats.insert(chat, until),
+                        None => state.frozen = Some(until),
+                    }
+                    drop(state);
+                    tracing::warn!("Flood limit hit, waiting {:?} before retrying", after.duration());
+
+                    if deadline.is_some_and(|deadline| until > deadline) {
+                        return Ok(None);
+                    }
+                }
+                // Telegram may have acted on the call even though the answer
+                // never came back
+                RequestError::Network(e) if !e.is_connect() && !request.idempotent() => return Err(error),
+                RequestError::Network(_) | RequestError::Io(_) => {
+                    tracing::warn!("Bot API call failed, retrying in {:?}: {}", backoff, error);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                _ => return Err(error),
+            }
+
+            self.metrics.outgoing("retried");
+            retries += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use axum::{Json, Router, extract::State as AxumState, http::Uri};
+    use serde_json::{Value, json};
+    use teloxide::{
+        prelude::*,
+        types::{MessageId, ReactionType},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::{Limits, Queue, State};
+    use crate::metrics::Metrics;
+
+    /// Refuses the first call with `RetryAfter` and answers the rest after a
+    /// short delay
+    async fn flooded_api(calls: Arc<AtomicUsize>) -> Bot {
+        async fn answer(AxumState(calls): AxumState<Arc<AtomicUsize>>, uri: Uri) -> Json<Value> {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Json(json!({
+                    "ok": false,
+                    "error_code": 429,
+                    "description": "Too Many Requests: retry after 1",
+                    "parameters": { "retry_after": 1 },
+                }));
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let result = match uri.path().ends_with("SendMessage") {
+                true => json!({ "message_id": 1, "date": 0, "chat": { "id": 1, "type": "private", "first_name": "user" } }),
+                false => json!(true),
+            };
+            Json(json!({ "ok": true, "result": result }))
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = Router::new().fallback(answer).with_state(calls);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Bot::new("TOKEN").set_api_url(url.parse().unwrap())
+    }
+
+    /// Reads each call and hangs up without answering the first one, as if the
+    /// answer got lost on the way back
+    async fn lossy_api(calls: Arc<AtomicUsize>) -> Bot {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 64 * 1024];
+                let _ = stream.read(&mut request).await;
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    continue;
+                }
+
+                let body = json!({ "ok": true, "result": true }).to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len(),
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        Bot::new("TOKEN").set_api_url(url.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_calls_are_resent_after_a_lost_answer() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let bot = lossy_api(Arc::clone(&calls)).await;
+        let queue = Queue::default();
+
+        assert!(queue.send(bot.send_message(ChatId(1), "hi")).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        calls.store(0, Ordering::SeqCst);
+        let react = bot.set_message_reaction(ChatId(1), MessageId(7)).reaction([ReactionType::Emoji { emoji: "🗿".to_string() }]);
+        queue.send(react).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn messages_that_never_left_are_resent() {
+        // Nothing listens on a port that was just freed
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let bot = Bot::new("TOKEN").set_api_url(url.parse().unwrap());
+
+        let metrics = Arc::new(Metrics::default());
+        let queue = Queue { max_retries: 1, ..Queue::default() }.with_metrics(Arc::clone(&metrics));
+        assert!(queue.send(bot.send_message(ChatId(1), "hi")).await.is_err());
+        assert!(metrics.render().contains("telos_outgoing_total{outcome=\"retried\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_waited_out_and_duplicate_reactions_coalesced() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let bot = flooded_api(Arc::clone(&calls)).await;
+        let queue = Queue::default();
+
+        let start = Instant::now();
+        queue.send(bot.send_message(ChatId(1), "hi")).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let react = || bot.set_message_reaction(ChatId(1), MessageId(7)).reaction([ReactionType::Emoji { emoji: "🗿".to_string() }]);
+        let (first, second) = tokio::join!(queue.react(react()), queue.react(react()));
+        assert_eq!((first.unwrap(), second.unwrap()), (true, false));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn calls_keep_to_the_chat_group_and_global_limits() {
+        let limits = Limits { global_per_sec: 3, chat_per_sec: 1, group_per_min: 2 };
+        let mut state = State::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let (user, group, other) = (Some(ChatId(1)), Some(ChatId(-100)), Some(ChatId(2)));
+
+        assert_eq!(state.reserve(Some(&limits), user, at(0)), Ok(()));
+        assert_eq!(state.reserve(Some(&limits), user, at(10)), Err(at(1000)));
+        assert_eq!(state.reserve(Some(&limits), group, at(20)), Ok(()));
+        assert_eq!(state.reserve(Some(&limits), other, at(30)), Ok(()));
+        // Three calls in the last second, whatever the chat
+        assert_eq!(state.reserve(Some(&limits), None, at(40)), Err(at(1000)));
+
+        assert_eq!(state.reserve(Some(&limits), user, at(1000)), Ok(()));
+        assert_eq!(state.reserve(Some(&limits), group, at(1500)), Ok(()));
+        // Two messages to the group this minute
+        assert_eq!(state.reserve(Some(&limits), group, at(3000)), Err(at(60_020)));
+
+        state.frozen_chats.insert(ChatId(2), at(9000));
+        assert_eq!(state.reserve(Some(&limits), other, at(5000)), Err(at(9000)));
+        assert_eq!(state.reserve(None, other, at(5000)), Err(at(9000)));
+        assert_eq!(state.reserve(None, user, at(5000)), Ok(()));
+    }
+}
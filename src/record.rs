@@ -0,0 +1,102 @@
+//! Recording of live traffic for `telos replay`. Every incoming update and
+//! every Bot API call sent for it is appended to a JSONL file, after the
+//! admins and whitelists the bot started with, so a misbehaviour seen in a
+//! group can be replayed locally.
+
+use std::{
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use teloxide::types::{Update, UpdateId};
+
+use crate::export::ConfigDocument;
+
+tokio::task_local! {
+    /// The update being handled by the current task
+    static UPDATE_ID: u32;
+}
+
+/// Runs `handling` as the handling of `update_id`, so the calls it makes are
+/// recorded as answers to that update
+pub async fn within<F: Future>(update_id: UpdateId, handling: F) -> F::Output {
+    UPDATE_ID.scope(update_id.0, handling).await
+}
+
+/// One line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// What the database held when recording started
+    Config { config: ConfigDocument },
+    Update { update: Value },
+    /// A call sent while handling `update_id`, or on its own like reports
+    Call { update_id: Option<u32>, method: String, body: Value },
+}
+
+/// Reads every entry of the recording at `path`
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", number + 1)))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Appends to the recording at `path`
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    /// Records the database the following updates are handled against
+    pub fn config(&self, config: ConfigDocument) {
+        self.write(Event::Config { config });
+    }
+
+    pub fn update(&self, update: &Update) {
+        match serde_json::to_value(update) {
+            Ok(update) => self.write(Event::Update { update }),
+            Err(e) => tracing::warn!("Error recording update {}: {}", update.id.0, e),
+        }
+    }
+
+    pub fn call(&self, method: &str, body: Value) {
+        let update_id = UPDATE_ID.try_with(|id| *id).ok();
+        self.write(Event::Call { update_id, method: method.to_string(), body });
+    }
+
+    fn write(&self, event: Event) {
+        let mut line = serde_json::to_string(&Entry { at: Utc::now(), event }).expect("entries serialize to JSON");
+        line.push('\n');
+
+        // A failing recording shouldn't stop the bot, nor be reported as an error
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::warn!("Error writing to the recording: {}", e);
+        }
+    }
+}
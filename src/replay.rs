@@ -0,0 +1,187 @@
+//! `telos replay <file>`: handles the updates of a recording again, against a
+//! scratch database seeded with the config it recorded and the fake Bot API,
+//! and compares the calls made for each update with the recorded ones.
+
+use std::{collections::HashMap, error::Error, fmt, path::Path, sync::Arc};
+
+use regex::Regex;
+use serde_json::Value;
+use teloxide::{dptree, types::Update};
+
+use crate::{
+    admin::AdminHandler,
+    config::Config,
+    db::DB,
+    fake_api::FakeApi,
+    handler::{self, Handler},
+    miguel::MiguelHandler,
+    module::Registry,
+    record::{self, Event},
+    storage::SnapshotStore,
+};
+
+/// A Bot API method and its parameters
+pub type Call = (String, Value);
+
+/// An update whose calls didn't match the recording
+pub struct Difference {
+    pub update_id: u32,
+    pub recorded: Vec<Call>,
+    pub replayed: Vec<Call>,
+}
+
+pub struct Replay {
+    pub updates: usize,
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for difference in &self.differences {
+            writeln!(f, "Update {}:", difference.update_id)?;
+            for (method, body) in &difference.recorded {
+                writeln!(f, "  - {method} {body}")?;
+            }
+            for (method, body) in &difference.replayed {
+                writeln!(f, "  + {method} {body}")?;
+            }
+        }
+        write!(f, "{} update(s) replayed, {} differ", self.updates, self.differences.len())
+    }
+}
+
+pub async fn run(config: Arc<Config>, path: &Path) -> Result<Replay, Box<dyn Error>> {
+    let db = Arc::new(DB::scratch(&config.database.path).await?);
+    let replay = replay(db.clone(), config, path).await;
+    db.discard().await?;
+    replay
+}
+
+async fn replay(db: Arc<DB>, config: Arc<Config>, path: &Path) -> Result<Replay, Box<dyn Error>> {
+    let entries = record::read(path)?;
+    let uuids = Regex::new(r"[0-9a-f]{8}-?[0-9a-f]{4}-?[0-9a-f]{4}-?[0-9a-f]{4}-?[0-9a-f]{12}").expect("the regex is valid");
+
+    let mut recorded: HashMap<u32, Vec<Call>> = HashMap::new();
+    for entry in &entries {
+        if let Event::Call { update_id: Some(update_id), method, body } = &entry.event {
+            recorded.entry(*update_id).or_default().push((method.clone(), normalize(&uuids, body.clone())));
+        }
+    }
+
+    let admin = AdminHandler::new(db.clone(), Arc::clone(&config)).ok_or("Error creating admin handler")?;
+    let miguel = MiguelHandler::new(Arc::clone(&config)).ok_or("Error creating miguel handler")?;
+    let registry = Registry::new(db.clone()).register(admin).register(miguel);
+    let schema = handler::schema(Arc::new(Handler::new(registry)));
+
+    let api = FakeApi::start().await?;
+    let bot = api.bot();
+
+    let mut replay = Replay { updates: 0, differences: Vec::new() };
+    for entry in entries {
+        match entry.event {
+            // Written again whenever the bot restarted
+            Event::Config { config } => db.replace_config(&config).await?,
+            Event::Update { update } => {
+                let update: Update = serde_json::from_str(&update.to_string())?;
+                let update_id = update.id.0;
+                // Updates no branch takes are recorded as such, with no calls
+                let _ = schema.dispatch(dptree::deps![bot.clone(), update]).await;
+                replay.updates += 1;
+
+                let replayed: Vec<Call> = api
+                    .take_calls()
+                    .into_iter()
+                    .map(|(method, body)| (method, normalize(&uuids, body)))
+                    .collect();
+                let recorded = recorded.remove(&update_id).unwrap_or_default();
+                if replayed != recorded {
+                    replay.differences.push(Difference { update_id, recorded, replayed });
+                }
+            }
+            Event::Call { .. } => {}
+        }
+    }
+
+    Ok(replay)
+}
+
+/// Masks the ids that are generated anew on every run, like the ones in
+/// Confirm buttons
+fn normalize(uuids: &Regex, value: Value) -> Value {
+    match value {
+        Value::String(text) => Value::String(uuids.replace_all(&text, "<id>").into_owned()),
+        Value::Array(values) => Value::Array(values.into_iter().map(|value| normalize(uuids, value)).collect()),
+        Value::Object(fields) => {
+            Value::Object(fields.into_iter().map(|(name, value)| (name, normalize(uuids, value))).collect())
+        }
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use teloxide::{prelude::*, types::UpdateId};
+
+    use super::replay;
+    use crate::{
+        config::{Config, MiguelSettings},
+        db::Admin,
+        export::{CONFIG_VERSION, ConfigDocument},
+        record::{self, Recorder},
+        testing::test_db,
+    };
+
+    fn update(id: u32, chat: i64, from: i64, text: &str) -> Update {
+        serde_json::from_str(
+            &json!({
+                "update_id": id,
+                "message": {
+                    "message_id": id,
+                    "date": 0,
+                    "chat": {"id": chat, "type": "private", "first_name": "user"},
+                    "from": {"id": from, "is_bot": false, "first_name": "user"},
+                    "text": text,
+                },
+            })
+            .to_string(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies_that_changed_are_reported() {
+        let path = std::env::temp_dir().join(format!("telos-replay-{}.jsonl", uuid::Uuid::now_v7()));
+        let recorder = Recorder::open(&path).unwrap();
+        let admin = Admin { user_id: 1, name: None, added_by: None, added_at: None };
+        recorder.config(ConfigDocument {
+            version: CONFIG_VERSION,
+            admins: vec![admin],
+            whitelisted_groups: vec![],
+            whitelisted_threads: vec![],
+            ignored_users: vec![],
+            exempted_bots: vec![],
+            disabled_modules: vec![],
+        });
+
+        recorder.update(&update(1, 2, 2, "/miguel"));
+        let reply = json!({"chat_id": 2, "text": MiguelSettings::default().text});
+        record::within(UpdateId(1), async { recorder.call("SendMessage", reply) }).await;
+        // Admin commands only work with the recorded admins
+        recorder.update(&update(2, 1, 1, "/list_admins"));
+        recorder.update(&update(3, 2, 2, "/miguel"));
+        record::within(UpdateId(3), async { recorder.call("SendMessage", json!({"chat_id": 2, "text": "Parem!"})) }).await;
+        drop(recorder);
+
+        let replay = replay(Arc::new(test_db().await), Arc::new(Config::default()), &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replay.updates, 3);
+        let differing: Vec<u32> = replay.differences.iter().map(|difference| difference.update_id).collect();
+        assert_eq!(differing, [2, 3]);
+        assert!(replay.differences[0].replayed[0].1["text"].as_str().unwrap().contains("1"));
+        assert_eq!(replay.differences[1].recorded[0].1["text"], "Parem!");
+    }
+}
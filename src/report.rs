@@ -0,0 +1,345 @@
+//! Posting errors and lifecycle events to an admin log chat. Every `error!`
+//! event is reported with the fields of the spans it happened in, like the
+//! chat and command of the update. Events logged with `report = true` are
+//! posted at any level, which is how startup, shutdown and migrations show
+//! up. The same error is reported once per `dedup_secs` and at most
+//! `max_per_minute` errors are posted.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use teloxide::{
+    prelude::*,
+    types::{MessageId, ThreadId},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+    span,
+};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+use crate::{config::ReportSettings, queue::Queue};
+
+/// Longest report posted, below Telegram's limit of 4096 characters
+const MAX_REPORT_CHARS: usize = 4000;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// The error and the errors that caused it, skipping causes whose text is
+/// already part of the message
+pub fn chain(error: &dyn Error) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_text = cause.to_string();
+        if !text.contains(&cause_text) {
+            text.push_str(": ");
+            text.push_str(&cause_text);
+        }
+        source = cause.source();
+    }
+    text
+}
+
+/// Builds the layer that collects reports and the outbox that posts them
+/// once there is a bot. None when no log chat is configured.
+pub fn layer(settings: &ReportSettings) -> Option<(ReportLayer, Outbox)> {
+    let chat = ChatId(settings.chat_id?);
+    let thread = settings.thread_id.map(|id| ThreadId(MessageId(id)));
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let limits = Limits::new(Duration::from_secs(settings.dedup_secs), settings.max_per_minute);
+    let layer = ReportLayer { limits: Arc::new(Mutex::new(limits)), sender: sender.clone() };
+    let outbox = Outbox { chat, thread, sender, receiver };
+
+    Some((layer, outbox))
+}
+
+enum Outgoing {
+    Report(String),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Reports waiting to be posted
+pub struct Outbox {
+    chat: ChatId,
+    thread: Option<ThreadId>,
+    sender: mpsc::UnboundedSender<Outgoing>,
+    receiver: mpsc::UnboundedReceiver<Outgoing>,
+}
+
+impl Outbox {
+    /// Starts posting reports through `queue`, beginning with those logged
+    /// before the bot existed
+    pub fn spawn(self, bot: Bot, queue: Arc<Queue>) -> Reporter {
+        let Self { chat, thread, sender, mut receiver } = self;
+
+        tokio::spawn(async move {
+            while let Some(outgoing) = receiver.recv().await {
+                match outgoing {
+                    Outgoing::Report(text) => {
+                        let mut request = bot.send_message(chat, text);
+                        if let Some(thread) = thread {
+                            request = request.message_thread_id(thread);
+                        }
+                        // Not an error, which would be reported again
+                        if let Err(e) = queue.send(request).await {
+                            tracing::warn!("Error posting report: {:?}", e);
+                        }
+                    }
+                    Outgoing::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Reporter { sender }
+    }
+}
+
+/// Handle on the task posting reports
+#[derive(Clone)]
+pub struct Reporter {
+    sender: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl Reporter {
+    /// Waits up to `deadline` for the reports logged so far to be posted
+    pub async fn flush(&self, deadline: Duration) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Outgoing::Flush(done)).is_ok() && tokio::time::timeout(deadline, flushed).await.is_err() {
+            tracing::warn!("Reports were still being posted after {:?}", deadline);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Seen {
+    last: Option<Instant>,
+    repeats: usize,
+}
+
+/// Deduplication and rate limiting of error reports
+struct Limits {
+    dedup: Duration,
+    max_per_minute: usize,
+    seen: HashMap<String, Seen>,
+    sent: VecDeque<Instant>,
+    dropped: usize,
+}
+
+impl Limits {
+    fn new(dedup: Duration, max_per_minute: usize) -> Self {
+        Self { dedup, max_per_minute, seen: HashMap::new(), sent: VecDeque::new(), dropped: 0 }
+    }
+
+    /// Whether the error identified by `key` is reported at `now`, with notes
+    /// on the reports held back since the last one
+    fn admit(&mut self, key: &str, now: Instant) -> Option<Vec<String>> {
+        if let Some(seen) = self.seen.get_mut(key)
+            && seen.last.is_some_and(|last| now.duration_since(last) < self.dedup)
+        {
+            seen.repeats += 1;
+            return None;
+        }
+
+        while self.sent.front().is_some_and(|sent| now.duration_since(*sent) >= RATE_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.max_per_minute {
+            self.dropped += 1;
+            return None;
+        }
+
+        let mut notes = Vec::new();
+        let seen = self.seen.entry(key.to_string()).or_default();
+        if seen.repeats > 0 {
+            notes.push(format!("Repeated {} more times since the last report", seen.repeats));
+        }
+        if self.dropped > 0 {
+            notes.push(format!("{} other reports were dropped by the rate limit", self.dropped));
+        }
+        *seen = Seen { last: Some(now), repeats: 0 };
+        self.dropped = 0;
+        self.sent.push_back(now);
+
+        let dedup = self.dedup;
+        self.seen.retain(|_, seen| seen.repeats > 0 || seen.last.is_some_and(|last| now.duration_since(last) < dedup));
+
+        Some(notes)
+    }
+}
+
+/// The fields of an event or span, formatted
+#[derive(Default)]
+struct Fields {
+    message: String,
+    report: bool,
+    values: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.values.push((name, value.to_string())),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        match field.name() {
+            "report" => self.report = value,
+            name => self.values.push((name, value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            name => self.values.push((name, format!("{value:?}"))),
+        }
+    }
+}
+
+/// Turns error events and events marked `report = true` into reports
+pub struct ReportLayer {
+    limits: Arc<Mutex<Limits>>,
+    sender: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl<S> Layer<S> for ReportLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(fields) = span.extensions_mut().get_mut::<Fields>()
+        {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        let error = *event.metadata().level() == Level::ERROR;
+        if !error && !fields.report {
+            return;
+        }
+
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    fields.values.extend(span_fields.values.iter().cloned());
+                }
+            }
+        }
+
+        let notes = if fields.report {
+            Vec::new()
+        } else {
+            let command = fields.values.iter().find(|(name, _)| *name == "command").map(|(_, value)| value.as_str());
+            let key = format!("{} {} {}", event.metadata().target(), fields.message, command.unwrap_or_default());
+            match self.limits.lock().unwrap().admit(&key, Instant::now()) {
+                Some(notes) => notes,
+                None => return,
+            }
+        };
+
+        let mut text = format!("{} {}", if error { "🔴" } else { "ℹ️" }, fields.message);
+        for (name, value) in &fields.values {
+            text.push_str(&format!("\n{name}: {value}"));
+        }
+        for note in notes {
+            text.push_str(&format!("\n({note})"));
+        }
+        if text.chars().count() > MAX_REPORT_CHARS {
+            text = text.chars().take(MAX_REPORT_CHARS).collect::<String>() + "…";
+        }
+
+        // Fails only when reports aren't being posted, like in subcommands
+        let _ = self.sender.send(Outgoing::Report(text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{Limits, layer};
+    use crate::{config::ReportSettings, testing::fake_api};
+
+    #[test]
+    fn repeated_errors_are_folded_and_rate_limited() {
+        let mut limits = Limits::new(Duration::from_secs(600), 2);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(limits.admit("db down", at(0)), Some(vec![]));
+        assert_eq!(limits.admit("db down", at(1)), None);
+        assert_eq!(limits.admit("db down", at(2)), None);
+        assert_eq!(limits.admit("timeout", at(3)), Some(vec![]));
+        // Two reports in the last minute already
+        assert_eq!(limits.admit("bad reply", at(4)), None);
+
+        assert_eq!(
+            limits.admit("db down", at(601)),
+            Some(vec![
+                "Repeated 2 more times since the last report".to_string(),
+                "1 other reports were dropped by the rate limit".to_string(),
+            ])
+        );
+        assert_eq!(limits.admit("bad reply", at(602)), Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn errors_and_events_reach_the_log_chat() {
+        let (bot, calls) = fake_api().await;
+        let settings = ReportSettings { chat_id: Some(-100), thread_id: Some(5), ..Default::default() };
+        let (report_layer, outbox) = layer(&settings).unwrap();
+        let subscriber = tracing_subscriber::registry().with(report_layer);
+
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            tracing::info!(report = true, "Bot started");
+            tracing::info!("Not reported");
+
+            let span = tracing::info_span!("update", chat_id = 7, command = "/whitelist");
+            let _entered = span.enter();
+            tracing::error!("Error whitelisting group: {}", "database is locked");
+            tracing::error!("Error whitelisting group: {}", "database is locked");
+        }
+
+        let reporter = outbox.spawn(bot, Arc::default());
+        reporter.flush(Duration::from_secs(5)).await;
+
+        let calls = calls.lock().unwrap();
+        let texts: Vec<&str> = calls.iter().map(|(_, body)| body["text"].as_str().unwrap()).collect();
+        assert_eq!(
+            texts,
+            ["ℹ️ Bot started", "🔴 Error whitelisting group: database is locked\nchat_id: 7\ncommand: /whitelist"]
+        );
+        assert!(calls.iter().all(|(method, body)| method == "SendMessage" && body["chat_id"] == -100 && body["message_thread_id"] == 5));
+    }
+}
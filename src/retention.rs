@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::{config::RetentionSettings, db::DbError, shutdown::Shutdown, storage::{SharedStorage, Storage}};
+
+/// Expired dialogs are kept this long, so a late answer is still told that it
+/// came too late instead of being taken as an ordinary message
+const EXPIRED_DIALOGS: chrono::Duration = chrono::Duration::days(1);
+
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub interval: Duration,
+    /// Admin requests older than this are deleted, answered or not
+    pub requests: Option<chrono::Duration>,
+    /// Ignore entries are deleted this long after they expire
+    pub expired_ignores: Option<chrono::Duration>,
+}
+
+impl RetentionPolicy {
+    /// Builds the policy from the `[retention]` settings. A retention of 0
+    /// days keeps that data forever.
+    pub fn from_config(settings: &RetentionSettings) -> Result<Self, String> {
+        if settings.interval_hours == 0 {
+            return Err("retention.interval_hours must be at least 1".to_string());
+        }
+
+        Ok(Self {
+            interval: Duration::from_secs(settings.interval_hours.saturating_mul(60 * 60)),
+            requests: days(settings.request_days),
+            expired_ignores: days(settings.ignore_days),
+        })
+    }
+}
+
+/// 0 days, and more days than fit in a duration, mean forever
+fn days(days: u64) -> Option<chrono::Duration> {
+    match days {
+        0 => None,
+        days => chrono::Duration::try_days(i64::try_from(days).ok()?),
+    }
+}
+
+/// Deletes everything older than the policy allows, returning how many
+/// requests, ignore entries and dialogs were removed
+pub async fn purge(db: &dyn Storage, policy: &RetentionPolicy) -> Result<(u64, u64, u64), DbError> {
+    let now = Utc::now();
+
+    let requests = match policy.requests {
+        Some(retention) => db.purge_requests(now - retention).await?,
+        None => 0,
+    };
+
+    let ignores = match policy.expired_ignores {
+        Some(retention) => db.purge_expired_ignores(now - retention).await?,
+        None => 0,
+    };
+
+    let dialogs = db.purge_expired_dialogs(now - EXPIRED_DIALOGS).await?;
+
+    Ok((requests, ignores, dialogs))
+}
+
+/// Purges old data every `policy.interval`, starting right away
+pub async fn run_schedule(db: SharedStorage, policy: RetentionPolicy, shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(policy.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown.triggered() => return,
+        }
+
+        match purge(&*db, &policy).await {
+            Ok((0, 0, 0)) => {}
+            Ok((requests, ignores, dialogs)) => tracing::info!(
+                "Purged {} old admin request(s), {} expired ignore entr(ies) and {} expired dialog(s)",
+                requests,
+                ignores,
+                dialogs
+            ),
+            Err(e) => tracing::error!("Error purging old data: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Stopping the bot cleanly on SIGTERM or SIGINT: the update listener stops,
+//! updates already received are handled, background tasks finish what they
+//! are doing and the database pool is closed.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use teloxide::dispatching::ShutdownToken;
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+
+type Tasks = Vec<(&'static str, JoinHandle<()>)>;
+
+/// Tells background tasks to stop, and keeps track of them so they can be
+/// waited for
+#[derive(Clone)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            triggered: Arc::new(watch::channel(false).0),
+            tasks: Arc::default(),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    /// Resolves once shutdown was triggered
+    pub async fn triggered(&self) {
+        let mut triggered = self.triggered.subscribe();
+        // The sender lives as long as `self`, so this can't fail
+        let _ = triggered.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Spawns a background task that is waited for by `drain`. Tasks should
+    /// return at their next safe point once `triggered` resolves.
+    pub fn spawn(&self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|(_, task)| !task.is_finished());
+        tasks.push((name, tokio::spawn(task)));
+    }
+
+    /// Triggers shutdown and waits up to `deadline` for the background
+    /// tasks, aborting those that are still running
+    pub async fn drain(&self, deadline: Duration) {
+        self.trigger();
+
+        let deadline = Instant::now() + deadline;
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for (name, mut task) in tasks {
+            match tokio::time::timeout_at(deadline, &mut task).await {
+                Ok(Ok(())) => tracing::debug!("Background task {name} stopped"),
+                Ok(Err(e)) => tracing::error!("Background task {name} failed: {:?}", e),
+                Err(_) => {
+                    tracing::warn!("Background task {name} didn't stop in time, aborting it");
+                    task.abort();
+                }
+            }
+        }
+    }
+}
+
+/// Resolves on the first SIGTERM or SIGINT, with the name of the signal
+pub async fn signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok("SIGTERM"),
+            _ = interrupt.recv() => Ok("SIGINT"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("Ctrl-C")
+    }
+}
+
+/// Drives the dispatcher until it stops by itself or shutdown is triggered.
+/// The listener is then stopped and the updates already received get up to
+/// `deadline` to be handled.
+pub async fn dispatch(dispatching: impl Future<Output = ()>, token: ShutdownToken, shutdown: &Shutdown, deadline: Duration) {
+    tokio::pin!(dispatching);
+
+    tokio::select! {
+        () = &mut dispatching => return,
+        () = shutdown.triggered() => {}
+    }
+
+    if token.shutdown().is_err() {
+        // Not dispatching yet, so nothing is in flight
+        return;
+    }
+
+    tracing::info!("Waiting up to {:?} for the updates being handled", deadline);
+    if tokio::time::timeout(deadline, dispatching).await.is_err() {
+        tracing::warn!("Updates were still being handled after {:?}, stopping anyway", deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn draining_waits_for_tasks_and_aborts_stuck_ones() {
+        let shutdown = Shutdown::default();
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let (stop, done) = (shutdown.clone(), Arc::clone(&finished));
+        shutdown.spawn("polite", async move {
+            stop.triggered().await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            done.store(true, Ordering::SeqCst);
+        });
+        shutdown.spawn("stuck", std::future::pending());
+
+        let start = std::time::Instant::now();
+        shutdown.drain(Duration::from_millis(200)).await;
+
+        assert!(finished.load(Ordering::SeqCst));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
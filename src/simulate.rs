@@ -0,0 +1,252 @@
+//! `telos simulate`: a terminal chat with the bot. Lines typed are sent as
+//! messages from a simulated user in a simulated chat, and the calls the bot
+//! makes and the changes to its database are printed after each one. The
+//! database is a scratch one where user 1 is the superadmin.
+
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+    sync::Arc,
+};
+
+use serde_json::{Value, json};
+use teloxide::{dispatching::UpdateHandler, dptree, types::Update};
+
+use crate::{
+    admin::AdminHandler,
+    config::Config,
+    db::DB,
+    export::ConfigDocument,
+    fake_api::FakeApi,
+    handler::{self, Handler, HandlerError},
+    miguel::MiguelHandler,
+    module::Registry,
+    storage::AdminStore,
+};
+
+const SUPERADMIN: i64 = 1;
+const GROUP: i64 = -1001;
+
+const HELP: &str = "Type a message to send it. Simulator commands:
+  :private            Talk to the bot in private
+  :group              Talk in a group
+  :thread <id>        Talk in a thread of a forum group
+  :as <user_id> [username]
+                      Send as another user, 1 is the superadmin
+  :press <n>          Press the n-th button of the last keyboard
+  :help               Show this help
+  :quit               Leave";
+
+#[derive(Clone, Copy)]
+enum Chat {
+    Private,
+    Group,
+    Thread(i32),
+}
+
+struct Session {
+    db: Arc<DB>,
+    api: FakeApi,
+    schema: UpdateHandler<HandlerError>,
+    chat: Chat,
+    user_id: i64,
+    username: Option<String>,
+    /// Callback data of the buttons last sent
+    buttons: Vec<String>,
+    next_id: i32,
+}
+
+pub async fn run(config: Arc<Config>, input: impl BufRead, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let db = Arc::new(DB::scratch(&config.database.path).await?);
+    let simulated = simulate(db.clone(), config, input, out).await;
+    db.discard().await?;
+    simulated
+}
+
+async fn simulate(db: Arc<DB>, config: Arc<Config>, input: impl BufRead, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    db.add_admin(SUPERADMIN, None, Some("superadmin")).await?;
+
+    let admin = AdminHandler::new(db.clone(), Arc::clone(&config)).ok_or("Error creating admin handler")?;
+    let miguel = MiguelHandler::new(Arc::clone(&config)).ok_or("Error creating miguel handler")?;
+    let registry = Registry::new(db.clone()).register(admin).register(miguel);
+    let schema = handler::schema(Arc::new(Handler::new(registry)));
+
+    let api = FakeApi::start().await?;
+    let mut session = Session {
+        db,
+        api,
+        schema,
+        chat: Chat::Private,
+        user_id: SUPERADMIN,
+        username: None,
+        buttons: Vec::new(),
+        next_id: 1,
+    };
+
+    writeln!(out, "{HELP}")?;
+    session.prompt(out)?;
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => {}
+            [":quit"] => break,
+            [":help"] => writeln!(out, "{HELP}")?,
+            [":private"] => session.chat = Chat::Private,
+            [":group"] => session.chat = Chat::Group,
+            [":thread", id] => match id.parse() {
+                Ok(id) => session.chat = Chat::Thread(id),
+                Err(_) => writeln!(out, "Invalid thread id {id:?}")?,
+            },
+            [":as", id, rest @ ..] if rest.len() <= 1 => match id.parse() {
+                Ok(id) => {
+                    session.user_id = id;
+                    session.username = rest.first().map(|username| username.to_string());
+                }
+                Err(_) => writeln!(out, "Invalid user id {id:?}")?,
+            },
+            [":press", n] => match n.parse::<usize>().ok().and_then(|n| session.buttons.get(n.wrapping_sub(1))) {
+                Some(data) => {
+                    let query = session.callback_query(data.clone());
+                    session.send(json!({"callback_query": query}), out).await?;
+                }
+                None => writeln!(out, "No button {n}")?,
+            },
+            [command, ..] if command.starts_with(':') => writeln!(out, "Unknown command {command}, try :help")?,
+            _ => {
+                let message = session.message(line);
+                session.send(json!({"message": message}), out).await?;
+            }
+        }
+
+        session.prompt(out)?;
+    }
+
+    Ok(())
+}
+
+impl Session {
+    fn prompt(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let chat = match self.chat {
+            Chat::Private => "private".to_string(),
+            Chat::Group => "group".to_string(),
+            Chat::Thread(id) => format!("thread {id}"),
+        };
+        write!(out, "[{chat} as {}] > ", self.user_id)?;
+        out.flush()
+    }
+
+    fn user(&self) -> Value {
+        json!({
+            "id": self.user_id,
+            "is_bot": false,
+            "first_name": format!("User {}", self.user_id),
+            "username": self.username,
+        })
+    }
+
+    fn chat(&self) -> Value {
+        match self.chat {
+            Chat::Private => json!({"id": self.user_id, "type": "private", "first_name": format!("User {}", self.user_id)}),
+            Chat::Group => json!({"id": GROUP, "type": "supergroup", "title": "Simulated group"}),
+            Chat::Thread(_) => json!({"id": GROUP, "type": "supergroup", "title": "Simulated group", "is_forum": true}),
+        }
+    }
+
+    fn message(&mut self, text: &str) -> Value {
+        self.next_id += 1;
+        let mut message = json!({
+            "message_id": self.next_id,
+            "date": 0,
+            "chat": self.chat(),
+            "from": self.user(),
+            "text": text,
+        });
+        if let Chat::Thread(id) = self.chat {
+            message["message_thread_id"] = json!(id);
+            message["is_topic_message"] = json!(true);
+        }
+        message
+    }
+
+    fn callback_query(&mut self, data: String) -> Value {
+        self.next_id += 1;
+        json!({
+            "id": self.next_id.to_string(),
+            "from": self.user(),
+            "chat_instance": "simulated",
+            "data": data,
+            "message": {"message_id": 1, "date": 0, "chat": self.chat(), "text": "keyboard"},
+        })
+    }
+
+    /// Handles the update and prints what the bot did
+    async fn send(&mut self, mut update: Value, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        self.next_id += 1;
+        update["update_id"] = json!(self.next_id);
+        let update: Update = serde_json::from_str(&update.to_string())?;
+
+        let before = ConfigDocument::from_db(&*self.db).await?;
+        let _ = self.schema.dispatch(dptree::deps![self.api.bot(), update]).await;
+        let after = ConfigDocument::from_db(&*self.db).await?;
+
+        for (method, body) in self.api.take_calls() {
+            let text = body["text"].as_str().unwrap_or_default();
+            match method.as_str() {
+                "SendMessage" => {
+                    writeln!(out, "bot: {text}")?;
+                    self.show_buttons(&body, out)?;
+                }
+                "EditMessageText" => writeln!(out, "bot edits: {text}")?,
+                "SetMessageReaction" => writeln!(out, "bot reacts {}", body["reaction"][0]["emoji"].as_str().unwrap_or_default())?,
+                "AnswerCallbackQuery" if !text.is_empty() => writeln!(out, "bot answers: {text}")?,
+                "AnswerCallbackQuery" => {}
+                "SendDocument" => writeln!(out, "bot sends {}", body["caption"].as_str().unwrap_or("a document"))?,
+                method => writeln!(out, "bot calls {method} {body}")?,
+            }
+        }
+
+        for line in before.diff(&after) {
+            writeln!(out, "db: {line}")?;
+        }
+
+        Ok(())
+    }
+
+    fn show_buttons(&mut self, body: &Value, out: &mut dyn Write) -> std::io::Result<()> {
+        let Some(rows) = body["reply_markup"]["inline_keyboard"].as_array() else {
+            return Ok(());
+        };
+
+        self.buttons.clear();
+        for button in rows.iter().filter_map(Value::as_array).flatten() {
+            if let Some(data) = button["callback_data"].as_str() {
+                self.buttons.push(data.to_string());
+                writeln!(out, "  [{}] {}", self.buttons.len(), button["text"].as_str().unwrap_or_default())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::simulate;
+    use crate::{config::MiguelSettings, testing::test_db};
+
+    #[tokio::test]
+    async fn typed_lines_show_replies_and_changes() {
+        let script = "/miguel\n:group\n/whitelist_group\n:as 2 migeyel\nsalve miguel\n:as 1\n:quit\n/never_sent\n";
+        let mut out = Vec::new();
+        simulate(Arc::new(test_db().await), Arc::default(), script.as_bytes(), &mut out).await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("[private as 1] > bot: {}", MiguelSettings::default().text)));
+        assert!(out.contains("[group as 1] > bot: Group whitelisted!\ndb: + group -1001: Simulated group\n"));
+        assert!(out.contains("[group as 2] > bot reacts 🗿\n"));
+        assert!(!out.contains("never_sent"));
+    }
+}
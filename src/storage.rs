@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::db::{Admin, BecomeAdminRequest, DbError, DisabledModule, ExemptedBot, IgnoredUser, OpenDialog, WhitelistedGroup, WhitelistedThread};
+use crate::export::ConfigDocument;
+
+pub mod cache;
+pub mod metered;
+#[cfg(test)]
+pub mod memory;
+
+/// Everything the handlers need from persistent storage. `db::DB` is the
+/// SQLite implementation, `memory::MemoryStorage` keeps state in-process.
+pub trait Storage:
+    AdminStore + WhitelistStore + RequestStore + IgnoreStore + ModuleStore + DialogStore + RetentionStore + SnapshotStore + Send + Sync
+{
+}
+
+impl<
+    T: AdminStore + WhitelistStore + RequestStore + IgnoreStore + ModuleStore + DialogStore + RetentionStore + SnapshotStore + Send + Sync,
+> Storage for T
+{
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+#[async_trait]
+pub trait AdminStore {
+    /// Adds an admin under `added_by`, or a superadmin when it is `None`
+    async fn add_admin(&self, user_id: i64, added_by: Option<i64>, name: Option<&str>) -> Result<(), DbError>;
+
+    async fn remove_admin(&self, user_id: i64) -> Result<(), DbError>;
+
+    async fn make_superadmin(&self, user_id: i64) -> Result<(), DbError>;
+
+    async fn get_admin(&self, user_id: i64) -> Result<Option<Admin>, DbError>;
+
+    async fn get_admins(&self) -> Result<Vec<Admin>, DbError>;
+
+    /// Counts the admins that were added, directly or transitively, by this admin
+    async fn count_admin_subtree(&self, user_id: i64) -> Result<i64, DbError>;
+
+    /// Whether `ancestor_id` added the admin, directly or through others.
+    /// False when the admin doesn't exist.
+    async fn is_ancestor(&self, user_id: i64, ancestor_id: i64) -> Result<bool, DbError> {
+        let mut admin = self.get_admin(user_id).await?;
+
+        while let Some(adder) = admin.and_then(|a| a.added_by) {
+            if adder == ancestor_id {
+                return Ok(true);
+            }
+            admin = self.get_admin(adder).await?;
+        }
+
+        Ok(false)
+    }
+
+    /// Removes the admin only if `remover_id` is one of its ancestors,
+    /// failing with `PermissionDenied` otherwise
+    async fn remove_admin_with_traversal(&self, user_id: i64, remover_id: i64) -> Result<(), DbError> {
+        self.get_admin(user_id).await?.ok_or(DbError::NotFound)?;
+
+        if !self.is_ancestor(user_id, remover_id).await? {
+            return Err(DbError::PermissionDenied);
+        }
+        self.remove_admin(user_id).await
+    }
+}
+
+#[async_trait]
+pub trait WhitelistStore {
+    async fn add_whitelisted_group(&self, group_id: i64, added_by: i64, group_name: Option<&str>) -> Result<(), DbError>;
+
+    async fn add_whitelisted_thread(
+        &self,
+        thread_id: i32,
+        group_id: i64,
+        added_by: i64,
+        group_name: Option<&str>,
+        thread_name: Option<&str>,
+    ) -> Result<(), DbError>;
+
+    async fn remove_whitelisted_group(&self, group_id: i64) -> Result<(), DbError>;
+
+    async fn remove_whitelisted_thread(&self, thread_id: i32, group_id: i64) -> Result<(), DbError>;
+
+    async fn get_whitelisted_group(&self, group_id: i64) -> Result<Option<WhitelistedGroup>, DbError>;
+
+    async fn get_whitelisted_groups(&self) -> Result<Vec<WhitelistedGroup>, DbError>;
+
+    async fn get_whitelisted_threads(&self, group_id: i64) -> Result<Vec<WhitelistedThread>, DbError>;
+
+    async fn get_all_whitelisted_threads(&self) -> Result<Vec<WhitelistedThread>, DbError>;
+
+    async fn is_group_whitelisted(&self, group_id: i64) -> Result<bool, DbError>;
+
+    async fn is_thread_whitelisted(&self, thread_id: i32, group_id: i64) -> Result<bool, DbError>;
+}
+
+#[async_trait]
+pub trait RequestStore {
+    /// Creates a request unless the user already has a pending one, returning
+    /// its id
+    async fn create_become_admin_request(&self, user_id: i64, user_name: Option<&str>) -> Result<String, DbError>;
+
+    async fn get_become_admin_requests(&self) -> Result<Vec<BecomeAdminRequest>, DbError>;
+
+    /// Answers a pending request and makes its user an admin added by `admin_id`
+    async fn approve_become_admin_request(&self, request_id: &str, admin_id: i64) -> Result<(), DbError>;
+
+    async fn reject_become_admin_request(&self, request_id: &str) -> Result<(), DbError>;
+}
+
+#[async_trait]
+pub trait IgnoreStore {
+    /// Ignores a user everywhere or in one chat, replacing any previous entry
+    /// with the same scope
+    async fn ignore_user(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        added_by: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DbError>;
+
+    async fn unignore_user(&self, user_id: i64, chat_id: Option<i64>) -> Result<(), DbError>;
+
+    async fn is_user_ignored(&self, user_id: i64, chat_id: i64) -> Result<bool, DbError>;
+
+    async fn get_ignored_users(&self) -> Result<Vec<IgnoredUser>, DbError>;
+
+    async fn add_exempted_bot(&self, user_id: i64, added_by: i64, name: Option<&str>) -> Result<(), DbError>;
+
+    async fn remove_exempted_bot(&self, user_id: i64) -> Result<(), DbError>;
+
+    async fn is_bot_exempted(&self, user_id: i64) -> Result<bool, DbError>;
+
+    async fn get_exempted_bots(&self) -> Result<Vec<ExemptedBot>, DbError>;
+}
+
+#[async_trait]
+pub trait ModuleStore {
+    /// Turns a module off in one chat, failing with `AlreadyExists` if it
+    /// already is
+    async fn disable_module(&self, chat_id: i64, module: &str, added_by: i64) -> Result<(), DbError>;
+
+    async fn enable_module(&self, chat_id: i64, module: &str) -> Result<(), DbError>;
+
+    async fn is_module_disabled(&self, chat_id: i64, module: &str) -> Result<bool, DbError>;
+
+    async fn get_disabled_modules(&self) -> Result<Vec<DisabledModule>, DbError>;
+}
+
+#[async_trait]
+pub trait DialogStore {
+    /// Starts or advances the dialog of a user in a chat, replacing the one
+    /// they were in
+    async fn save_dialog(&self, dialog: &OpenDialog) -> Result<(), DbError>;
+
+    async fn get_dialog(&self, chat_id: i64, user_id: i64) -> Result<Option<OpenDialog>, DbError>;
+
+    async fn remove_dialog(&self, chat_id: i64, user_id: i64) -> Result<(), DbError>;
+
+    async fn get_dialogs(&self) -> Result<Vec<OpenDialog>, DbError>;
+}
+
+/// What `forget_user` removed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ForgetReport {
+    pub requests_deleted: u64,
+    pub admin_name_removed: bool,
+    /// Dialogs the user was in, with the answers given so far
+    pub dialogs_deleted: u64,
+    /// Ignore list entries about the user, kept so moderation still applies
+    pub ignore_entries_kept: u64,
+}
+
+#[async_trait]
+pub trait RetentionStore {
+    /// Deletes the user's admin requests and dialogs and removes their name
+    /// from the admin list. Admin rights and ignore entries are kept.
+    async fn forget_user(&self, user_id: i64) -> Result<ForgetReport, DbError>;
+
+    /// Deletes admin requests created before `before`, answered or not
+    async fn purge_requests(&self, before: DateTime<Utc>) -> Result<u64, DbError>;
+
+    /// Deletes ignore entries that expired before `before`
+    async fn purge_expired_ignores(&self, before: DateTime<Utc>) -> Result<u64, DbError>;
+
+    /// Deletes dialogs that expired before `before`
+    async fn purge_expired_dialogs(&self, before: DateTime<Utc>) -> Result<u64, DbError>;
+}
+
+#[async_trait]
+pub trait SnapshotStore {
+    /// Replaces admins, whitelists, the ignore list and exempted bots with the
+    /// contents of the document in a single transaction
+    async fn replace_config(&self, doc: &ConfigDocument) -> Result<(), DbError>;
+
+    /// Writes a consistent copy of the whole database to `path`, which must not exist yet
+    async fn vacuum_into(&self, path: &str) -> Result<(), DbError>;
+}
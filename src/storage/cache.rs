@@ -0,0 +1,490 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::db::{Admin, BecomeAdminRequest, DbError, DisabledModule, ExemptedBot, IgnoredUser, OpenDialog, WhitelistedGroup, WhitelistedThread};
+use crate::export::ConfigDocument;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use crate::storage::{
+    AdminStore, DialogStore, ForgetReport, IgnoreStore, ModuleStore, RequestStore, RetentionStore, SharedStorage, SnapshotStore, WhitelistStore,
+};
+
+/// Hit and miss counters for the lookups done on every incoming message
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// The admin set, the whitelists, the ignore list and the disabled
+/// modules, loaded in one go
+struct Snapshot {
+    admins: HashMap<i64, Admin>,
+    groups: HashSet<i64>,
+    threads: HashSet<(i64, i32)>,
+    ignored: Vec<IgnoredUser>,
+    exempted: HashSet<i64>,
+    disabled: HashSet<(i64, String)>,
+}
+
+/// A value loaded from the inner storage, dropped when what it was loaded
+/// from changes
+struct Slot<T> {
+    value: RwLock<Option<Arc<T>>>,
+    // Bumped on every invalidation, so a load that raced with a write is discarded
+    generation: AtomicU64,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self { value: RwLock::new(None), generation: AtomicU64::new(0) }
+    }
+}
+
+impl<T> Slot<T> {
+    fn get(&self) -> Option<Arc<T>> {
+        self.value.read().unwrap().clone()
+    }
+
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *self.value.write().unwrap() = None;
+    }
+
+    /// Stores what `load` returns, unless the slot was invalidated meanwhile
+    async fn reload<F: Future<Output = Result<T, DbError>>>(&self, load: F) -> Result<Arc<T>, DbError> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let value = Arc::new(load.await?);
+
+        let mut current = self.value.write().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            *current = Some(Arc::clone(&value));
+        }
+
+        Ok(value)
+    }
+}
+
+/// Wraps another storage, answering the per-message lookups (`get_admin`,
+/// whitelist, ignore, exemption, module and dialog checks) from memory. Writes
+/// invalidate what they change: the snapshot, or the set of users in a dialog.
+/// `spawn_refresh` reloads both periodically to pick up changes made by other
+/// instances sharing the database.
+pub struct CachedStorage {
+    inner: SharedStorage,
+    snapshot: Slot<Snapshot>,
+    /// Who is in a dialog, by chat and user. Answers are saved often, so they
+    /// leave the snapshot alone.
+    dialogs: Slot<HashSet<(i64, i64)>>,
+    stats: Arc<CacheStats>,
+    metrics: Arc<Metrics>,
+}
+
+impl CachedStorage {
+    pub fn new(inner: SharedStorage) -> Self {
+        Self {
+            inner,
+            snapshot: Slot::default(),
+            dialogs: Slot::default(),
+            stats: Arc::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn stats(&self) -> Arc<CacheStats> {
+        Arc::clone(&self.stats)
+    }
+
+    pub fn spawn_refresh(self: &Arc<Self>, interval: Duration, shutdown: &Shutdown) {
+        let cache = Arc::downgrade(self);
+        let stop = shutdown.clone();
+        shutdown.spawn("cache refresh", async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    () = stop.triggered() => return,
+                }
+                let Some(cache) = cache.upgrade() else {
+                    return;
+                };
+
+                if let Err(e) = cache.reload().await {
+                    tracing::error!("Error refreshing cache: {:?}", e);
+                }
+                if let Err(e) = cache.reload_dialogs().await {
+                    tracing::error!("Error refreshing cached dialogs: {:?}", e);
+                }
+            }
+        });
+    }
+
+    async fn reload(&self) -> Result<Arc<Snapshot>, DbError> {
+        self.snapshot
+            .reload(async {
+                Ok(Snapshot {
+                    admins: self.inner.get_admins().await?.into_iter().map(|a| (a.user_id, a)).collect(),
+                    groups: self.inner.get_whitelisted_groups().await?.into_iter().map(|g| g.group_id).collect(),
+                    threads: self
+                        .inner
+                        .get_all_whitelisted_threads()
+                        .await?
+                        .into_iter()
+                        .map(|t| (t.group_id, t.thread_id))
+                        .collect(),
+                    ignored: self.inner.get_ignored_users().await?,
+                    exempted: self.inner.get_exempted_bots().await?.into_iter().map(|b| b.user_id).collect(),
+                    disabled: self.inner.get_disabled_modules().await?.into_iter().map(|m| (m.chat_id, m.module)).collect(),
+                })
+            })
+            .await
+    }
+
+    async fn reload_dialogs(&self) -> Result<Arc<HashSet<(i64, i64)>>, DbError> {
+        self.dialogs
+            .reload(async { Ok(self.inner.get_dialogs().await?.into_iter().map(|d| (d.chat_id, d.user_id)).collect()) })
+            .await
+    }
+
+    fn lookup(&self, hit: bool) {
+        let (counter, outcome) = match hit {
+            true => (&self.stats.hits, "hit"),
+            false => (&self.stats.misses, "miss"),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.metrics.cache_lookup(outcome);
+    }
+
+    async fn snapshot(&self) -> Result<Arc<Snapshot>, DbError> {
+        let snapshot = self.snapshot.get();
+        self.lookup(snapshot.is_some());
+        match snapshot {
+            Some(snapshot) => Ok(snapshot),
+            None => self.reload().await,
+        }
+    }
+
+    async fn open_dialogs(&self) -> Result<Arc<HashSet<(i64, i64)>>, DbError> {
+        let dialogs = self.dialogs.get();
+        self.lookup(dialogs.is_some());
+        match dialogs {
+            Some(dialogs) => Ok(dialogs),
+            None => self.reload_dialogs().await,
+        }
+    }
+
+    /// Passes on the result of a write to the tables in the snapshot
+    fn invalidated<T>(&self, result: Result<T, DbError>) -> Result<T, DbError> {
+        self.snapshot.invalidate();
+        result
+    }
+}
+
+#[async_trait]
+impl AdminStore for CachedStorage {
+    async fn add_admin(&self, user_id: i64, added_by: Option<i64>, name: Option<&str>) -> Result<(), DbError> {
+        self.invalidated(self.inner.add_admin(user_id, added_by, name).await)
+    }
+
+    async fn remove_admin(&self, user_id: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.remove_admin(user_id).await)
+    }
+
+    async fn make_superadmin(&self, user_id: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.make_superadmin(user_id).await)
+    }
+
+    async fn get_admin(&self, user_id: i64) -> Result<Option<Admin>, DbError> {
+        Ok(self.snapshot().await?.admins.get(&user_id).cloned())
+    }
+
+    async fn get_admins(&self) -> Result<Vec<Admin>, DbError> {
+        self.inner.get_admins().await
+    }
+
+    async fn count_admin_subtree(&self, user_id: i64) -> Result<i64, DbError> {
+        self.inner.count_admin_subtree(user_id).await
+    }
+}
+
+#[async_trait]
+impl WhitelistStore for CachedStorage {
+    async fn add_whitelisted_group(&self, group_id: i64, added_by: i64, group_name: Option<&str>) -> Result<(), DbError> {
+        self.invalidated(self.inner.add_whitelisted_group(group_id, added_by, group_name).await)
+    }
+
+    async fn add_whitelisted_thread(
+        &self,
+        thread_id: i32,
+        group_id: i64,
+        added_by: i64,
+        group_name: Option<&str>,
+        thread_name: Option<&str>,
+    ) -> Result<(), DbError> {
+        let result = self
+            .inner
+            .add_whitelisted_thread(thread_id, group_id, added_by, group_name, thread_name)
+            .await;
+        self.invalidated(result)
+    }
+
+    async fn remove_whitelisted_group(&self, group_id: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.remove_whitelisted_group(group_id).await)
+    }
+
+    async fn remove_whitelisted_thread(&self, thread_id: i32, group_id: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.remove_whitelisted_thread(thread_id, group_id).await)
+    }
+
+    async fn get_whitelisted_group(&self, group_id: i64) -> Result<Option<WhitelistedGroup>, DbError> {
+        self.inner.get_whitelisted_group(group_id).await
+    }
+
+    async fn get_whitelisted_groups(&self) -> Result<Vec<WhitelistedGroup>, DbError> {
+        self.inner.get_whitelisted_groups().await
+    }
+
+    async fn get_whitelisted_threads(&self, group_id: i64) -> Result<Vec<WhitelistedThread>, DbError> {
+        self.inner.get_whitelisted_threads(group_id).await
+    }
+
+    async fn get_all_whitelisted_threads(&self) -> Result<Vec<WhitelistedThread>, DbError> {
+        self.inner.get_all_whitelisted_threads().await
+    }
+
+    async fn is_group_whitelisted(&self, group_id: i64) -> Result<bool, DbError> {
+        Ok(self.snapshot().await?.groups.contains(&group_id))
+    }
+
+    async fn is_thread_whitelisted(&self, thread_id: i32, group_id: i64) -> Result<bool, DbError> {
+        Ok(self.snapshot().await?.threads.contains(&(group_id, thread_id)))
+    }
+}
+
+#[async_trait]
+impl RequestStore for CachedStorage {
+    async fn create_become_admin_request(&self, user_id: i64, user_name: Option<&str>) -> Result<String, DbError> {
+        self.inner.create_become_admin_request(user_id, user_name).await
+    }
+
+    async fn get_become_admin_requests(&self) -> Result<Vec<BecomeAdminRequest>, DbError> {
+        self.inner.get_become_admin_requests().await
+    }
+
+    async fn approve_become_admin_request(&self, request_id: &str, admin_id: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.approve_become_admin_request(request_id, admin_id).await)
+    }
+
+    async fn reject_become_admin_request(&self, request_id: &str) -> Result<(), DbError> {
+        self.inner.reject_become_admin_request(request_id).await
+    }
+}
+
+#[async_trait]
+impl IgnoreStore for CachedStorage {
+    async fn ignore_user(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        added_by: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DbError> {
+        self.invalidated(self.inner.ignore_user(user_id, chat_id, added_by, expires_at).await)
+    }
+
+    async fn unignore_user(&self, user_id: i64, chat_id: Option<i64>) -> Result<(), DbError> {
+        self.invalidated(self.inner.unignore_user(user_id, chat_id).await)
+    }
+
+    async fn is_user_ignored(&self, user_id: i64, chat_id: i64) -> Result<bool, DbError> {
+        let now = Utc::now();
+        Ok(self.snapshot().await?.ignored.iter().any(|u| {
+            u.user_id == user_id
+                && u.chat_id.is_none_or(|id| id == chat_id)
+                && u.expires_at.is_none_or(|expires_at| expires_at > now)
+        }))
+    }
+
+    async fn get_ignored_users(&self) -> Result<Vec<IgnoredUser>, DbError> {
+        self.inner.get_ignored_users().await
+    }
+
+    async fn add_exempted_bot(&self, user_id: i64, added_by: i64, name: Option<&str>) -> Result<(), DbError> {
+        self.invalidated(self.inner.add_exempted_bot(user_id, added_by, name).await)
+    }
+
+    async fn remove_exempted_bot(&self, user_id: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.remove_exempted_bot(user_id).await)
+    }
+
+    async fn is_bot_exempted(&self, user_id: i64) -> Result<bool, DbError> {
+        Ok(self.snapshot().await?.exempted.contains(&user_id))
+    }
+
+    async fn get_exempted_bots(&self) -> Result<Vec<ExemptedBot>, DbError> {
+        self.inner.get_exempted_bots().await
+    }
+}
+
+#[async_trait]
+impl ModuleStore for CachedStorage {
+    async fn disable_module(&self, chat_id: i64, module: &str, added_by: i64) -> Result<(), DbError> {
+        self.invalidated(self.inner.disable_module(chat_id, module, added_by).await)
+    }
+
+    async fn enable_module(&self, chat_id: i64, module: &str) -> Result<(), DbError> {
+        self.invalidated(self.inner.enable_module(chat_id, module).await)
+    }
+
+    async fn is_module_disabled(&self, chat_id: i64, module: &str) -> Result<bool, DbError> {
+        Ok(self.snapshot().await?.disabled.contains(&(chat_id, module.to_string())))
+    }
+
+    async fn get_disabled_modules(&self) -> Result<Vec<DisabledModule>, DbError> {
+        self.inner.get_disabled_modules().await
+    }
+}
+
+#[async_trait]
+impl DialogStore for CachedStorage {
+    async fn save_dialog(&self, dialog: &OpenDialog) -> Result<(), DbError> {
+        let result = self.inner.save_dialog(dialog).await;
+        self.dialogs.invalidate();
+        result
+    }
+
+    /// Only users in a dialog reach the database
+    async fn get_dialog(&self, chat_id: i64, user_id: i64) -> Result<Option<OpenDialog>, DbError> {
+        if !self.open_dialogs().await?.contains(&(chat_id, user_id)) {
+            return Ok(None);
+        }
+        self.inner.get_dialog(chat_id, user_id).await
+    }
+
+    async fn remove_dialog(&self, chat_id: i64, user_id: i64) -> Result<(), DbError> {
+        let result = self.inner.remove_dialog(chat_id, user_id).await;
+        self.dialogs.invalidate();
+        result
+    }
+
+    async fn get_dialogs(&self) -> Result<Vec<OpenDialog>, DbError> {
+        self.inner.get_dialogs().await
+    }
+}
+
+#[async_trait]
+impl RetentionStore for CachedStorage {
+    async fn forget_user(&self, user_id: i64) -> Result<ForgetReport, DbError> {
+        let result = self.inner.forget_user(user_id).await;
+        self.dialogs.invalidate();
+        self.invalidated(result)
+    }
+
+    async fn purge_requests(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        self.inner.purge_requests(before).await
+    }
+
+    async fn purge_expired_ignores(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        self.invalidated(self.inner.purge_expired_ignores(before).await)
+    }
+
+    async fn purge_expired_dialogs(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let result = self.inner.purge_expired_dialogs(before).await;
+        self.dialogs.invalidate();
+        result
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for CachedStorage {
+    async fn replace_config(&self, doc: &ConfigDocument) -> Result<(), DbError> {
+        self.invalidated(self.inner.replace_config(doc).await)
+    }
+
+    async fn vacuum_into(&self, path: &str) -> Result<(), DbError> {
+        self.inner.vacuum_into(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use super::CachedStorage;
+    use crate::db::OpenDialog;
+    use crate::metrics::Metrics;
+    use crate::storage::{AdminStore, DialogStore, WhitelistStore, memory::MemoryStorage};
+
+    #[tokio::test]
+    async fn lookups_are_served_from_memory_until_a_write() {
+        let metrics = Arc::new(Metrics::default());
+        let cache = CachedStorage::new(Arc::new(MemoryStorage::with_superadmin(1, None))).with_metrics(Arc::clone(&metrics));
+        let stats = cache.stats();
+
+        assert!(!cache.is_group_whitelisted(-100).await.unwrap());
+        assert!(cache.get_admin(1).await.unwrap().is_some());
+        assert_eq!((stats.hits(), stats.misses()), (1, 1));
+
+        cache.add_whitelisted_group(-100, 1, None).await.unwrap();
+
+        assert!(cache.is_group_whitelisted(-100).await.unwrap());
+        assert!(cache.is_group_whitelisted(-100).await.unwrap());
+        assert_eq!((stats.hits(), stats.misses()), (2, 2));
+
+        let text = metrics.render();
+        assert!(text.contains("telos_cache_lookups_total{outcome=\"hit\"} 2\n"));
+        assert!(text.contains("telos_cache_lookups_total{outcome=\"miss\"} 2\n"));
+
+        cache.remove_whitelisted_group(-100).await.unwrap();
+        assert!(!cache.is_group_whitelisted(-100).await.unwrap());
+
+        cache.remove_admin(1).await.unwrap();
+        assert!(cache.get_admin(1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn dialog_writes_leave_the_snapshot_alone() {
+        let cache = CachedStorage::new(Arc::new(MemoryStorage::with_superadmin(1, None)));
+        let stats = cache.stats();
+        let dialog = OpenDialog { chat_id: 1, user_id: 1, dialog: "name".to_string(), answers: vec![], expires_at: Utc::now() };
+
+        assert!(cache.get_admin(1).await.unwrap().is_some());
+        assert!(cache.get_dialog(1, 1).await.unwrap().is_none());
+
+        cache.save_dialog(&dialog).await.unwrap();
+        assert!(cache.get_dialog(1, 1).await.unwrap().is_some());
+        cache.remove_dialog(1, 1).await.unwrap();
+        assert!(cache.get_dialog(1, 1).await.unwrap().is_none());
+
+        // Only the set of open dialogs was reloaded
+        assert!(cache.get_admin(1).await.unwrap().is_some());
+        assert_eq!((stats.hits(), stats.misses()), (1, 4));
+    }
+}
@@ -0,0 +1,539 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::Error;
+use uuid::Uuid;
+
+use crate::db::{Admin, BecomeAdminRequest, DbError, DisabledModule, ExemptedBot, IgnoredUser, OpenDialog, WhitelistedGroup, WhitelistedThread};
+use crate::export::ConfigDocument;
+use crate::storage::{AdminStore, DialogStore, ForgetReport, IgnoreStore, ModuleStore, RequestStore, RetentionStore, SnapshotStore, WhitelistStore};
+
+/// In-process storage with the same semantics as the SQLite schema, including
+/// its cascades, so handlers can be exercised without a database file
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    admins: Vec<Admin>,
+    groups: Vec<WhitelistedGroup>,
+    threads: Vec<WhitelistedThread>,
+    requests: Vec<BecomeAdminRequest>,
+    ignored: Vec<IgnoredUser>,
+    exempted: Vec<ExemptedBot>,
+    disabled: Vec<DisabledModule>,
+    dialogs: Vec<OpenDialog>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts with a single superadmin, like the initial migration does
+    pub fn with_superadmin(user_id: i64, name: Option<&str>) -> Self {
+        let storage = Self::new();
+        storage.state.lock().unwrap().admins.push(Admin {
+            user_id,
+            name: name.map(str::to_string),
+            added_by: None,
+            added_at: Some(Utc::now()),
+        });
+        storage
+    }
+}
+
+impl State {
+    fn admin_exists(&self, user_id: i64) -> bool {
+        self.admins.iter().any(|a| a.user_id == user_id)
+    }
+
+    /// Mirrors the foreign keys on `added_by`: the admins they added are
+    /// removed, everything else they added is kept
+    fn remove_admin(&mut self, user_id: i64) {
+        let mut removed = vec![user_id];
+        while let Some(id) = removed.pop() {
+            self.admins.retain(|a| a.user_id != id);
+            removed.extend(self.admins.iter().filter(|a| a.added_by == Some(id)).map(|a| a.user_id));
+
+            for group in self.groups.iter_mut().filter(|g| g.added_by == Some(id)) {
+                group.added_by = None;
+            }
+            for thread in self.threads.iter_mut().filter(|t| t.added_by == Some(id)) {
+                thread.added_by = None;
+            }
+
+            for user in self.ignored.iter_mut().filter(|u| u.added_by == Some(id)) {
+                user.added_by = None;
+            }
+            for bot in self.exempted.iter_mut().filter(|b| b.added_by == Some(id)) {
+                bot.added_by = None;
+            }
+            for module in self.disabled.iter_mut().filter(|m| m.added_by == Some(id)) {
+                module.added_by = None;
+            }
+        }
+    }
+
+    fn remove_group(&mut self, group_id: i64) {
+        self.groups.retain(|g| g.group_id != group_id);
+        self.threads.retain(|t| t.group_id != group_id);
+    }
+}
+
+#[async_trait]
+impl AdminStore for MemoryStorage {
+    async fn add_admin(&self, user_id: i64, added_by: Option<i64>, name: Option<&str>) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.admin_exists(user_id) {
+            return Err(DbError::AlreadyExists);
+        }
+        if let Some(added_by) = added_by
+            && !state.admin_exists(added_by)
+        {
+            return Err(DbError::NotFound);
+        }
+
+        state.admins.push(Admin {
+            user_id,
+            name: name.map(str::to_string),
+            added_by,
+            added_at: Some(Utc::now()),
+        });
+        Ok(())
+    }
+
+    async fn remove_admin(&self, user_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.admin_exists(user_id) {
+            return Err(DbError::NotFound);
+        }
+        state.remove_admin(user_id);
+        Ok(())
+    }
+
+    async fn make_superadmin(&self, user_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let admin = state.admins.iter_mut().find(|a| a.user_id == user_id).ok_or(DbError::NotFound)?;
+        admin.added_by = None;
+        Ok(())
+    }
+
+    async fn get_admin(&self, user_id: i64) -> Result<Option<Admin>, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.admins.iter().find(|a| a.user_id == user_id).cloned())
+    }
+
+    async fn get_admins(&self) -> Result<Vec<Admin>, DbError> {
+        Ok(self.state.lock().unwrap().admins.clone())
+    }
+
+    async fn count_admin_subtree(&self, user_id: i64) -> Result<i64, DbError> {
+        let state = self.state.lock().unwrap();
+
+        let mut count = 0;
+        let mut pending = vec![user_id];
+        while let Some(id) = pending.pop() {
+            for admin in state.admins.iter().filter(|a| a.added_by == Some(id)) {
+                count += 1;
+                pending.push(admin.user_id);
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl WhitelistStore for MemoryStorage {
+    async fn add_whitelisted_group(&self, group_id: i64, added_by: i64, group_name: Option<&str>) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.groups.iter().any(|g| g.group_id == group_id) {
+            return Err(DbError::AlreadyExists);
+        }
+        if !state.admin_exists(added_by) {
+            return Err(DbError::NotFound);
+        }
+
+        state.groups.push(WhitelistedGroup {
+            group_id,
+            group_name: group_name.map(str::to_string),
+            added_by: Some(added_by),
+            created_at: Some(Utc::now()),
+        });
+        Ok(())
+    }
+
+    async fn add_whitelisted_thread(
+        &self,
+        thread_id: i32,
+        group_id: i64,
+        added_by: i64,
+        group_name: Option<&str>,
+        thread_name: Option<&str>,
+    ) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.threads.iter().any(|t| t.thread_id == thread_id && t.group_id == group_id) {
+            return Err(DbError::AlreadyExists);
+        }
+        if !state.groups.iter().any(|g| g.group_id == group_id) {
+            return Err(DbError::NotFound);
+        }
+        if !state.admin_exists(added_by) {
+            return Err(DbError::NotFound);
+        }
+
+        state.threads.push(WhitelistedThread {
+            thread_id,
+            group_id,
+            group_name: group_name.map(str::to_string),
+            thread_name: thread_name.map(str::to_string),
+            added_by: Some(added_by),
+            created_at: Some(Utc::now()),
+        });
+        Ok(())
+    }
+
+    async fn remove_whitelisted_group(&self, group_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.groups.iter().any(|g| g.group_id == group_id) {
+            return Err(DbError::NotFound);
+        }
+        state.remove_group(group_id);
+        Ok(())
+    }
+
+    async fn remove_whitelisted_thread(&self, thread_id: i32, group_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.threads.len();
+        state.threads.retain(|t| !(t.thread_id == thread_id && t.group_id == group_id));
+        if state.threads.len() == before {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn get_whitelisted_group(&self, group_id: i64) -> Result<Option<WhitelistedGroup>, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.groups.iter().find(|g| g.group_id == group_id).cloned())
+    }
+
+    async fn get_whitelisted_groups(&self) -> Result<Vec<WhitelistedGroup>, DbError> {
+        Ok(self.state.lock().unwrap().groups.clone())
+    }
+
+    async fn get_whitelisted_threads(&self, group_id: i64) -> Result<Vec<WhitelistedThread>, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.threads.iter().filter(|t| t.group_id == group_id).cloned().collect())
+    }
+
+    async fn get_all_whitelisted_threads(&self) -> Result<Vec<WhitelistedThread>, DbError> {
+        Ok(self.state.lock().unwrap().threads.clone())
+    }
+
+    async fn is_group_whitelisted(&self, group_id: i64) -> Result<bool, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.groups.iter().any(|g| g.group_id == group_id))
+    }
+
+    async fn is_thread_whitelisted(&self, thread_id: i32, group_id: i64) -> Result<bool, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.threads.iter().any(|t| t.thread_id == thread_id && t.group_id == group_id))
+    }
+}
+
+#[async_trait]
+impl RequestStore for MemoryStorage {
+    async fn create_become_admin_request(&self, user_id: i64, user_name: Option<&str>) -> Result<String, DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.requests.iter().any(|r| r.user_id == user_id && r.pending.unwrap_or(false)) {
+            return Err(DbError::AlreadyExists);
+        }
+
+        let request_id = Uuid::now_v7().to_string();
+        state.requests.push(BecomeAdminRequest {
+            request_id: request_id.clone(),
+            user_id,
+            user_name: user_name.map(str::to_string),
+            created_at: Some(Utc::now()),
+            pending: Some(true),
+            accepted: None,
+        });
+
+        Ok(request_id)
+    }
+
+    async fn get_become_admin_requests(&self) -> Result<Vec<BecomeAdminRequest>, DbError> {
+        Ok(self.state.lock().unwrap().requests.clone())
+    }
+
+    async fn approve_become_admin_request(&self, request_id: &str, admin_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let request = state
+            .requests
+            .iter()
+            .find(|r| r.request_id == request_id)
+            .ok_or(DbError::NotFound)?;
+
+        if !request.pending.unwrap_or(false) {
+            return Err(DbError::NotPending);
+        }
+        if state.admin_exists(request.user_id) {
+            return Err(DbError::AlreadyExists);
+        }
+        if !state.admin_exists(admin_id) {
+            return Err(DbError::NotFound);
+        }
+
+        let admin = Admin {
+            user_id: request.user_id,
+            name: request.user_name.clone(),
+            added_by: Some(admin_id),
+            added_at: Some(Utc::now()),
+        };
+        state.admins.push(admin);
+
+        let request = state.requests.iter_mut().find(|r| r.request_id == request_id).unwrap();
+        request.pending = Some(false);
+        request.accepted = Some(true);
+        Ok(())
+    }
+
+    async fn reject_become_admin_request(&self, request_id: &str) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let position = state
+            .requests
+            .iter()
+            .position(|r| r.request_id == request_id)
+            .ok_or(DbError::NotFound)?;
+
+        if !state.requests[position].pending.unwrap_or(false) {
+            return Err(DbError::NotPending);
+        }
+        state.requests.remove(position);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IgnoreStore for MemoryStorage {
+    async fn ignore_user(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        added_by: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        state.ignored.retain(|u| !(u.user_id == user_id && u.chat_id == chat_id));
+        state.ignored.push(IgnoredUser {
+            user_id,
+            chat_id,
+            added_by: Some(added_by),
+            created_at: Some(Utc::now()),
+            expires_at,
+        });
+        Ok(())
+    }
+
+    async fn unignore_user(&self, user_id: i64, chat_id: Option<i64>) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.ignored.len();
+        state.ignored.retain(|u| !(u.user_id == user_id && u.chat_id == chat_id));
+        if state.ignored.len() == before {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn is_user_ignored(&self, user_id: i64, chat_id: i64) -> Result<bool, DbError> {
+        let state = self.state.lock().unwrap();
+        let now = Utc::now();
+        Ok(state.ignored.iter().any(|u| {
+            u.user_id == user_id
+                && u.chat_id.is_none_or(|id| id == chat_id)
+                && u.expires_at.is_none_or(|expires_at| expires_at > now)
+        }))
+    }
+
+    async fn get_ignored_users(&self) -> Result<Vec<IgnoredUser>, DbError> {
+        let state = self.state.lock().unwrap();
+        let now = Utc::now();
+        Ok(state
+            .ignored
+            .iter()
+            .filter(|u| u.expires_at.is_none_or(|expires_at| expires_at > now))
+            .cloned()
+            .collect())
+    }
+
+    async fn add_exempted_bot(&self, user_id: i64, added_by: i64, name: Option<&str>) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.exempted.iter().any(|b| b.user_id == user_id) {
+            return Err(DbError::AlreadyExists);
+        }
+
+        state.exempted.push(ExemptedBot {
+            user_id,
+            name: name.map(str::to_string),
+            added_by: Some(added_by),
+            created_at: Some(Utc::now()),
+        });
+        Ok(())
+    }
+
+    async fn remove_exempted_bot(&self, user_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.exempted.len();
+        state.exempted.retain(|b| b.user_id != user_id);
+        if state.exempted.len() == before {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn is_bot_exempted(&self, user_id: i64) -> Result<bool, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.exempted.iter().any(|b| b.user_id == user_id))
+    }
+
+    async fn get_exempted_bots(&self) -> Result<Vec<ExemptedBot>, DbError> {
+        Ok(self.state.lock().unwrap().exempted.clone())
+    }
+}
+
+#[async_trait]
+impl ModuleStore for MemoryStorage {
+    async fn disable_module(&self, chat_id: i64, module: &str, added_by: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        if state.disabled.iter().any(|m| m.chat_id == chat_id && m.module == module) {
+            return Err(DbError::AlreadyExists);
+        }
+
+        state.disabled.push(DisabledModule {
+            chat_id,
+            module: module.to_string(),
+            added_by: Some(added_by),
+            created_at: Some(Utc::now()),
+        });
+        Ok(())
+    }
+
+    async fn enable_module(&self, chat_id: i64, module: &str) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.disabled.len();
+        state.disabled.retain(|m| m.chat_id != chat_id || m.module != module);
+        if state.disabled.len() == before {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn is_module_disabled(&self, chat_id: i64, module: &str) -> Result<bool, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.disabled.iter().any(|m| m.chat_id == chat_id && m.module == module))
+    }
+
+    async fn get_disabled_modules(&self) -> Result<Vec<DisabledModule>, DbError> {
+        Ok(self.state.lock().unwrap().disabled.clone())
+    }
+}
+
+#[async_trait]
+impl DialogStore for MemoryStorage {
+    async fn save_dialog(&self, dialog: &OpenDialog) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        state.dialogs.retain(|d| d.chat_id != dialog.chat_id || d.user_id != dialog.user_id);
+        state.dialogs.push(dialog.clone());
+        Ok(())
+    }
+
+    async fn get_dialog(&self, chat_id: i64, user_id: i64) -> Result<Option<OpenDialog>, DbError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.dialogs.iter().find(|d| d.chat_id == chat_id && d.user_id == user_id).cloned())
+    }
+
+    async fn remove_dialog(&self, chat_id: i64, user_id: i64) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.dialogs.len();
+        state.dialogs.retain(|d| d.chat_id != chat_id || d.user_id != user_id);
+        if state.dialogs.len() == before {
+            return Err(DbError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn get_dialogs(&self) -> Result<Vec<OpenDialog>, DbError> {
+        Ok(self.state.lock().unwrap().dialogs.clone())
+    }
+}
+
+#[async_trait]
+impl RetentionStore for MemoryStorage {
+    async fn forget_user(&self, user_id: i64) -> Result<ForgetReport, DbError> {
+        let mut state = self.state.lock().unwrap();
+
+        let before = state.requests.len();
+        state.requests.retain(|r| r.user_id != user_id);
+        let requests_deleted = (before - state.requests.len()) as u64;
+
+        let mut admin_name_removed = false;
+        for admin in state.admins.iter_mut().filter(|a| a.user_id == user_id && a.name.is_some()) {
+            admin.name = None;
+            admin_name_removed = true;
+        }
+
+        let before = state.dialogs.len();
+        state.dialogs.retain(|d| d.user_id != user_id);
+        let dialogs_deleted = (before - state.dialogs.len()) as u64;
+
+        Ok(ForgetReport {
+            requests_deleted,
+            admin_name_removed,
+            dialogs_deleted,
+            ignore_entries_kept: state.ignored.iter().filter(|u| u.user_id == user_id).count() as u64,
+        })
+    }
+
+    async fn purge_requests(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.requests.len();
+        state.requests.retain(|r| r.created_at.is_none_or(|created_at| created_at >= before));
+        Ok((count - state.requests.len()) as u64)
+    }
+
+    async fn purge_expired_ignores(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.ignored.len();
+        state.ignored.retain(|u| u.expires_at.is_none_or(|expires_at| expires_at >= before));
+        Ok((count - state.ignored.len()) as u64)
+    }
+
+    async fn purge_expired_dialogs(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.dialogs.len();
+        state.dialogs.retain(|d| d.expires_at >= before);
+        Ok((count - state.dialogs.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for MemoryStorage {
+    async fn replace_config(&self, doc: &ConfigDocument) -> Result<(), DbError> {
+        let mut state = self.state.lock().unwrap();
+        state.admins = doc.admins_in_insert_order().into_iter().cloned().collect();
+        state.groups = doc.whitelisted_groups.clone();
+        state.threads = doc.whitelisted_threads.clone();
+        state.ignored = doc.ignored_users.clone();
+        state.exempted = doc.exempted_bots.clone();
+        state.disabled = doc.disabled_modules.clone();
+        Ok(())
+    }
+
+    async fn vacuum_into(&self, _path: &str) -> Result<(), DbError> {
+        Err(DbError::Storage(Error::Protocol("in-memory storage can't be snapshotted".to_string())))
+    }
+}
@@ -0,0 +1,236 @@
+use std::{future::Future, sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::db::{Admin, BecomeAdminRequest, DbError, DisabledModule, ExemptedBot, IgnoredUser, OpenDialog, WhitelistedGroup, WhitelistedThread};
+use crate::export::ConfigDocument;
+use crate::metrics::Metrics;
+use crate::storage::{
+    AdminStore, DialogStore, ForgetReport, IgnoreStore, ModuleStore, RequestStore, RetentionStore, SharedStorage, SnapshotStore, WhitelistStore,
+};
+
+/// Wraps another storage, recording how long each call takes
+pub struct MeteredStorage {
+    inner: SharedStorage,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredStorage {
+    pub fn new(inner: SharedStorage, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    async fn timed<T>(&self, operation: &'static str, call: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = call.await;
+        self.metrics.db_latency(operation, start.elapsed());
+        result
+    }
+}
+
+#[async_trait]
+impl AdminStore for MeteredStorage {
+    async fn add_admin(&self, user_id: i64, added_by: Option<i64>, name: Option<&str>) -> Result<(), DbError> {
+        self.timed("add_admin", self.inner.add_admin(user_id, added_by, name)).await
+    }
+
+    async fn remove_admin(&self, user_id: i64) -> Result<(), DbError> {
+        self.timed("remove_admin", self.inner.remove_admin(user_id)).await
+    }
+
+    async fn make_superadmin(&self, user_id: i64) -> Result<(), DbError> {
+        self.timed("make_superadmin", self.inner.make_superadmin(user_id)).await
+    }
+
+    async fn get_admin(&self, user_id: i64) -> Result<Option<Admin>, DbError> {
+        self.timed("get_admin", self.inner.get_admin(user_id)).await
+    }
+
+    async fn get_admins(&self) -> Result<Vec<Admin>, DbError> {
+        self.timed("get_admins", self.inner.get_admins()).await
+    }
+
+    async fn count_admin_subtree(&self, user_id: i64) -> Result<i64, DbError> {
+        self.timed("count_admin_subtree", self.inner.count_admin_subtree(user_id)).await
+    }
+}
+
+#[async_trait]
+impl WhitelistStore for MeteredStorage {
+    async fn add_whitelisted_group(&self, group_id: i64, added_by: i64, group_name: Option<&str>) -> Result<(), DbError> {
+        self.timed("add_whitelisted_group", self.inner.add_whitelisted_group(group_id, added_by, group_name)).await
+    }
+
+    async fn add_whitelisted_thread(
+        &self,
+        thread_id: i32,
+        group_id: i64,
+        added_by: i64,
+        group_name: Option<&str>,
+        thread_name: Option<&str>,
+    ) -> Result<(), DbError> {
+        let call = self.inner.add_whitelisted_thread(thread_id, group_id, added_by, group_name, thread_name);
+        self.timed("add_whitelisted_thread", call).await
+    }
+
+    async fn remove_whitelisted_group(&self, group_id: i64) -> Result<(), DbError> {
+        self.timed("remove_whitelisted_group", self.inner.remove_whitelisted_group(group_id)).await
+    }
+
+    async fn remove_whitelisted_thread(&self, thread_id: i32, group_id: i64) -> Result<(), DbError> {
+        self.timed("remove_whitelisted_thread", self.inner.remove_whitelisted_thread(thread_id, group_id)).await
+    }
+
+    async fn get_whitelisted_group(&self, group_id: i64) -> Result<Option<WhitelistedGroup>, DbError> {
+        self.timed("get_whitelisted_group", self.inner.get_whitelisted_group(group_id)).await
+    }
+
+    async fn get_whitelisted_groups(&self) -> Result<Vec<WhitelistedGroup>, DbError> {
+        self.timed("get_whitelisted_groups", self.inner.get_whitelisted_groups()).await
+    }
+
+    async fn get_whitelisted_threads(&self, group_id: i64) -> Result<Vec<WhitelistedThread>, DbError> {
+        self.timed("get_whitelisted_threads", self.inner.get_whitelisted_threads(group_id)).await
+    }
+
+    async fn get_all_whitelisted_threads(&self) -> Result<Vec<WhitelistedThread>, DbError> {
+        self.timed("get_all_whitelisted_threads", self.inner.get_all_whitelisted_threads()).await
+    }
+
+    async fn is_group_whitelisted(&self, group_id: i64) -> Result<bool, DbError> {
+        self.timed("is_group_whitelisted", self.inner.is_group_whitelisted(group_id)).await
+    }
+
+    async fn is_thread_whitelisted(&self, thread_id: i32, group_id: i64) -> Result<bool, DbError> {
+        self.timed("is_thread_whitelisted", self.inner.is_thread_whitelisted(thread_id, group_id)).await
+    }
+}
+
+#[async_trait]
+impl RequestStore for MeteredStorage {
+    async fn create_become_admin_request(&self, user_id: i64, user_name: Option<&str>) -> Result<String, DbError> {
+        self.timed("create_become_admin_request", self.inner.create_become_admin_request(user_id, user_name)).await
+    }
+
+    async fn get_become_admin_requests(&self) -> Result<Vec<BecomeAdminRequest>, DbError> {
+        self.timed("get_become_admin_requests", self.inner.get_become_admin_requests()).await
+    }
+
+    async fn approve_become_admin_request(&self, request_id: &str, admin_id: i64) -> Result<(), DbError> {
+        self.timed("approve_become_admin_request", self.inner.approve_become_admin_request(request_id, admin_id)).await
+    }
+
+    async fn reject_become_admin_request(&self, request_id: &str) -> Result<(), DbError> {
+        self.timed("reject_become_admin_request", self.inner.reject_become_admin_request(request_id)).await
+    }
+}
+
+#[async_trait]
+impl IgnoreStore for MeteredStorage {
+    async fn ignore_user(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        added_by: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DbError> {
+        self.timed("ignore_user", self.inner.ignore_user(user_id, chat_id, added_by, expires_at)).await
+    }
+
+    async fn unignore_user(&self, user_id: i64, chat_id: Option<i64>) -> Result<(), DbError> {
+        self.timed("unignore_user", self.inner.unignore_user(user_id, chat_id)).await
+    }
+
+    async fn is_user_ignored(&self, user_id: i64, chat_id: i64) -> Result<bool, DbError> {
+        self.timed("is_user_ignored", self.inner.is_user_ignored(user_id, chat_id)).await
+    }
+
+    async fn get_ignored_users(&self) -> Result<Vec<IgnoredUser>, DbError> {
+        self.timed("get_ignored_users", self.inner.get_ignored_users()).await
+    }
+
+    async fn add_exempted_bot(&self, user_id: i64, added_by: i64, name: Option<&str>) -> Result<(), DbError> {
+        self.timed("add_exempted_bot", self.inner.add_exempted_bot(user_id, added_by, name)).await
+    }
+
+    async fn remove_exempted_bot(&self, user_id: i64) -> Result<(), DbError> {
+        self.timed("remove_exempted_bot", self.inner.remove_exempted_bot(user_id)).await
+    }
+
+    async fn is_bot_exempted(&self, user_id: i64) -> Result<bool, DbError> {
+        self.timed("is_bot_exempted", self.inner.is_bot_exempted(user_id)).await
+    }
+
+    async fn get_exempted_bots(&self) -> Result<Vec<ExemptedBot>, DbError> {
+        self.timed("get_exempted_bots", self.inner.get_exempted_bots()).await
+    }
+}
+
+#[async_trait]
+impl ModuleStore for MeteredStorage {
+    async fn disable_module(&self, chat_id: i64, module: &str, added_by: i64) -> Result<(), DbError> {
+        self.timed("disable_module", self.inner.disable_module(chat_id, module, added_by)).await
+    }
+
+    async fn enable_module(&self, chat_id: i64, module: &str) -> Result<(), DbError> {
+        self.timed("enable_module", self.inner.enable_module(chat_id, module)).await
+    }
+
+    async fn is_module_disabled(&self, chat_id: i64, module: &str) -> Result<bool, DbError> {
+        self.timed("is_module_disabled", self.inner.is_module_disabled(chat_id, module)).await
+    }
+
+    async fn get_disabled_modules(&self) -> Result<Vec<DisabledModule>, DbError> {
+        self.timed("get_disabled_modules", self.inner.get_disabled_modules()).await
+    }
+}
+
+#[async_trait]
+impl DialogStore for MeteredStorage {
+    async fn save_dialog(&self, dialog: &OpenDialog) -> Result<(), DbError> {
+        self.timed("save_dialog", self.inner.save_dialog(dialog)).await
+    }
+
+    async fn get_dialog(&self, chat_id: i64, user_id: i64) -> Result<Option<OpenDialog>, DbError> {
+        self.timed("get_dialog", self.inner.get_dialog(chat_id, user_id)).await
+    }
+
+    async fn remove_dialog(&self, chat_id: i64, user_id: i64) -> Result<(), DbError> {
+        self.timed("remove_dialog", self.inner.remove_dialog(chat_id, user_id)).await
+    }
+
+    async fn get_dialogs(&self) -> Result<Vec<OpenDialog>, DbError> {
+        self.timed("get_dialogs", self.inner.get_dialogs()).await
+    }
+}
+
+#[async_trait]
+impl RetentionStore for MeteredStorage {
+    async fn forget_user(&self, user_id: i64) -> Result<ForgetReport, DbError> {
+        self.timed("forget_user", self.inner.forget_user(user_id)).await
+    }
+
+    async fn purge_requests(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        self.timed("purge_requests", self.inner.purge_requests(before)).await
+    }
+
+    async fn purge_expired_ignores(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        self.timed("purge_expired_ignores", self.inner.purge_expired_ignores(before)).await
+    }
+
+    async fn purge_expired_dialogs(&self, before: DateTime<Utc>) -> Result<u64, DbError> {
+        self.timed("purge_expired_dialogs", self.inner.purge_expired_dialogs(before)).await
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for MeteredStorage {
+    async fn replace_config(&self, doc: &ConfigDocument) -> Result<(), DbError> {
+        self.timed("replace_config", self.inner.replace_config(doc)).await
+    }
+
+    async fn vacuum_into(&self, path: &str) -> Result<(), DbError> {
+        self.timed("vacuum_into", self.inner.vacuum_into(path)).await
+    }
+}
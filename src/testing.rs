@@ -0,0 +1,28 @@
+//! Helpers shared by the unit tests
+
+use teloxide::prelude::*;
+
+use crate::{db::DB, fake_api::FakeApi};
+
+pub use crate::fake_api::Calls;
+
+/// A freshly migrated in-memory SQLite database
+#[cfg(not(feature = "postgres"))]
+pub async fn test_db() -> DB {
+    DB::scratch("sqlite::memory:").await.unwrap()
+}
+
+/// A freshly migrated schema of the database in `TEST_DATABASE_URL`, so
+/// tests can run in parallel against the same server
+#[cfg(feature = "postgres")]
+pub async fn test_db() -> DB {
+    let url = std::env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must point to a PostgreSQL database");
+    DB::scratch(&url).await.unwrap()
+}
+
+/// A bot talking to a fresh `FakeApi`, with the calls it records
+pub async fn fake_api() -> (Bot, Calls) {
+    let api = FakeApi::start().await.unwrap();
+    (api.bot(), api.calls())
+}
@@ -0,0 +1,180 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use teloxide::{
+    prelude::*,
+    update_listeners::{UpdateListener, webhooks},
+};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::config::WebhookSettings;
+
+/// Where Telegram sends updates in webhook mode. TLS is terminated in front
+/// of the bot, which only listens on a local address.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// Public HTTPS URL registered with Telegram
+    pub url: Url,
+    /// Local address the listener binds to
+    pub address: SocketAddr,
+    /// Path the listener accepts updates on
+    pub path: String,
+    /// Expected in the `X-Telegram-Bot-Api-Secret-Token` header, generated
+    /// on every start when unset
+    pub secret_token: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Builds the config from the `[webhook]` settings. The bot uses long
+    /// polling unless `webhook.url` is set.
+    pub fn from_config(settings: &WebhookSettings) -> Result<Option<Self>, String> {
+        let url = match &settings.url {
+            Some(url) => url.parse::<Url>().map_err(|e| format!("webhook.url is not a valid URL: {e}"))?,
+            None => return Ok(None),
+        };
+
+        let address = settings
+            .address
+            .parse()
+            .map_err(|_| format!("webhook.address must be an ip:port address, got {:?}", settings.address))?;
+
+        let path = settings.path.clone().unwrap_or_else(|| url.path().to_string());
+        if !path.starts_with('/') {
+            return Err(format!("webhook.path must start with /, got {path:?}"));
+        }
+
+        let secret_token = settings.secret.clone();
+        if let Some(secret) = &secret_token
+            && (secret.is_empty()
+                || secret.len() > 256
+                || !secret.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        {
+            return Err("webhook.secret must be 1 to 256 characters of A-Z, a-z, 0-9, _ and -".to_string());
+        }
+
+        Ok(Some(Self { url, address, path, secret_token }))
+    }
+
+    fn options(&self) -> webhooks::Options {
+        let options = webhooks::Options::new(self.address, self.url.clone()).path(self.path.clone());
+        match &self.secret_token {
+            Some(secret) => options.secret_token(secret.clone()),
+            None => options,
+        }
+    }
+}
+
+/// Registers the webhook and starts listening for updates. The returned task
+/// finishes once the listener was stopped and the webhook deregistered, so
+/// it should be awaited before exiting.
+pub async fn listen(
+    bot: Bot,
+    config: &WebhookConfig,
+) -> Result<(impl UpdateListener<Err = Infallible> + use<>, JoinHandle<()>), Box<dyn std::error::Error>> {
+    // Bind first, so a busy port doesn't leave a webhook pointing nowhere
+    let tcp_listener = tokio::net::TcpListener::bind(config.address).await?;
+
+    let (listener, stop_flag, router) = webhooks::axum_to_router(bot, config.options()).await?;
+    tracing::info!("Webhook registered, listening on {}{}", config.address, config.path);
+
+    let server = tokio::spawn(async move {
+        if let Err(e) = axum::serve(tcp_listener, router).with_graceful_shutdown(stop_flag).await {
+            tracing::error!("Webhook server error: {:?}", e);
+        }
+        tracing::info!("Webhook deregistered");
+    });
+
+    Ok((listener, server))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use serde_json::json;
+    use teloxide::prelude::*;
+
+    use super::{WebhookConfig, listen};
+    use crate::{
+        admin::AdminHandler,
+        handler::{self, Handler},
+        miguel::MiguelHandler,
+        module::Registry,
+        storage::{WhitelistStore, memory::MemoryStorage},
+        testing::fake_api,
+    };
+
+    const SECRET: &str = "webhook-secret";
+
+    #[tokio::test]
+    async fn posted_updates_reach_the_handlers() {
+        let (bot, calls) = fake_api().await;
+        let storage = Arc::new(MemoryStorage::with_superadmin(1, None));
+        let registry = Registry::new(storage.clone())
+            .register(AdminHandler::new(storage.clone(), Arc::default()).unwrap())
+            .register(MiguelHandler::new(Arc::default()).unwrap());
+        let the_handler = Handler::new(registry);
+
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = WebhookConfig {
+            url: "https://example.com/telegram".parse().unwrap(),
+            address,
+            path: "/telegram".to_string(),
+            secret_token: Some(SECRET.to_string()),
+        };
+
+        let (listener, server) = listen(bot.clone(), &config).await.unwrap();
+        let mut dispatcher = Dispatcher::builder(bot, handler::schema(Arc::new(the_handler))).build();
+        let shutdown = dispatcher.shutdown_token();
+        let dispatching = tokio::spawn(async move {
+            dispatcher
+                .dispatch_with_listener(listener, LoggingErrorHandler::new())
+                .await;
+        });
+
+        // Recorded from Telegram, trimmed to the fields the handlers read
+        let update = json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 10,
+                "date": 0,
+                "chat": {"id": -100, "type": "supergroup", "title": "group"},
+                "from": {"id": 1, "is_bot": false, "first_name": "root"},
+                "text": "/whitelist_group",
+            },
+        });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{address}/telegram");
+        let post = |secret: &'static str| {
+            client
+                .post(&url)
+                .header("X-Telegram-Bot-Api-Secret-Token", secret)
+                .header("Content-Type", "application/json")
+                .body(update.to_string())
+                .send()
+        };
+
+        assert_eq!(post("wrong").await.unwrap().status(), 401);
+        assert_eq!(post(SECRET).await.unwrap().status(), 200);
+
+        for _ in 0..100 {
+            if storage.is_group_whitelisted(-100).await.unwrap() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(storage.is_group_whitelisted(-100).await.unwrap());
+
+        shutdown.shutdown().unwrap().await;
+        dispatching.await.unwrap();
+        server.await.unwrap();
+
+        let calls = calls.lock().unwrap();
+        let methods: Vec<&str> = calls.iter().map(|(method, _)| method.as_str()).collect();
+        let set_webhook = calls.iter().find(|(method, _)| method == "SetWebhook").unwrap();
+        assert_eq!(set_webhook.1["url"], "https://example.com/telegram");
+        assert_eq!(set_webhook.1["secret_token"], SECRET);
+        assert_eq!(methods.last(), Some(&"DeleteWebhook"));
+    }
+}
@@ -0,0 +1,226 @@
+//! Feeds scripted updates through the dispatcher schema the bot runs, with a
+//! real database and a fake Bot API, and checks the calls the bot made
+#![cfg(not(feature = "postgres"))]
+
+use std::sync::Arc;
+
+use serde_json::{Value, json};
+use teloxide::{dispatching::UpdateHandler, prelude::*};
+use telos::{
+    admin::AdminHandler,
+    config::MiguelSettings,
+    db::DB,
+    fake_api::FakeApi,
+    handler::{self, Handler, HandlerError},
+    miguel::MiguelHandler,
+    module::Registry,
+    storage::{AdminStore, RequestStore, WhitelistStore},
+};
+
+const SUPERADMIN: i64 = 1;
+const GROUP: i64 = -100;
+
+struct Harness {
+    api: FakeApi,
+    bot: Bot,
+    db: Arc<DB>,
+    schema: UpdateHandler<HandlerError>,
+    next_update: i64,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let db = Arc::new(DB::new("sqlite::memory:").await.unwrap());
+        db.migrate().await.unwrap();
+        db.add_admin(SUPERADMIN, None, Some("root")).await.unwrap();
+
+        let registry = Registry::new(db.clone())
+            .register(AdminHandler::new(db.clone(), Arc::default()).unwrap())
+            .register(MiguelHandler::new(Arc::default()).unwrap());
+        let schema = handler::schema(Arc::new(Handler::new(registry)));
+
+        let api = FakeApi::start().await.unwrap();
+        let bot = api.bot();
+        Self { api, bot, db, schema, next_update: 1 }
+    }
+
+    /// Handles one update, given everything but its id
+    async fn update(&mut self, mut update: Value) {
+        update["update_id"] = json!(self.next_update);
+        self.next_update += 1;
+
+        let update: Update = serde_json::from_str(&update.to_string()).unwrap();
+        let result = self.schema.dispatch(dptree::deps![self.bot.clone(), update]).await;
+        assert!(result.is_break(), "update was not handled");
+    }
+
+    async fn message(&mut self, chat: Value, from: Value, text: &str) {
+        let message = json!({"message_id": self.next_update, "date": 0, "chat": chat, "from": from, "text": text});
+        self.update(json!({"message": message})).await;
+    }
+
+    async fn private(&mut self, from: i64, text: &str) {
+        self.message(json!({"id": from, "type": "private", "first_name": "user"}), user(from), text).await;
+    }
+
+    async fn group(&mut self, from: i64, text: &str) {
+        self.message(json!({"id": GROUP, "type": "supergroup", "title": "group"}), user(from), text).await;
+    }
+
+    /// A message from an anonymous admin of the group, sent as the group
+    async fn anonymous(&mut self, text: &str) {
+        let group = json!({"id": GROUP, "type": "supergroup", "title": "group"});
+        let message = json!({
+            "message_id": self.next_update,
+            "date": 0,
+            "chat": group,
+            "sender_chat": group,
+            "from": {"id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot"},
+            "text": text,
+        });
+        self.update(json!({"message": message})).await;
+    }
+
+    /// Presses a button on a message the bot sent to `chat`
+    async fn press(&mut self, chat: i64, from: i64, data: &str) {
+        let chat = match chat {
+            GROUP => json!({"id": GROUP, "type": "supergroup", "title": "group"}),
+            chat => json!({"id": chat, "type": "private", "first_name": "user"}),
+        };
+        self.update(json!({"callback_query": {
+            "id": "query",
+            "from": user(from),
+            "chat_instance": "instance",
+            "data": data,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": chat,
+                "text": "summary",
+            },
+        }}))
+        .await;
+    }
+
+    /// Texts sent since the last call, with the chat they went to
+    fn replies(&self) -> Vec<(i64, String)> {
+        self.api
+            .take_calls()
+            .into_iter()
+            .filter(|(method, _)| method == "SendMessage")
+            .map(|(_, body)| (body["chat_id"].as_i64().unwrap(), body["text"].as_str().unwrap().to_string()))
+            .collect()
+    }
+}
+
+fn user(id: i64) -> Value {
+    json!({"id": id, "is_bot": false, "first_name": format!("user{id}"), "username": format!("user{id}")})
+}
+
+#[tokio::test]
+async fn groups_are_quiet_until_whitelisted() {
+    let mut bot = Harness::new().await;
+
+    bot.group(2, "/miguel").await;
+    assert_eq!(bot.replies(), []);
+
+    // Only admins whitelist
+    bot.group(2, "/whitelist_group").await;
+    assert_eq!(bot.replies(), []);
+
+    bot.group(SUPERADMIN, "/whitelist_group").await;
+    bot.group(2, "/miguel").await;
+    let replies = bot.replies();
+    assert_eq!(replies.len(), 2);
+    assert_eq!(replies[1], (GROUP, MiguelSettings::default().text));
+}
+
+#[tokio::test]
+async fn admins_are_approved_and_removed() {
+    let mut bot = Harness::new().await;
+
+    bot.private(2, "/become_admin").await;
+    let requests = bot.db.get_become_admin_requests().await.unwrap();
+    bot.replies();
+
+    bot.private(SUPERADMIN, &format!("/approve_become_admin {}", requests[0].request_id)).await;
+    assert!(bot.db.get_admin(2).await.unwrap().is_some());
+    assert_eq!(bot.replies(), [(SUPERADMIN, "Admin approved!".to_string())]);
+
+    bot.private(SUPERADMIN, "/remove_admin 2").await;
+    let calls = bot.api.take_calls();
+    let (_, prompt) = calls.iter().find(|(method, _)| method == "SendMessage").unwrap();
+    let confirm = prompt["reply_markup"]["inline_keyboard"][0][0]["callback_data"].as_str().unwrap();
+    assert!(bot.db.get_admin(2).await.unwrap().is_some());
+
+    bot.press(SUPERADMIN, SUPERADMIN, confirm).await;
+    assert!(bot.db.get_admin(2).await.unwrap().is_none());
+    let calls = bot.api.take_calls();
+    assert!(calls.iter().any(|(method, _)| method == "AnswerCallbackQuery"));
+}
+
+#[tokio::test]
+async fn miguel_is_answered_and_reacted_to() {
+    let mut bot = Harness::new().await;
+    bot.private(3, "/miguelito").await;
+    assert_eq!(bot.replies(), [(3, MiguelSettings::default().text)]);
+
+    // Only messages from miguel himself get the reaction
+    bot.private(3, "miguel").await;
+    bot.message(json!({"id": 4, "type": "private", "first_name": "user"}), json!({
+        "id": 4, "is_bot": false, "first_name": "Miguel", "username": "migeyel",
+    }), "o miguwu chegou")
+    .await;
+
+    let calls = bot.api.take_calls();
+    assert_eq!(calls.len(), 1);
+    let (method, body) = &calls[0];
+    assert_eq!(method, "SetMessageReaction");
+    assert_eq!(body["chat_id"], 4);
+    assert_eq!(body["reaction"][0]["emoji"], "🗿");
+}
+
+#[tokio::test]
+async fn anonymous_admins_act_as_their_group() {
+    let mut bot = Harness::new().await;
+    bot.api.add_administrator(GROUP, SUPERADMIN, true);
+
+    bot.anonymous("/become_admin").await;
+    let requests = bot.db.get_become_admin_requests().await.unwrap();
+    assert_eq!((requests[0].user_id, requests[0].user_name.as_deref()), (GROUP, Some("group")));
+    bot.private(SUPERADMIN, &format!("/approve_become_admin {}", requests[0].request_id)).await;
+    bot.replies();
+
+    bot.anonymous("/whitelist_group").await;
+    assert_eq!(bot.replies(), [(GROUP, "Group whitelisted!".to_string())]);
+    assert_eq!(bot.db.get_whitelisted_group(GROUP).await.unwrap().unwrap().added_by, Some(GROUP));
+
+    // Only what needs the group's rights is checked with Telegram
+    bot.anonymous("hello").await;
+    assert!(bot.api.take_calls().iter().all(|(method, _)| method != "GetChatAdministrators"));
+
+    // Any admin of the group may answer for it
+    bot.anonymous("/unwhitelist_group").await;
+    let calls = bot.api.take_calls();
+    let (_, prompt) = calls.iter().find(|(method, _)| method == "SendMessage").unwrap();
+    let confirm = prompt["reply_markup"]["inline_keyboard"][0][0]["callback_data"].as_str().unwrap();
+
+    bot.press(GROUP, 3, confirm).await;
+    assert!(bot.db.is_group_whitelisted(GROUP).await.unwrap());
+    bot.press(GROUP, SUPERADMIN, confirm).await;
+    assert!(!bot.db.is_group_whitelisted(GROUP).await.unwrap());
+}
+
+#[tokio::test]
+async fn groups_without_anonymous_admins_cant_act() {
+    let mut bot = Harness::new().await;
+    bot.db.add_admin(GROUP, Some(SUPERADMIN), Some("group")).await.unwrap();
+
+    bot.anonymous("/whitelist_group").await;
+    assert_eq!(bot.replies(), []);
+
+    bot.api.add_administrator(GROUP, 2, false);
+    bot.anonymous("/whitelist_group").await;
+    assert_eq!(bot.replies(), []);
+    assert!(!bot.db.is_group_whitelisted(GROUP).await.unwrap());
+}
@@ -0,0 +1,142 @@
+//! Runs the bot binary against a fake Bot API and stops it with SIGTERM while
+//! it is still replying to a batch of updates
+#![cfg(all(unix, not(feature = "postgres")))]
+
+use std::{
+    process::{Command, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{Json, Router, body::Bytes, extract::State, http::Uri};
+use serde_json::{Value, json};
+
+const UPDATES: i64 = 8;
+const REPLY_DELAY: Duration = Duration::from_millis(500);
+const LOG_CHAT: i64 = -1000;
+
+#[derive(Default)]
+struct FakeApi {
+    replies_started: AtomicUsize,
+    replies_sent: AtomicUsize,
+    /// Offset of the last getUpdates call
+    offset: AtomicI64,
+    reports: Mutex<Vec<String>>,
+}
+
+fn message(id: i64, text: &str) -> Value {
+    json!({
+        "message_id": id,
+        "date": 0,
+        "chat": { "id": id, "type": "private", "first_name": "user" },
+        "from": { "id": id, "is_bot": false, "first_name": "user" },
+        "text": text,
+    })
+}
+
+async fn answer(State(api): State<Arc<FakeApi>>, uri: Uri, body: Bytes) -> Json<Value> {
+    let method = uri.path().rsplit('/').next().unwrap_or_default().to_lowercase();
+    let body: Value = serde_json::from_slice(&body).unwrap_or_default();
+
+    let result = match method.as_str() {
+        "getme" => json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "telos",
+            "username": "telos_bot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+            "has_main_web_app": false,
+        }),
+        "getupdates" => {
+            let offset = body["offset"].as_i64().unwrap_or(0);
+            api.offset.store(offset, Ordering::SeqCst);
+
+            if offset <= UPDATES && body["timeout"].as_i64() != Some(0) {
+                let updates: Vec<Value> = (offset.max(1)..=UPDATES)
+                    .map(|id| json!({ "update_id": id, "message": message(id, "/miguel") }))
+                    .collect();
+                json!(updates)
+            } else {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                json!([])
+            }
+        }
+        "sendmessage" if body["chat_id"] == LOG_CHAT => {
+            api.reports.lock().unwrap().push(body["text"].as_str().unwrap_or_default().to_string());
+            message(LOG_CHAT, "")
+        }
+        "sendmessage" => {
+            api.replies_started.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(REPLY_DELAY).await;
+            api.replies_sent.fetch_add(1, Ordering::SeqCst);
+            message(body["chat_id"].as_i64().unwrap_or(0), body["text"].as_str().unwrap_or_default())
+        }
+        _ => json!(true),
+    };
+
+    Json(json!({ "ok": true, "result": result }))
+}
+
+async fn wait_until(what: &str, timeout: Duration, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < timeout, "timed out waiting for {what}");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn sigterm_lets_in_flight_replies_finish() {
+    let api = Arc::new(FakeApi::default());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let app = Router::new().fallback(answer).with_state(Arc::clone(&api));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let dir = std::env::temp_dir().join(format!("telos-shutdown-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut bot = Command::new(env!("CARGO_BIN_EXE_telos"))
+        .current_dir(&dir)
+        .env("TELOXIDE_TOKEN", "123:test")
+        .env("TELOXIDE_API_URL", &url)
+        .env("DATABASE_PATH", format!("sqlite://{}/db.sqlite?mode=rwc", dir.display()))
+        .env("SHUTDOWN_DEADLINE_SECS", "10")
+        .env("REPORT_CHAT_ID", LOG_CHAT.to_string())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    wait_until("the first reply", Duration::from_secs(30), || {
+        api.replies_started.load(Ordering::SeqCst) > 0
+    })
+    .await;
+
+    let status = Command::new("kill").args(["-TERM", &bot.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    let signalled = Instant::now();
+
+    wait_until("the bot to exit", Duration::from_secs(20), || bot.try_wait().unwrap().is_some()).await;
+    let status = bot.wait().unwrap();
+
+    assert!(status.success(), "bot exited with {status}");
+    assert!(signalled.elapsed() < Duration::from_secs(10));
+    assert_eq!(api.replies_sent.load(Ordering::SeqCst), UPDATES as usize);
+    // The handled updates were confirmed, so they won't be delivered again
+    assert_eq!(api.offset.load(Ordering::SeqCst), UPDATES + 1);
+    // The pool was closed, leaving no journal behind
+    assert!(!dir.join("db.sqlite-journal").exists());
+    assert!(!dir.join("db.sqlite-wal").exists());
+
+    let reports = api.reports.lock().unwrap().clone();
+    assert!(reports[0].starts_with("ℹ️ Applied migrations: "), "{reports:?}");
+    assert!(reports[1].starts_with("ℹ️ Bot started with long polling"), "{reports:?}");
+    assert_eq!(reports[2..], ["ℹ️ Received SIGTERM, shutting down...", "ℹ️ Bot stopped"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}